@@ -0,0 +1,228 @@
+use chrono::{NaiveDate, Utc};
+use chrono_tz::Tz;
+use serde_json::{json, Value};
+
+use crate::formatting::{parse_date, DateFormat, RenderWarning};
+use crate::helpers::{days_between, parse_number};
+
+/// Invoice fields holding the due date and the amount owed, in order of preference
+const DUE_DATE_FIELDS: [&str; 3] = ["due_date", "fecha_vencimiento", "vencimiento"];
+const AMOUNT_FIELDS: [&str; 2] = ["amount_due", "saldo"];
+
+/// Aging bucket of an invoice by its days overdue
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AgingBucket {
+    Current,
+    Days1To30,
+    Days31To60,
+    Days61To90,
+    Over90,
+}
+
+impl AgingBucket {
+    pub const ALL: [AgingBucket; 5] = [
+        AgingBucket::Current,
+        AgingBucket::Days1To30,
+        AgingBucket::Days31To60,
+        AgingBucket::Days61To90,
+        AgingBucket::Over90,
+    ];
+
+    pub fn of(days_overdue: i64) -> Self {
+        match days_overdue {
+            i64::MIN..=0 => AgingBucket::Current,
+            1..=30 => AgingBucket::Days1To30,
+            31..=60 => AgingBucket::Days31To60,
+            61..=90 => AgingBucket::Days61To90,
+            _ => AgingBucket::Over90,
+        }
+    }
+
+    /// Value of the invoice `aging_bucket` field
+    pub fn label(self) -> &'static str {
+        match self {
+            AgingBucket::Current => "current",
+            AgingBucket::Days1To30 => "1-30",
+            AgingBucket::Days31To60 => "31-60",
+            AgingBucket::Days61To90 => "61-90",
+            AgingBucket::Over90 => "90+",
+        }
+    }
+
+    /// Client field with the amount owed in this bucket
+    pub fn total_field(self) -> &'static str {
+        match self {
+            AgingBucket::Current => "aging_total_current",
+            AgingBucket::Days1To30 => "aging_total_1_30",
+            AgingBucket::Days31To60 => "aging_total_31_60",
+            AgingBucket::Days61To90 => "aging_total_61_90",
+            AgingBucket::Over90 => "aging_total_90_plus",
+        }
+    }
+}
+
+/// Today's date in the business timezone (America/Bogota when unset or unknown), so
+/// invoices don't age a day early in the evening
+pub fn today_in(timezone: Option<&str>) -> NaiveDate {
+    let tz: Tz = timezone.and_then(|t| t.trim().parse().ok()).unwrap_or(chrono_tz::America::Bogota);
+    Utc::now().with_timezone(&tz).date_naive()
+}
+
+fn due_date(invoice: &Value, hint: Option<DateFormat>) -> Option<NaiveDate> {
+    DUE_DATE_FIELDS.iter().find_map(|field| invoice.get(*field).and_then(|v| parse_date(v, hint)))
+}
+
+fn amount(invoice: &Value) -> f64 {
+    AMOUNT_FIELDS.iter().find_map(|field| invoice.get(*field).and_then(parse_number)).unwrap_or(0.0)
+}
+
+/// The invoice's own `currency`, else the client's
+fn currency(invoice: &Value, client_currency: &str) -> String {
+    invoice.get("currency").and_then(|v| v.as_str())
+        .map(|c| c.trim().to_uppercase())
+        .filter(|c| !c.is_empty())
+        .unwrap_or_else(|| client_currency.to_string())
+}
+
+/// Adds the computed aging fields to the raw client data, before amounts and dates are
+/// formatted.
+///
+/// Each invoice with a due date gets `days_overdue` (recomputed as of `today`),
+/// `is_overdue` and `aging_bucket`. The client gets `as_of_date`, `is_overdue`,
+/// `aging_bucket` (its oldest invoice's), `overdue_total` and one `aging_total_*` amount
+/// per bucket; `total_days_overdue` is replaced by the largest recomputed value. Clients
+/// whose invoices have no due dates keep their data as it is.
+///
+/// Invoices without a `currency` are in `client_currency`. The totals are left out, and
+/// reported, when the invoices with due dates are in different currencies.
+pub fn add_computed_fields(
+    data: &mut Value,
+    today: NaiveDate,
+    hint: Option<DateFormat>,
+    client_currency: &str,
+    warnings: &mut Vec<RenderWarning>,
+) {
+    let Some(map) = data.as_object_mut() else {
+        return;
+    };
+    map.insert("as_of_date".to_string(), json!(today.format("%Y-%m-%d").to_string()));
+
+    let mut totals = [0.0; AgingBucket::ALL.len()];
+    let mut currencies: Vec<String> = Vec::new();
+    let mut max_days = None;
+    if let Some(invoices) = map.get_mut("invoices").and_then(|v| v.as_array_mut()) {
+        for invoice in invoices.iter_mut() {
+            let Some(due) = due_date(invoice, hint) else {
+                continue;
+            };
+            let days = days_between(due, today);
+            let bucket = AgingBucket::of(days);
+            totals[bucket as usize] += amount(invoice);
+            let code = currency(invoice, client_currency);
+            if !currencies.contains(&code) {
+                currencies.push(code);
+            }
+            max_days = max_days.max(Some(days));
+
+            if let Some(fields) = invoice.as_object_mut() {
+                fields.insert("days_overdue".to_string(), json!(days));
+                fields.insert("is_overdue".to_string(), json!(days > 0));
+                fields.insert("aging_bucket".to_string(), json!(bucket.label()));
+            }
+        }
+    }
+
+    let Some(days) = max_days else {
+        return;
+    };
+    map.insert("total_days_overdue".to_string(), json!(days));
+    map.insert("is_overdue".to_string(), json!(days > 0));
+    map.insert("aging_bucket".to_string(), json!(AgingBucket::of(days).label()));
+    if currencies.len() > 1 {
+        warnings.push(RenderWarning {
+            field: "overdue_total".to_string(),
+            value: currencies.join(", "),
+            message: "Invoices are in different currencies; overdue and aging totals are not computed".to_string(),
+        });
+        return;
+    }
+    map.insert("overdue_total".to_string(), json!(totals[1..].iter().sum::<f64>()));
+    for (bucket, total) in AgingBucket::ALL.iter().zip(totals) {
+        map.insert(bucket.total_field().to_string(), json!(total));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_boundaries() {
+        let labels: Vec<&str> = [-5, 0, 1, 30, 31, 60, 61, 90, 91].iter()
+            .map(|days| AgingBucket::of(*days).label())
+            .collect();
+        assert_eq!(labels, ["current", "current", "1-30", "1-30", "31-60", "31-60", "61-90", "61-90", "90+"]);
+    }
+
+    #[test]
+    fn test_add_computed_fields() {
+        let today = NaiveDate::from_ymd_opt(2026, 10, 19).unwrap();
+        let mut data = json!({
+            "total_days_overdue": 3,
+            "invoices": [
+                { "invoice_number": "A", "due_date": "2026-11-01", "amount_due": 100000 },
+                { "invoice_number": "B", "due_date": "2026-10-01", "amount_due": "250000", "days_overdue": 2 },
+                { "invoice_number": "C", "fecha_vencimiento": "15/07/2026", "saldo": 50000 },
+                { "invoice_number": "D", "amount_due": 999 },
+                { "invoice_number": "E", "due_date": "2026-10-10", "amount_due": "1.500.000" }
+            ]
+        });
+        let mut warnings = Vec::new();
+        add_computed_fields(&mut data, today, Some(DateFormat::parse("DD/MM/AAAA").unwrap()), "COP", &mut warnings);
+        assert!(warnings.is_empty());
+
+        let invoices = data["invoices"].as_array().unwrap();
+        assert_eq!(invoices[0]["days_overdue"], 0);
+        assert_eq!(invoices[0]["is_overdue"], false);
+        assert_eq!(invoices[0]["aging_bucket"], "current");
+        assert_eq!(invoices[1]["days_overdue"], 18, "recomputed as of today");
+        assert_eq!(invoices[1]["aging_bucket"], "1-30");
+        assert_eq!(invoices[2]["days_overdue"], 96);
+        assert_eq!(invoices[2]["aging_bucket"], "90+");
+        assert!(invoices[3].get("aging_bucket").is_none(), "no due date");
+
+        assert_eq!(data["as_of_date"], "2026-10-19");
+        assert_eq!(data["total_days_overdue"], 96);
+        assert_eq!(data["is_overdue"], true);
+        assert_eq!(data["aging_bucket"], "90+");
+        assert_eq!(data["aging_total_current"], 100000.0);
+        assert_eq!(data["aging_total_1_30"], 1_750_000.0, "COP-grouped amounts are read in full");
+        assert_eq!(data["aging_total_31_60"], 0.0);
+        assert_eq!(data["aging_total_90_plus"], 50000.0);
+        assert_eq!(data["overdue_total"], 1_800_000.0);
+
+        let mut data = json!({ "total_days_overdue": 3, "invoices": [{ "amount_due": 1 }] });
+        add_computed_fields(&mut data, today, None, "COP", &mut warnings);
+        assert_eq!(data["total_days_overdue"], 3, "left alone without due dates");
+    }
+
+    #[test]
+    fn test_mixed_currencies_have_no_totals() {
+        let today = NaiveDate::from_ymd_opt(2026, 10, 19).unwrap();
+        let mut data = json!({
+            "invoices": [
+                { "due_date": "2026-10-01", "amount_due": 250000 },
+                { "due_date": "2026-10-10", "amount_due": 120, "currency": "usd" },
+                { "due_date": "2026-11-01", "amount_due": 80, "currency": "COP" }
+            ]
+        });
+        let mut warnings = Vec::new();
+        add_computed_fields(&mut data, today, None, "COP", &mut warnings);
+
+        assert_eq!(data["aging_bucket"], "1-30", "the invoice fields are still computed");
+        assert!(data.get("overdue_total").is_none());
+        assert!(data.get("aging_total_1_30").is_none());
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].value, "COP, USD");
+    }
+}
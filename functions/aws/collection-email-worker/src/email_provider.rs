@@ -11,9 +11,22 @@ pub struct EmailMessage {
     pub text_body: String,
     pub from: String,
     pub attachments: Vec<Attachment>,
+    /// Imágenes que el HTML referencia como `cid:<content_id>` (p. ej. el QR de pago)
+    pub inline_images: Vec<InlineImage>,
     pub client_id: Option<String>,
     pub execution_id: Option<String>,
     pub message_id: Option<String>,
+    /// Clave determinística del outbox; el proveedor la adjunta como tag para poder
+    /// reconciliar envíos en duda
+    pub idempotency_key: Option<String>,
+}
+
+/// Imagen embebida en el cuerpo HTML, referenciada con `<img src="cid:<content_id>">`
+#[derive(Debug, Clone, PartialEq)]
+pub struct InlineImage {
+    pub content_id: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
 }
 
 /// Resultado del envío de email con metadata del proveedor
@@ -21,6 +34,7 @@ pub struct EmailMessage {
 pub struct SendResult {
     pub message_id: String,
     pub provider: String,
+    #[allow(dead_code)]
     pub metadata: Option<serde_json::Value>,
 }
 
@@ -33,4 +47,23 @@ pub trait EmailProvider: Send + Sync {
     
     /// Retorna el nombre del proveedor (para logging y debugging)
     fn provider_name(&self) -> &str;
+
+    /// Busca un mensaje ya aceptado por el proveedor usando la clave de idempotencia.
+    /// Retorna None si el proveedor no lo conoce o no permite consultarlo.
+    async fn find_sent_message(&self, _idempotency_key: &str) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        Ok(None)
+    }
+
+    /// Indica si todo mensaje aceptado deja evidencia (consultable o como evento), de modo
+    /// que la falta de evidencia tras OUTBOX_GIVE_UP_AFTER_HOURS prueba que no se envió.
+    fn confirms_every_send(&self) -> bool {
+        false
+    }
+
+    /// Indica si el proveedor entrega `inline_images` como partes inline que el HTML
+    /// puede referenciar con `cid:`. Si no, el worker no genera el QR de pago y deja
+    /// las imágenes de storage con su URL remota.
+    fn supports_inline_images(&self) -> bool {
+        true
+    }
 }
@@ -0,0 +1,125 @@
+use std::sync::LazyLock;
+use html5ever::serialize::{serialize, SerializeOpts, TraversalScope};
+use html5ever::tendril::TendrilSink;
+use html5ever::{namespace_url, ns, parse_fragment, LocalName, QualName};
+use markup5ever_rcdom::{Handle, NodeData, RcDom, SerializableHandle};
+use regex::{Captures, Regex};
+use serde_json::Value;
+
+/// Templates are rendered with Handlebars' HTML escaping, so imported customer data
+/// (`full_name`, `company_name`...) can't inject markup. Raw HTML only passes through
+/// `{{{triple-stash}}}` or the business's allow-listed rich fields
+/// (`collection_config.rich_text_fields`), whose values are sanitized first.
+static PLAIN_EXPRESSION: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(\{?)\{\{(~?)\s*((?:@root\.|this\.|\.\./)*)([A-Za-z_][\w-]*)\s*(~?)\}\}(\}?)").unwrap()
+});
+
+/// Elements removed together with their content
+const DROPPED_ELEMENTS: [&str; 14] = [
+    "script", "style", "iframe", "object", "embed", "form", "input", "button", "textarea",
+    "select", "link", "meta", "base", "svg",
+];
+
+/// Rewrites `{{field}}` to `{{{field}}}` for every allow-listed rich field, so the
+/// sanitized HTML of those fields is rendered as markup
+pub fn raw_rich_fields(template: &str, rich_fields: &[String]) -> String {
+    if rich_fields.is_empty() {
+        return template.to_string();
+    }
+
+    PLAIN_EXPRESSION.replace_all(template, |caps: &Captures| {
+        let triple = !caps[1].is_empty() && !caps[6].is_empty();
+        if triple || !rich_fields.iter().any(|f| f == &caps[4]) {
+            return caps[0].to_string();
+        }
+        format!("{}{{{{{{{}{}{}{}}}}}}}{}", &caps[1], &caps[2], &caps[3], &caps[4], &caps[5], &caps[6])
+    }).to_string()
+}
+
+/// Sanitizes the allow-listed rich fields of the client data in place
+pub fn sanitize_rich_fields(data: &mut Value, rich_fields: &[String]) {
+    let Some(map) = data.as_object_mut() else {
+        return;
+    };
+    for field in rich_fields {
+        if let Some(Value::String(html)) = map.get_mut(field) {
+            *html = sanitize_html(html);
+        }
+    }
+}
+
+/// Keeps formatting markup but drops scripts, embedded content, forms, event handler
+/// attributes and `javascript:`/`data:` URLs
+pub fn sanitize_html(html: &str) -> String {
+    if !html.contains('<') {
+        return html.to_string();
+    }
+
+    let dom = parse_fragment(
+        RcDom::default(),
+        Default::default(),
+        QualName::new(None, ns!(html), LocalName::from("body")),
+        vec![],
+    ).one(html);
+    let root = dom.document.children.borrow().first().cloned().unwrap_or_else(|| dom.document.clone());
+    clean(&root);
+
+    let mut bytes = Vec::new();
+    let handle: SerializableHandle = root.into();
+    let opts = SerializeOpts {
+        traversal_scope: TraversalScope::ChildrenOnly(None),
+        ..Default::default()
+    };
+    if serialize(&mut bytes, &handle, opts).is_err() {
+        return String::new();
+    }
+    String::from_utf8(bytes).unwrap_or_default()
+}
+
+fn unsafe_url(value: &str) -> bool {
+    let value: String = value.chars().filter(|c| !c.is_whitespace() && !c.is_control()).collect::<String>().to_lowercase();
+    value.starts_with("javascript:") || value.starts_with("vbscript:") || (value.starts_with("data:") && !value.starts_with("data:image/"))
+}
+
+fn clean(node: &Handle) {
+    node.children.borrow_mut().retain(|child| match &child.data {
+        NodeData::Element { name, .. } => !DROPPED_ELEMENTS.contains(&name.local.as_ref()),
+        NodeData::Comment { .. } | NodeData::ProcessingInstruction { .. } => false,
+        _ => true,
+    });
+
+    for child in node.children.borrow().iter() {
+        if let NodeData::Element { attrs, .. } = &child.data {
+            attrs.borrow_mut().retain(|a| {
+                let name = a.name.local.as_ref().to_ascii_lowercase();
+                !name.starts_with("on")
+                    && name != "srcdoc"
+                    && !(matches!(name.as_str(), "href" | "src" | "action" | "formaction" | "xlink:href") && unsafe_url(&a.value))
+            });
+        }
+        clean(child);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_raw_rich_fields() {
+        let fields = vec!["agent_message".to_string()];
+        assert_eq!(raw_rich_fields("<p>{{agent_message}}</p>", &fields), "<p>{{{agent_message}}}</p>");
+        assert_eq!(raw_rich_fields("{{~ @root.agent_message ~}}", &fields), "{{{~@root.agent_message~}}}");
+        assert_eq!(raw_rich_fields("{{{agent_message}}} {{full_name}}", &fields), "{{{agent_message}}} {{full_name}}");
+        assert_eq!(raw_rich_fields("{{agent_message}}", &[]), "{{agent_message}}");
+    }
+
+    #[test]
+    fn test_sanitize_html() {
+        assert_eq!(
+            sanitize_html(r#"<p onclick="x()">Hola <b>ACME</b><script>alert(1)</script></p><a href=" javascript:alert(1)">x</a><img src="data:image/png;base64,AA" onerror="y()">"#),
+            r#"<p>Hola <b>ACME</b></p><a>x</a><img src="data:image/png;base64,AA">"#
+        );
+        assert_eq!(sanitize_html("Sin marcado & texto"), "Sin marcado & texto");
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+use std::error::Error;
+use chrono::{DateTime, Duration, Utc};
+use log::info;
+
+use crate::supabase::SupabaseService;
+
+/// An execution competing for the shared provider send rate
+#[derive(Debug, Clone)]
+pub struct ActiveExecution {
+    pub execution_id: String,
+    pub business_id: String,
+}
+
+/// Scheduling decision for the next batch of one execution
+#[derive(Debug, Clone)]
+pub struct FairPlan {
+    /// Emails per second this execution may use
+    pub share_per_second: f64,
+    /// Earliest time the next batch may start
+    pub next_batch_not_before: DateTime<Utc>,
+    /// When the remaining clients are expected to be sent at the current share
+    pub expected_completion_at: DateTime<Utc>,
+}
+
+/// Global provider send rate shared by every execution (SES default is 14/s)
+pub fn global_send_rate() -> f64 {
+    std::env::var("PROVIDER_SEND_RATE_PER_SECOND")
+        .ok()
+        .and_then(|v| v.parse::<f64>().ok())
+        .filter(|v| *v > 0.0)
+        .unwrap_or(14.0)
+}
+
+/// Splits `global_rate` between executions in two levels: first between businesses
+/// in proportion to their weight (default 1, i.e. round-robin), then equally between
+/// the executions of each business. A business with 50k clients in ten executions
+/// therefore gets the same share as a small business with one.
+pub fn fair_shares(
+    active: &[ActiveExecution],
+    business_weights: &HashMap<String, f64>,
+    global_rate: f64,
+) -> HashMap<String, f64> {
+    let mut executions_by_business: HashMap<&str, Vec<&str>> = HashMap::new();
+    for exec in active {
+        let list = executions_by_business.entry(exec.business_id.as_str()).or_default();
+        if !list.contains(&exec.execution_id.as_str()) {
+            list.push(exec.execution_id.as_str());
+        }
+    }
+
+    let weight_of = |business_id: &str| {
+        business_weights.get(business_id).copied().filter(|w| *w > 0.0).unwrap_or(1.0)
+    };
+    let total_weight: f64 = executions_by_business.keys().map(|b| weight_of(b)).sum();
+
+    let mut shares = HashMap::new();
+    if total_weight <= 0.0 {
+        return shares;
+    }
+
+    for (business_id, executions) in &executions_by_business {
+        let business_rate = global_rate * weight_of(business_id) / total_weight;
+        let per_execution = business_rate / executions.len() as f64;
+        for execution_id in executions {
+            shares.insert(execution_id.to_string(), per_execution);
+        }
+    }
+
+    shares
+}
+
+/// Time needed to send `clients` emails at `share_per_second`
+fn send_duration(clients: i64, share_per_second: f64) -> Duration {
+    if share_per_second <= 0.0 || clients <= 0 {
+        return Duration::zero();
+    }
+    Duration::milliseconds(((clients as f64 / share_per_second) * 1000.0).ceil() as i64)
+}
+
+/// Builds the plan for an execution whose last batch of `batch_size` clients started at
+/// `batch_started_at`. The next batch waits until the execution's share of the rate has
+/// "paid" for the previous one, so a large tenant can't monopolise the provider.
+pub fn plan(
+    share_per_second: f64,
+    batch_started_at: DateTime<Utc>,
+    batch_size: i64,
+    remaining_clients: i64,
+    now: DateTime<Utc>,
+) -> FairPlan {
+    let next_batch_not_before = batch_started_at + send_duration(batch_size, share_per_second);
+    let start = next_batch_not_before.max(now);
+
+    FairPlan {
+        share_per_second,
+        next_batch_not_before,
+        expected_completion_at: start + send_duration(remaining_clients, share_per_second),
+    }
+}
+
+/// Spaces the sends of one batch so the execution never exceeds its share of the
+/// provider rate, whatever the batch size, and stops the batch before the Lambda
+/// runs out of time.
+pub struct Pacer {
+    share_per_second: f64,
+    started: tokio::time::Instant,
+    sent: u32,
+    /// Last moment a send may start; None when the run has no deadline
+    stop_at: Option<tokio::time::Instant>,
+}
+
+impl Pacer {
+    pub fn new(share_per_second: f64) -> Self {
+        Self { share_per_second, started: tokio::time::Instant::now(), sent: 0, stop_at: None }
+    }
+
+    pub fn stop_at(mut self, stop_at: Option<tokio::time::Instant>) -> Self {
+        self.stop_at = stop_at;
+        self
+    }
+
+    /// How long the next send must wait when `elapsed` has passed since the first one
+    fn delay(&self, elapsed: std::time::Duration) -> std::time::Duration {
+        if self.share_per_second <= 0.0 {
+            return std::time::Duration::ZERO;
+        }
+        let due = std::time::Duration::from_secs_f64(self.sent as f64 / self.share_per_second);
+        due.saturating_sub(elapsed)
+    }
+
+    /// Whether the next send, due after `elapsed`, can start within `left`
+    fn fits(&self, elapsed: std::time::Duration, left: std::time::Duration) -> bool {
+        !left.is_zero() && self.delay(elapsed) <= left
+    }
+
+    /// False when the next send could not start before the stop time; the rest of the
+    /// batch must be left for another run
+    pub fn has_time(&self) -> bool {
+        let Some(stop_at) = self.stop_at else {
+            return true;
+        };
+        let now = tokio::time::Instant::now();
+        self.fits(now - self.started, stop_at.saturating_duration_since(now))
+    }
+
+    /// Waits until the execution's share allows another send, then counts it
+    pub async fn wait(&mut self) {
+        let delay = self.delay(self.started.elapsed());
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        self.sent += 1;
+    }
+}
+
+/// This execution's share of the provider rate among the executions that currently
+/// have work due within FAIR_SCHEDULING_WINDOW_MINUTES. Returns (share, global rate,
+/// number of active executions).
+async fn share_for_execution(
+    supabase: &SupabaseService,
+    execution_id: &str,
+    business_id: &str,
+) -> Result<(f64, f64, usize), Box<dyn Error + Send + Sync>> {
+    let window_minutes = std::env::var("FAIR_SCHEDULING_WINDOW_MINUTES")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(60);
+    let now = Utc::now();
+
+    let mut active = supabase.get_active_executions(&(now + Duration::minutes(window_minutes)).to_rfc3339()).await?;
+    if !active.iter().any(|a| a.execution_id == execution_id) {
+        active.push(ActiveExecution {
+            execution_id: execution_id.to_string(),
+            business_id: business_id.to_string(),
+        });
+    }
+
+    let mut business_ids: Vec<String> = active.iter().map(|a| a.business_id.clone()).collect();
+    business_ids.sort();
+    business_ids.dedup();
+    let weights = supabase.get_business_send_weights(&business_ids).await.unwrap_or_default();
+
+    let global_rate = global_send_rate();
+    let shares = fair_shares(&active, &weights, global_rate);
+    let share = shares.get(execution_id).copied().unwrap_or(global_rate);
+    Ok((share, global_rate, shares.len()))
+}
+
+/// Pacer for the batch about to start
+pub async fn pacer_for_execution(
+    supabase: &SupabaseService,
+    execution_id: &str,
+    business_id: &str,
+) -> Result<Pacer, Box<dyn Error + Send + Sync>> {
+    let (share, global_rate, active) = share_for_execution(supabase, execution_id, business_id).await?;
+    info!("[fair_scheduler] Execution {}: pacing batch at {:.2}/s of {:.2}/s ({} active executions)", execution_id, share, global_rate, active);
+    Ok(Pacer::new(share))
+}
+
+/// Computes the fair plan for `execution_id` after a batch of `batch_size` clients
+/// that started at `batch_started_at`.
+pub async fn plan_for_execution(
+    supabase: &SupabaseService,
+    execution_id: &str,
+    business_id: &str,
+    batch_started_at: DateTime<Utc>,
+    batch_size: i64,
+    remaining_clients: i64,
+) -> Result<FairPlan, Box<dyn Error + Send + Sync>> {
+    let (share, global_rate, active) = share_for_execution(supabase, execution_id, business_id).await?;
+    let fair_plan = plan(share, batch_started_at, batch_size, remaining_clients, Utc::now());
+
+    info!(
+        "[fair_scheduler] Execution {}: {} active executions, share {:.2}/s of {:.2}/s, next batch not before {}, expected completion {}",
+        execution_id, active, share, global_rate,
+        fair_plan.next_batch_not_before.to_rfc3339(), fair_plan.expected_completion_at.to_rfc3339()
+    );
+
+    Ok(fair_plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exec(id: &str, business: &str) -> ActiveExecution {
+        ActiveExecution { execution_id: id.to_string(), business_id: business.to_string() }
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_businesses_share_equally_regardless_of_execution_count() {
+        let active = vec![
+            exec("big-1", "big"),
+            exec("big-2", "big"),
+            exec("big-3", "big"),
+            exec("small-1", "small"),
+        ];
+        let shares = fair_shares(&active, &HashMap::new(), 14.0);
+
+        assert!((shares["small-1"] - 7.0).abs() < 1e-9);
+        assert!((shares["big-1"] - 7.0 / 3.0).abs() < 1e-9);
+        let total: f64 = shares.values().sum();
+        assert!((total - 14.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_business_weights() {
+        let active = vec![exec("a-1", "a"), exec("b-1", "b")];
+        let weights = HashMap::from([("a".to_string(), 3.0)]);
+        let shares = fair_shares(&active, &weights, 12.0);
+
+        assert!((shares["a-1"] - 9.0).abs() < 1e-9);
+        assert!((shares["b-1"] - 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_duplicate_entries_count_once() {
+        let active = vec![exec("a-1", "a"), exec("a-1", "a")];
+        let shares = fair_shares(&active, &HashMap::new(), 10.0);
+        assert!((shares["a-1"] - 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_plan_defers_next_batch_by_share() {
+        let started = utc("2026-03-15T15:00:00Z");
+        let now = utc("2026-03-15T15:00:30Z");
+        // 100 emails at 2/s = 50s after the batch started
+        let p = plan(2.0, started, 100, 400, now);
+
+        assert_eq!(p.next_batch_not_before, utc("2026-03-15T15:00:50Z"));
+        // 400 remaining at 2/s = 200s after the next batch may start
+        assert_eq!(p.expected_completion_at, utc("2026-03-15T15:04:10Z"));
+    }
+
+    #[test]
+    fn test_plan_never_starts_in_the_past() {
+        let started = utc("2026-03-15T15:00:00Z");
+        let now = utc("2026-03-15T15:10:00Z");
+        let p = plan(14.0, started, 14, 28, now);
+
+        assert_eq!(p.next_batch_not_before, utc("2026-03-15T15:00:01Z"));
+        assert_eq!(p.expected_completion_at, utc("2026-03-15T15:10:02Z"));
+    }
+    #[test]
+    fn test_pacer_spaces_sends_by_share() {
+        let mut pacer = Pacer::new(4.0);
+        assert_eq!(pacer.delay(std::time::Duration::ZERO), std::time::Duration::ZERO, "the first send goes out at once");
+
+        pacer.sent = 3;
+        // The fourth send is due 750ms after the first one
+        assert_eq!(pacer.delay(std::time::Duration::from_millis(500)), std::time::Duration::from_millis(250));
+        assert_eq!(pacer.delay(std::time::Duration::from_secs(1)), std::time::Duration::ZERO);
+    }
+
+    #[test]
+    fn test_pacer_stops_before_the_deadline_at_small_shares() {
+        // 100 clients at 0.1/s need 990s; with 840s left only the first 85 can start
+        let mut pacer = Pacer::new(0.1);
+        let left = std::time::Duration::from_secs(840);
+        let mut started = 0;
+        while started < 100 && pacer.fits(std::time::Duration::ZERO, left) {
+            pacer.sent += 1;
+            started += 1;
+        }
+        assert_eq!(started, 85);
+
+        assert!(!Pacer::new(0.0).fits(std::time::Duration::from_secs(5), std::time::Duration::ZERO), "past the stop time");
+        assert!(Pacer::new(0.0).has_time(), "no deadline");
+    }
+}
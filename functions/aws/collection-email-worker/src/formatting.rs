@@ -0,0 +1,463 @@
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime};
+use rusty_money::{iso, LocalFormat};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::helpers::parse_number;
+use crate::models::CollectionConfig;
+
+/// Currency used when neither the business, the client nor the invoice name one
+pub const DEFAULT_CURRENCY: &str = "COP";
+
+/// Separators and symbol placement of a locale
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct NumberLocale {
+    group: char,
+    decimal: char,
+    symbol_first: bool,
+    symbol_space: bool,
+}
+
+const fn locale(group: char, decimal: char, symbol_first: bool, symbol_space: bool) -> NumberLocale {
+    NumberLocale { group, decimal, symbol_first, symbol_space }
+}
+
+/// Known locales; unknown regions fall back to the language, then to the currency's own format
+fn number_locale(tag: &str) -> Option<NumberLocale> {
+    let tag = tag.trim().replace('_', "-").to_lowercase();
+    let by_tag = match tag.as_str() {
+        "es-co" | "es-ar" | "es-uy" | "es-py" | "pt-br" => Some(locale('.', ',', true, true)),
+        "es-cl" | "es-ve" | "es-ec" => Some(locale('.', ',', true, false)),
+        "es-mx" | "es-us" | "es-do" | "es-gt" | "es-pa" | "en-us" | "en-gb" | "en-ca" | "en-au" => Some(locale(',', '.', true, false)),
+        "es-pe" | "es-bo" => Some(locale(',', '.', true, true)),
+        "es-es" | "de-de" | "de-at" | "it-it" | "pt-pt" => Some(locale('.', ',', false, true)),
+        "fr-fr" | "fr-ca" | "fr-be" => Some(locale('\u{a0}', ',', false, true)),
+        "de-ch" => Some(locale('\'', '.', true, true)),
+        _ => None,
+    };
+
+    by_tag.or_else(|| match tag.split('-').next() {
+        Some("es") | Some("pt") => Some(locale('.', ',', true, true)),
+        Some("en") => Some(locale(',', '.', true, false)),
+        Some("de") | Some("it") => Some(locale('.', ',', false, true)),
+        Some("fr") => Some(locale('\u{a0}', ',', false, true)),
+        _ => None,
+    })
+}
+
+/// How amounts are rendered for one business (optionally overridden per invoice currency)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AmountFormat {
+    pub currency: String,
+    pub locale: Option<String>,
+    /// None = the currency's minor units
+    pub decimals: Option<u32>,
+    pub symbol: bool,
+}
+
+impl Default for AmountFormat {
+    /// The historical format: COP with "." thousands, no decimals and no symbol
+    fn default() -> Self {
+        Self {
+            currency: DEFAULT_CURRENCY.to_string(),
+            locale: None,
+            decimals: Some(0),
+            symbol: false,
+        }
+    }
+}
+
+impl AmountFormat {
+    pub fn from_config(config: &CollectionConfig) -> Self {
+        let Some(currency) = config.currency.as_deref().filter(|c| !c.trim().is_empty()) else {
+            // Businesses that never declared a currency keep the legacy output
+            return Self {
+                locale: config.locale.clone(),
+                decimals: config.amount_decimals.or(Some(0)),
+                symbol: config.show_currency_symbol,
+                ..Self::default()
+            };
+        };
+
+        Self {
+            currency: currency.trim().to_uppercase(),
+            locale: config.locale.clone(),
+            decimals: config.amount_decimals,
+            symbol: config.show_currency_symbol,
+        }
+    }
+
+    /// Same settings for another currency (e.g. an invoice billed in USD). The legacy
+    /// "no decimals" default only applies to the business currency.
+    pub fn for_currency(&self, code: Option<&str>) -> Self {
+        match code.map(|c| c.trim().to_uppercase()).filter(|c| !c.is_empty() && *c != self.currency) {
+            Some(code) if iso::find(&code).is_some() => Self {
+                currency: code,
+                decimals: None,
+                ..self.clone()
+            },
+            _ => self.clone(),
+        }
+    }
+
+    /// Formats `value` honouring the currency's minor units and the locale's separators
+    /// and symbol placement. Unknown currency codes fall back to `DEFAULT_CURRENCY`.
+    pub fn format(&self, value: f64) -> String {
+        let iso_currency = iso::find(&self.currency)
+            .or_else(|| iso::find(DEFAULT_CURRENCY))
+            .expect("default currency exists");
+
+        let number_locale = self.locale.as_deref().and_then(number_locale).unwrap_or_else(|| {
+            let native = LocalFormat::from_locale(iso_currency.locale);
+            locale(native.digit_separator, native.exponent_separator, iso_currency.symbol_first, !iso_currency.symbol_first)
+        });
+        let decimals = self.decimals.unwrap_or(iso_currency.exponent).min(6) as usize;
+
+        let rounded = format!("{:.*}", decimals, value.abs());
+        let (integer, fraction) = rounded.split_once('.').unwrap_or((&rounded, ""));
+
+        let mut grouped = String::new();
+        for (i, c) in integer.chars().enumerate() {
+            if i > 0 && (integer.len() - i) % 3 == 0 {
+                grouped.push(number_locale.group);
+            }
+            grouped.push(c);
+        }
+        if !fraction.is_empty() {
+            grouped.push(number_locale.decimal);
+            grouped.push_str(fraction);
+        }
+
+        let sign = if value < 0.0 && rounded.chars().any(|c| c.is_ascii_digit() && c != '0') { "-" } else { "" };
+        let space = if number_locale.symbol_space { " " } else { "" };
+        match (self.symbol, number_locale.symbol_first) {
+            (false, _) => format!("{}{}", sign, grouped),
+            (true, true) => format!("{}{}{}{}", sign, iso_currency.symbol, space, grouped),
+            (true, false) => format!("{}{}{}{}", sign, grouped, space, iso_currency.symbol),
+        }
+    }
+}
+
+/// Money amount keys of client and invoice data. Listed explicitly because names like
+/// `total_invoices` or `numero_total` hold counts.
+const AMOUNT_FIELDS: [&str; 29] = [
+    "amount", "amount_due", "total_amount_due", "amount_paid", "paid_amount", "subtotal", "total",
+    "total_amount", "tax", "tax_amount", "iva", "discount", "discount_amount", "balance",
+    "overdue_total", "aging_total_current", "aging_total_1_30", "aging_total_31_60",
+    "aging_total_61_90", "aging_total_90_plus", "monto", "saldo", "valor", "valor_factura",
+    "valor_total", "importe", "price", "precio", "abono",
+];
+
+/// Keys holding money amounts in client and invoice data
+pub fn is_amount_field(key: &str) -> bool {
+    AMOUNT_FIELDS.contains(&key.to_lowercase().as_str())
+}
+
+/// Formats every amount field of `object` in place and keeps the raw number next to it
+/// as `<field>_value` for the template helpers. An invoice-level `currency` field
+/// overrides the business currency.
+pub fn format_amount_fields(object: &mut Value, format: &AmountFormat) {
+    let Some(map) = object.as_object_mut() else {
+        return;
+    };
+
+    let format = format.for_currency(map.get("currency").and_then(|c| c.as_str()));
+    let keys: Vec<String> = map.keys().filter(|k| is_amount_field(k)).cloned().collect();
+
+    for key in keys {
+        let Some(amount) = map.get(&key).and_then(parse_number) else {
+            continue;
+        };
+        map.insert(format!("{}_value", key), serde_json::json!(amount));
+        map.insert(key, Value::String(format.format(amount)));
+    }
+}
+
+/// Something the renderer could not interpret. Reported in dry runs and in the
+/// execution audit log; the email is still sent with the original value.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct RenderWarning {
+    /// Path of the offending value, e.g. `invoices[2].due_date` or `subject`
+    pub field: String,
+    pub value: String,
+    pub message: String,
+}
+
+/// Output date formats supported by `collection_config.output_date_format`
+/// (AAAA is the Spanish spelling of YYYY; both are accepted)
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DateFormat {
+    #[default]
+    DayMonthYearDash,
+    MonthDayYearDash,
+    YearMonthDay,
+    DayMonthYearSlash,
+    MonthDayYearSlash,
+}
+
+impl DateFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_uppercase().replace("YYYY", "AAAA").as_str() {
+            "DD-MM-AAAA" => Some(DateFormat::DayMonthYearDash),
+            "MM-DD-AAAA" => Some(DateFormat::MonthDayYearDash),
+            "AAAA-MM-DD" => Some(DateFormat::YearMonthDay),
+            "DD/MM/AAAA" => Some(DateFormat::DayMonthYearSlash),
+            "MM/DD/AAAA" => Some(DateFormat::MonthDayYearSlash),
+            _ => None,
+        }
+    }
+
+    /// The format whose `strftime` pattern is `pattern`
+    pub fn from_strftime(pattern: &str) -> Option<Self> {
+        [
+            DateFormat::DayMonthYearDash,
+            DateFormat::MonthDayYearDash,
+            DateFormat::YearMonthDay,
+            DateFormat::DayMonthYearSlash,
+            DateFormat::MonthDayYearSlash,
+        ].into_iter().find(|format| format.strftime() == pattern)
+    }
+
+    pub fn strftime(&self) -> &'static str {
+        match self {
+            DateFormat::DayMonthYearDash => "%d-%m-%Y",
+            DateFormat::MonthDayYearDash => "%m-%d-%Y",
+            DateFormat::YearMonthDay => "%Y-%m-%d",
+            DateFormat::DayMonthYearSlash => "%d/%m/%Y",
+            DateFormat::MonthDayYearSlash => "%m/%d/%Y",
+        }
+    }
+
+    pub fn format(&self, date: NaiveDate) -> String {
+        date.format(self.strftime()).to_string()
+    }
+}
+
+/// Parses the date representations found in imported invoices: RFC 3339 and ISO
+/// timestamps, ISO dates, day-first and month-first dates with `/`, `-` or `.`,
+/// compact `YYYYMMDD` and Excel serial numbers. `hint` (the business's
+/// `input_date_format`) decides ambiguous values such as 03/04/2026.
+pub fn parse_date(value: &Value, hint: Option<DateFormat>) -> Option<NaiveDate> {
+    let text = match value {
+        Value::String(s) => s.trim().to_string(),
+        Value::Number(n) => n.to_string(),
+        _ => return None,
+    };
+    if text.is_empty() {
+        return None;
+    }
+
+    if let Ok(dt) = DateTime::parse_from_rfc3339(&text) {
+        return Some(dt.date_naive());
+    }
+    for fmt in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"] {
+        if let Ok(dt) = NaiveDateTime::parse_from_str(&text, fmt) {
+            return Some(dt.date());
+        }
+    }
+
+    // Excel stores dates as days since 1899-12-30; only accept plausible invoice years
+    if text.chars().all(|c| c.is_ascii_digit()) && (5..=6).contains(&text.len()) {
+        let serial: i64 = text.parse().ok()?;
+        return (20_000..=80_000).contains(&serial)
+            .then(|| NaiveDate::from_ymd_opt(1899, 12, 30).map(|epoch| epoch + Duration::days(serial)))
+            .flatten();
+    }
+
+    let mut formats: Vec<&str> = hint.map(|h| vec![h.strftime()]).unwrap_or_default();
+    formats.extend([
+        "%Y-%m-%d", "%Y/%m/%d", "%Y%m%d",
+        "%d-%m-%Y", "%d/%m/%Y", "%d.%m.%Y",
+        "%m-%d-%Y", "%m/%d/%Y",
+        "%d/%m/%y", "%d-%m-%y",
+    ]);
+
+    // Only the date part of "12/03/2026 10:00" matters
+    let date_part = text.split_whitespace().next().unwrap_or_default();
+    // A four-digit-year format reads "15/03/26" as the year 26; rejecting it inside the
+    // search lets the two-digit-year formats try
+    formats.iter().find_map(|fmt| {
+        NaiveDate::parse_from_str(date_part, fmt).ok()
+            .filter(|d| (1900..=2200).contains(&chrono::Datelike::year(d)))
+    })
+}
+
+/// Keys holding dates in client and invoice data
+pub fn is_date_field(key: &str) -> bool {
+    let key = key.to_lowercase();
+    !key.ends_with("_iso")
+        && (key == "date" || key.ends_with("_date") || key.starts_with("fecha") || key.contains("vencimiento"))
+}
+
+/// Re-renders every date field of `object` in `output` format and keeps the ISO date next
+/// to it as `<field>_iso`. Values that can't be parsed are left as they are and reported.
+pub fn format_date_fields(
+    object: &mut Value,
+    path: &str,
+    input: Option<DateFormat>,
+    output: DateFormat,
+    warnings: &mut Vec<RenderWarning>,
+) {
+    let Some(map) = object.as_object_mut() else {
+        return;
+    };
+
+    let keys: Vec<String> = map.keys().filter(|k| is_date_field(k)).cloned().collect();
+    for key in keys {
+        let value = &map[&key];
+        if value.is_null() || value.as_str().map(|s| s.trim().is_empty()).unwrap_or(false) {
+            continue;
+        }
+
+        match parse_date(value, input) {
+            Some(date) => {
+                map.insert(format!("{}_iso", key), Value::String(date.format("%Y-%m-%d").to_string()));
+                map.insert(key, Value::String(output.format(date)));
+            }
+            None => warnings.push(RenderWarning {
+                field: if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) },
+                value: value.as_str().map(str::to_string).unwrap_or_else(|| value.to_string()),
+                message: "Unrecognised date format".to_string(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn fmt(currency: &str, locale: Option<&str>, symbol: bool) -> AmountFormat {
+        AmountFormat {
+            currency: currency.to_string(),
+            locale: locale.map(str::to_string),
+            decimals: None,
+            symbol,
+        }
+    }
+
+    #[test]
+    fn test_default_matches_legacy_output() {
+        assert_eq!(AmountFormat::default().format(1_500_000.0), "1.500.000");
+        assert_eq!(AmountFormat::from_config(&CollectionConfig::default()).format(1_234.56), "1.235");
+    }
+
+    #[test]
+    fn test_minor_units_and_locales() {
+        assert_eq!(fmt("USD", Some("en-US"), true).format(1_500_000.5), "$1,500,000.50");
+        assert_eq!(fmt("COP", Some("es-CO"), true).format(1_500_000.0), "$ 1.500.000,00");
+        assert_eq!(fmt("EUR", Some("es-ES"), true).format(2_500.5), "2.500,50 €");
+        assert_eq!(fmt("JPY", Some("en-US"), true).format(1_234.4), "¥1,234");
+        assert_eq!(fmt("EUR", Some("fr-FR"), false).format(1_234_567.891), "1\u{a0}234\u{a0}567,89");
+        assert_eq!(fmt("USD", Some("en-US"), true).format(-42.0), "-$42.00");
+    }
+
+    #[test]
+    fn test_unknown_locale_uses_currency_format() {
+        assert_eq!(fmt("USD", Some("xx-YY"), false).format(1_000.0), "1,000.00");
+        assert_eq!(fmt("MXN", Some("es-419"), false).format(1_000.0), "1.000,00");
+    }
+
+    #[test]
+    fn test_config_with_currency_honours_minor_units() {
+        let config = CollectionConfig {
+            currency: Some("usd".to_string()),
+            locale: Some("en-US".to_string()),
+            amount_decimals: None,
+            show_currency_symbol: true,
+            ..Default::default()
+        };
+        assert_eq!(AmountFormat::from_config(&config).format(10.0), "$10.00");
+    }
+
+    #[test]
+    fn test_amount_field_detection() {
+        for key in ["amount_due", "total_amount_due", "subtotal", "tax_amount", "paid_amount", "Saldo", "valor_factura", "aging_total_1_30"] {
+            assert!(is_amount_field(key), "{} should be an amount", key);
+        }
+        for key in ["invoice_number", "days_overdue", "total_days_overdue", "amount_due_value", "due_date", "invoice_count", "total_invoices", "total_facturas", "numero_total"] {
+            assert!(!is_amount_field(key), "{} should not be an amount", key);
+        }
+    }
+
+    #[test]
+    fn test_format_amount_fields_with_invoice_currency() {
+        let base = AmountFormat::default();
+        let mut invoice = json!({
+            "invoice_number": "F-1",
+            "amount_due": "1500000",
+            "tax_amount": 285000,
+            "days_overdue": "10",
+        });
+        format_amount_fields(&mut invoice, &base);
+        assert_eq!(invoice["amount_due"], "1.500.000");
+        assert_eq!(invoice["amount_due_value"], json!(1_500_000.0));
+        assert_eq!(invoice["tax_amount"], "285.000");
+        assert_eq!(invoice["days_overdue"], "10");
+
+        let mut usd_invoice = json!({ "amount_due": 99.5, "currency": "USD" });
+        format_amount_fields(&mut usd_invoice, &base);
+        assert_eq!(usd_invoice["amount_due"], "99.50");
+
+        // Imported strings use either separator convention
+        for (raw, value) in [("1.500,50", 1500.5), ("1500,50", 1500.5), ("1.500.000", 1_500_000.0), ("1,500.50", 1500.5)] {
+            let mut invoice = json!({ "amount_due": raw });
+            format_amount_fields(&mut invoice, &base);
+            assert_eq!(invoice["amount_due_value"], json!(value), "{}", raw);
+        }
+    }
+
+    fn d(y: i32, m: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, day).unwrap()
+    }
+
+    #[test]
+    fn test_date_format_tokens() {
+        assert_eq!(DateFormat::parse("DD-MM-AAAA"), Some(DateFormat::DayMonthYearDash));
+        assert_eq!(DateFormat::parse("mm/dd/yyyy"), Some(DateFormat::MonthDayYearSlash));
+        assert_eq!(DateFormat::parse("AAAA-MM-DD").unwrap().format(d(2026, 3, 5)), "2026-03-05");
+        assert_eq!(DateFormat::parse("MM-DD-AAAA").unwrap().format(d(2026, 3, 5)), "03-05-2026");
+        assert_eq!(DateFormat::parse("DD/MM/AAAA").unwrap().format(d(2026, 3, 5)), "05/03/2026");
+        assert_eq!(DateFormat::parse("D-M-Y"), None);
+    }
+
+    #[test]
+    fn test_parse_date_inputs() {
+        let expected = Some(d(2026, 3, 15));
+        for input in ["2026-03-15", "2026-03-15T10:00:00Z", "2026-03-15 08:30:00", "15/03/2026", "15-03-2026", "15.03.2026", "20260315", "03/15/2026"] {
+            assert_eq!(parse_date(&json!(input), None), expected, "{}", input);
+        }
+        assert_eq!(parse_date(&json!(46096), None), expected);
+        assert_eq!(parse_date(&json!("not a date"), None), None);
+        assert_eq!(parse_date(&json!("31/02/2026"), None), None);
+        assert_eq!(parse_date(&json!("15/03/26"), None), expected);
+        assert_eq!(parse_date(&json!("15-03-26"), None), expected);
+    }
+
+    #[test]
+    fn test_parse_date_uses_input_hint_for_ambiguous_values() {
+        assert_eq!(parse_date(&json!("03/04/2026"), None), Some(d(2026, 4, 3)));
+        assert_eq!(parse_date(&json!("03/04/2026"), Some(DateFormat::MonthDayYearSlash)), Some(d(2026, 3, 4)));
+    }
+
+    #[test]
+    fn test_format_date_fields_reports_failures() {
+        let mut invoice = json!({
+            "invoice_date": "2026-01-10",
+            "due_date": "vence pronto",
+            "invoice_number": "F-1",
+        });
+        let mut warnings = Vec::new();
+        format_date_fields(&mut invoice, "invoices[0]", None, DateFormat::DayMonthYearSlash, &mut warnings);
+
+        assert_eq!(invoice["invoice_date"], "10/01/2026");
+        assert_eq!(invoice["invoice_date_iso"], "2026-01-10");
+        assert_eq!(invoice["due_date"], "vence pronto");
+        assert_eq!(warnings, vec![RenderWarning {
+            field: "invoices[0].due_date".to_string(),
+            value: "vence pronto".to_string(),
+            message: "Unrecognised date format".to_string(),
+        }]);
+    }
+}
@@ -0,0 +1,171 @@
+// Golden-file tests of the rendering pipeline.
+//
+// Every directory in `tests/fixtures/` is one case: `template.html` is a template as
+// exported by the TipTap editor and `case.json` holds the subject, the client data and
+// optionally the business `config`, `layout` and `partials`:
+//
+// ```json
+// { "subject": "...", "custom_data": { ... }, "invoices": [ ... ], "config": { ... } }
+// ```
+//
+// The case is rendered offline, as of 2026-10-19, through the same path as a send
+// (template compilation, formatting, layout, CSS inlining) and compared with
+// `tests/golden/<case>.html` and `tests/golden/<case>.txt` (subject, inline images,
+// warnings and plain-text body). After an intended change, regenerate the golden files
+// with `UPDATE_GOLDEN=1 cargo test -p collection-email-worker golden` and review their
+// diff.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use chrono::NaiveDate;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::models::{CollectionClient, CollectionConfig, EmailLayout, EmailTemplate, TemplatePartial};
+use crate::payment_link::PaymentLinks;
+use crate::payment_qr::PaymentQr;
+use crate::template_cache::CompiledTemplate;
+
+#[derive(Deserialize)]
+struct Case {
+    subject: String,
+    #[serde(default)]
+    custom_data: Option<Value>,
+    #[serde(default)]
+    invoices: Option<Value>,
+    #[serde(default)]
+    config: CollectionConfig,
+    #[serde(default)]
+    layout: EmailLayout,
+    #[serde(default)]
+    partials: Vec<TemplatePartial>,
+}
+
+/// Date the fixtures are rendered as of, so days overdue and aging buckets stay stable
+fn as_of() -> NaiveDate {
+    NaiveDate::from_ymd_opt(2026, 10, 19).unwrap()
+}
+
+fn manifest_dir() -> &'static Path {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+}
+
+fn updating() -> bool {
+    std::env::var_os("UPDATE_GOLDEN").is_some()
+}
+
+/// Compares `actual` with `tests/golden/<name>`, or rewrites it when `UPDATE_GOLDEN` is
+/// set. Returns a description of the first difference.
+fn check_golden(name: &str, actual: &str) -> Result<(), String> {
+    let path = manifest_dir().join("tests/golden").join(name);
+    if updating() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, actual).unwrap();
+        return Ok(());
+    }
+
+    let expected = fs::read_to_string(&path)
+        .map_err(|_| format!("{}: missing golden file {}", name, path.display()))?;
+    if actual == expected {
+        return Ok(());
+    }
+
+    let line = actual.lines().zip(expected.lines()).position(|(a, e)| a != e)
+        .unwrap_or_else(|| actual.lines().count().min(expected.lines().count()));
+    Err(format!(
+        "{} differs at line {}:\n  expected: {}\n  actual:   {}",
+        name,
+        line + 1,
+        expected.lines().nth(line).unwrap_or("<end of file>"),
+        actual.lines().nth(line).unwrap_or("<end of file>"),
+    ))
+}
+
+/// Asserts that `actual` matches the golden file `tests/golden/<name>`
+pub fn assert_golden(name: &str, actual: &str) {
+    if let Err(e) = check_golden(name, actual) {
+        panic!("{}\nRun with UPDATE_GOLDEN=1 and review the diff if the change is intended", e);
+    }
+}
+
+fn cases() -> Vec<PathBuf> {
+    let mut cases: Vec<PathBuf> = fs::read_dir(manifest_dir().join("tests/fixtures"))
+        .expect("tests/fixtures exists")
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.join("case.json").is_file())
+        .collect();
+    cases.sort();
+    cases
+}
+
+/// Renders the case in `dir`, returning the HTML body and the text summary
+fn render_case(dir: &Path) -> (String, String) {
+    let name = dir.file_name().unwrap().to_string_lossy().to_string();
+    let case: Case = serde_json::from_str(&fs::read_to_string(dir.join("case.json")).unwrap())
+        .unwrap_or_else(|e| panic!("{}/case.json: {}", name, e));
+    let content = fs::read_to_string(dir.join("template.html"))
+        .unwrap_or_else(|e| panic!("{}/template.html: {}", name, e));
+
+    let template = EmailTemplate { id: name.clone(), subject: case.subject, content };
+    let client = CollectionClient {
+        id: format!("{}-client", name),
+        execution_id: format!("{}-execution", name),
+        status: "pending".to_string(),
+        invoices: case.invoices,
+        custom_data: case.custom_data,
+        email_template_id: None,
+        threshold_id: None,
+    };
+    // Fixtures give the logo as an absolute URL; the storage path is resolved by Supabase
+    let mut layout = case.layout;
+    layout.logo_url = layout.logo_path.clone();
+
+    let compiled = CompiledTemplate::compile(template, case.config.rich_text_fields(), &case.partials);
+    let batch = crate::BatchContext {
+        today: as_of(),
+        payment_links: PaymentLinks::new(&case.config, b"golden-secret".to_vec()),
+        payment_qr: PaymentQr::from_config(&case.config),
+        inline_images: true,
+    };
+    let rendered = crate::render_client_email(&compiled, &client, &case.config, &layout, &batch);
+
+    let mut summary = format!("Subject: {}\n", rendered.subject);
+    if let Some(e) = &rendered.render_error {
+        summary.push_str(&format!("Render error: {}\n", e));
+    }
+    if !rendered.partials.is_empty() {
+        summary.push_str(&format!("Partials: {}\n", rendered.partials.join(", ")));
+    }
+    for image in &rendered.inline_images {
+        summary.push_str(&format!("Inline image: {} ({})\n", image.content_id, image.content_type));
+    }
+    for warning in &rendered.warnings {
+        summary.push_str(&format!("Warning: {} ({}): {}\n", warning.field, warning.value, warning.message));
+    }
+    summary.push('\n');
+    summary.push_str(&rendered.text_body);
+    summary.push('\n');
+
+    (rendered.html_body, summary)
+}
+
+#[test]
+fn test_golden_fixtures() {
+    let cases = cases();
+    assert!(!cases.is_empty(), "no fixtures in tests/fixtures");
+
+    let failures: Vec<String> = cases.iter().flat_map(|dir| {
+        let name = dir.file_name().unwrap().to_string_lossy().to_string();
+        let (html, summary) = render_case(dir);
+        [
+            check_golden(&format!("{}.html", name), &html),
+            check_golden(&format!("{}.txt", name), &summary),
+        ]
+    }).filter_map(Result::err).collect();
+
+    assert!(
+        failures.is_empty(),
+        "{}\n\nRun with UPDATE_GOLDEN=1 and review the diff if the change is intended",
+        failures.join("\n\n")
+    );
+}
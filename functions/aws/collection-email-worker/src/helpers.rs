@@ -0,0 +1,694 @@
+use std::cell::RefCell;
+use std::sync::LazyLock;
+use chrono::{NaiveDate, Utc};
+use handlebars::{
+    handlebars_helper, Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext, RenderError,
+    RenderErrorReason, Renderable, ScopedJson,
+};
+use regex::{Captures, Regex};
+use serde_json::Value;
+
+use crate::formatting::{parse_date, AmountFormat, DateFormat, RenderWarning};
+use crate::i18n::{Language, Message};
+use crate::invoice_table::InvoiceTable;
+use crate::models::{InvoiceTableColumn, InvoiceTableConfig};
+
+/// Registers the helper library available to every collection template:
+///
+/// - `{{currency total_amount_due_value "USD" decimals=0 symbol=false}}`
+/// - `{{date due_date_iso}}`, `{{date due_date_iso "AAAA-MM-DD"}}` or a chrono format such as `"%d %b %Y"`
+/// - `{{days_overdue due_date}}` (without arguments it keeps returning the invoice field)
+/// - `{{sum invoices "amount_due_value"}}`
+/// - `{{#if (gt total_amount_due_value 1000000)}}`, `gte`, `lt`, `lte`, `eq`, `ne`
+/// - `{{pluralize invoices "factura" "facturas"}}`
+/// - `{{default company_name "Cliente"}}`
+/// - `{{length invoices}}`, also written `{{invoices.length}}` (see [`rewrite_length_paths`])
+/// - `{{#lang "en"}}Dear customer{{else}}Estimado cliente{{/lang}}` picks a language variant
+/// - `{{t "footer"}}` prints system text from the message catalogue in the email's language
+/// - `{{invoice_table}}` draws the invoices as a table (see [`InvoiceTableHelper`])
+/// - `{{payment_url}}` is the signed payment link of the invoice in scope, else of the client
+/// - `{{payment_qr size=180}}` places the client's payment QR code image (see [`PaymentQrHelper`])
+pub fn register(handlebars: &mut Handlebars) {
+    handlebars.register_helper("currency", Box::new(Currency));
+    handlebars.register_helper("date", Box::new(Date));
+    handlebars.register_helper("days_overdue", Box::new(DaysOverdue));
+    handlebars.register_helper("sum", Box::new(sum));
+    handlebars.register_helper("gt", Box::new(gt));
+    handlebars.register_helper("gte", Box::new(gte));
+    handlebars.register_helper("lt", Box::new(lt));
+    handlebars.register_helper("lte", Box::new(lte));
+    handlebars.register_helper("eq", Box::new(eq));
+    handlebars.register_helper("ne", Box::new(ne));
+    handlebars.register_helper("pluralize", Box::new(pluralize));
+    handlebars.register_helper("default", Box::new(default));
+    handlebars.register_helper("length", Box::new(length));
+    handlebars.register_helper("lang", Box::new(Lang));
+    handlebars.register_helper("t", Box::new(Translate));
+    handlebars.register_helper("invoice_table", Box::new(InvoiceTableHelper));
+    handlebars.register_helper("payment_url", Box::new(PaymentUrl));
+    handlebars.register_helper("payment_qr", Box::new(PaymentQrHelper));
+}
+
+thread_local! {
+    /// Values the helpers could not interpret during the renders on this thread
+    static WARNINGS: RefCell<Vec<RenderWarning>> = const { RefCell::new(Vec::new()) };
+}
+
+fn warn(field: String, value: String, message: &str) {
+    WARNINGS.with(|w| w.borrow_mut().push(RenderWarning { field, value, message: message.to_string() }));
+}
+
+/// Returns and clears the warnings the helpers raised on this thread. Rendering is
+/// synchronous, so draining before and after a client's renders scopes them to it.
+pub fn take_warnings() -> Vec<RenderWarning> {
+    WARNINGS.with(|w| std::mem::take(&mut *w.borrow_mut()))
+}
+
+/// Reads a number from template data. Strings may carry a currency symbol and thousands
+/// separators; "1.500.000" is read as COP-style grouping (the legacy amount format).
+pub fn parse_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => {
+            let cleaned: String = s.chars().filter(|c| c.is_ascii_digit() || matches!(c, '.' | ',' | '-')).collect();
+            if !cleaned.chars().any(|c| c.is_ascii_digit()) {
+                return None;
+            }
+
+            let normalized = match (cleaned.rfind('.'), cleaned.rfind(',')) {
+                (Some(dot), Some(comma)) if comma > dot => cleaned.replace('.', "").replace(',', "."),
+                (Some(_), Some(_)) => cleaned.replace(',', ""),
+                (None, Some(_)) if is_grouped(&cleaned, ',') => cleaned.replace(',', ""),
+                (None, Some(_)) => cleaned.replace(',', "."),
+                (Some(_), None) if is_grouped(&cleaned, '.') => cleaned.replace('.', ""),
+                _ => cleaned,
+            };
+            normalized.parse::<f64>().ok()
+        }
+        _ => None,
+    }
+}
+
+/// True for "1.500.000"-style values: every group after the first has exactly three digits
+fn is_grouped(value: &str, separator: char) -> bool {
+    let digits = value.trim_start_matches('-');
+    let mut groups = digits.split(separator);
+    let first = groups.next().unwrap_or_default();
+    let rest: Vec<&str> = groups.collect();
+    !rest.is_empty()
+        && (1..=3).contains(&first.len())
+        && rest.iter().all(|g| g.len() == 3 && g.chars().all(|c| c.is_ascii_digit()))
+}
+
+fn date_param(value: Option<&&Value>) -> Option<NaiveDate> {
+    value.and_then(|v| parse_date(v, None))
+}
+
+/// Days between `due` and `as_of`; not-yet-due invoices count as 0
+pub fn days_between(due: NaiveDate, as_of: NaiveDate) -> i64 {
+    (as_of - due).num_days().max(0)
+}
+
+/// Numeric comparison when either side is a number (or both are numeric strings),
+/// text comparison otherwise
+fn compare(a: &Value, b: &Value) -> Option<std::cmp::Ordering> {
+    let numeric = match (a, b) {
+        (Value::String(x), Value::String(y)) => x.trim().parse::<f64>().is_ok() && y.trim().parse::<f64>().is_ok(),
+        _ => a.is_number() || b.is_number(),
+    };
+
+    match (numeric, parse_number(a), parse_number(b)) {
+        (true, Some(x), Some(y)) => x.partial_cmp(&y),
+        _ => Some(value_text(a).cmp(&value_text(b))),
+    }
+}
+
+fn value_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+fn is_empty(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::String(s) => s.trim().is_empty(),
+        Value::Array(a) => a.is_empty(),
+        _ => false,
+    }
+}
+
+handlebars_helper!(sum: |*args| {
+    let field = args.get(1).and_then(|v| v.as_str());
+    args.first()
+        .and_then(|v| v.as_array())
+        .map(|items| items.iter()
+            .filter_map(|item| match field {
+                Some(f) => item.get(f).and_then(parse_number),
+                None => parse_number(item),
+            })
+            .sum::<f64>())
+        .unwrap_or(0.0)
+});
+
+handlebars_helper!(gt: |a: Json, b: Json| compare(a, b) == Some(std::cmp::Ordering::Greater));
+handlebars_helper!(gte: |a: Json, b: Json| matches!(compare(a, b), Some(std::cmp::Ordering::Greater | std::cmp::Ordering::Equal)));
+handlebars_helper!(lt: |a: Json, b: Json| compare(a, b) == Some(std::cmp::Ordering::Less));
+handlebars_helper!(lte: |a: Json, b: Json| matches!(compare(a, b), Some(std::cmp::Ordering::Less | std::cmp::Ordering::Equal)));
+handlebars_helper!(eq: |a: Json, b: Json| compare(a, b) == Some(std::cmp::Ordering::Equal));
+handlebars_helper!(ne: |a: Json, b: Json| compare(a, b) != Some(std::cmp::Ordering::Equal));
+
+handlebars_helper!(pluralize: |*args| {
+    let count = match args.first() {
+        Some(Value::Array(items)) => items.len() as f64,
+        Some(v) => parse_number(v).unwrap_or(0.0),
+        None => 0.0,
+    };
+    let singular = args.get(1).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+    let plural = args.get(2).and_then(|v| v.as_str()).map(str::to_string)
+        .unwrap_or_else(|| format!("{}s", singular));
+    if count == 1.0 { singular } else { plural }
+});
+
+handlebars_helper!(default: |*args| {
+    args.iter()
+        .find(|v| !is_empty(v))
+        .map(|v| (*v).clone())
+        .unwrap_or(Value::String(String::new()))
+});
+
+handlebars_helper!(length: |value: Json| match value {
+    Value::Array(items) => items.len(),
+    Value::Object(map) => map.len(),
+    Value::String(s) => s.chars().count(),
+    _ => 0,
+});
+
+static EXPRESSION: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\{\{([^{}!]*)\}\}").unwrap());
+static BARE_LENGTH_PATH: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(\s*)((?:\.\./)*[A-Za-z_@][\w@]*(?:\.[\w@]+)*?)\.length(\s*)$").unwrap());
+static LENGTH_PATH: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"((?:\.\./)*[A-Za-z_@][\w@]*(?:\.[\w@]+)*?)\.length\b").unwrap());
+
+/// Handlebars-rust has no JavaScript-style `.length` on arrays, so `{{invoices.length}}`
+/// renders empty. Rewrites `path.length` inside expressions to the `length` helper:
+/// `{{invoices.length}}` becomes `{{length invoices}}` and
+/// `{{#if (gt invoices.length 1)}}` becomes `{{#if (gt (length invoices) 1)}}`.
+pub fn rewrite_length_paths(template: &str) -> String {
+    if !template.contains(".length") {
+        return template.to_string();
+    }
+
+    EXPRESSION.replace_all(template, |caps: &Captures| {
+        let inner = &caps[1];
+        if let Some(bare_caps) = BARE_LENGTH_PATH.captures(inner) {
+            return format!("{{{{{}length {}{}}}}}", &bare_caps[1], &bare_caps[2], &bare_caps[3]);
+        }
+        format!("{{{{{}}}}}", LENGTH_PATH.replace_all(inner, "(length $1)"))
+    }).to_string()
+}
+
+/// Language of the email being rendered (root `language`, set by the worker)
+fn email_language(ctx: &Context) -> Language {
+    ctx.data().get("language").and_then(|v| v.as_str()).and_then(Language::parse).unwrap_or_default()
+}
+
+/// `{{#lang "en" ["pt"...]}}...{{else}}...{{/lang}}` renders its block when the email's
+/// language is one of the given ones, and the `{{else}}` part otherwise
+struct Lang;
+
+impl HelperDef for Lang {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let current = email_language(ctx);
+        let matches = h.params().iter()
+            .filter_map(|p| p.value().as_str())
+            .any(|tag| Language::parse(tag) == Some(current));
+
+        match if matches { h.template() } else { h.inverse() } {
+            Some(template) => template.render(r, ctx, rc, out),
+            None => Ok(()),
+        }
+    }
+}
+
+/// `{{t "footer"}}` looks a key up in the system message catalogue
+struct Translate;
+
+impl HelperDef for Translate {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let key = h.param(0).and_then(|p| p.value().as_str()).unwrap_or_default();
+        let text = Message::from_key(key).map(|m| email_language(ctx).text(m)).unwrap_or_default();
+        Ok(ScopedJson::Derived(Value::String(text.to_string())))
+    }
+}
+
+/// `{{invoice_table [invoices] [columns="invoice_number,due_date,amount_due"] [sort="due_date"]
+/// [order="desc"] [totals=false]}}` renders the invoices as an email-safe table with the
+/// business's `collection_config.invoice_table` settings (root `invoice_table_config`);
+/// the hash arguments override them for one template. The table is HTML, so it is
+/// written unescaped; every value in it is escaped.
+struct InvoiceTableHelper;
+
+impl HelperDef for InvoiceTableHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let root = ctx.data();
+        let invoices = h.param(0).map(|p| p.value()).or_else(|| root.get("invoices"))
+            .and_then(|v| v.as_array()).cloned().unwrap_or_default();
+
+        let mut config: InvoiceTableConfig = root.get("invoice_table_config")
+            .and_then(|v| serde_json::from_value(v.clone()).ok())
+            .unwrap_or_default();
+        if let Some(columns) = h.hash_get("columns").and_then(|v| v.value().as_str()) {
+            config.columns = columns.split(',')
+                .map(str::trim)
+                .filter(|f| !f.is_empty())
+                .map(|field| config.columns.iter().find(|c| c.field == field).cloned()
+                    .unwrap_or_else(|| InvoiceTableColumn { field: field.to_string(), ..Default::default() }))
+                .collect();
+        }
+        if let Some(sort) = h.hash_get("sort").and_then(|v| v.value().as_str()) {
+            config.sort_by = Some(sort.to_string());
+        }
+        if let Some(order) = h.hash_get("order").and_then(|v| v.value().as_str()) {
+            config.sort_order = Some(order.to_string());
+        }
+        if let Some(totals) = h.hash_get("totals").and_then(|v| v.value().as_bool()) {
+            config.show_totals = Some(totals);
+        }
+
+        let amount_format: AmountFormat = root.get("amount_format")
+            .and_then(|v| serde_json::from_value(v.clone()).ok())
+            .unwrap_or_default();
+        let date_format = root.get("output_date_format").and_then(|v| v.as_str())
+            .unwrap_or(DateFormat::default().strftime())
+            .to_string();
+
+        let table = InvoiceTable {
+            config: &config,
+            amount_format: &amount_format,
+            date_format: &date_format,
+            language: email_language(ctx),
+        };
+        out.write(&table.render(&invoices))?;
+        Ok(())
+    }
+}
+
+/// `{{days_overdue due_date [as_of]}}` computes the days since `due_date`, by default as of
+/// the email's `as_of_date` (today in the business timezone).
+/// Invoices already carry a `days_overdue` field, so `{{days_overdue}}` without arguments
+/// resolves that field instead, keeping existing templates unchanged.
+struct DaysOverdue;
+
+impl HelperDef for DaysOverdue {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        if h.params().is_empty() {
+            let field = rc.evaluate(ctx, "days_overdue")?;
+            return Ok(ScopedJson::Derived(field.as_json().clone()));
+        }
+
+        let params: Vec<&Value> = h.params().iter().map(|p| p.value()).collect();
+        let as_of = date_param(params.get(1))
+            .or_else(|| ctx.data().get("as_of_date_iso").and_then(|v| parse_date(v, None)))
+            .unwrap_or_else(|| Utc::now().date_naive());
+        let days = date_param(params.first()).map(|due| days_between(due, as_of));
+
+        Ok(ScopedJson::Derived(days.map(Value::from).unwrap_or(Value::Null)))
+    }
+}
+
+/// `{{payment_url}}` resolves the `payment_url` the worker signs for each invoice with a
+/// number and for the client's whole balance. Inside `{{#each invoices}}` an invoice
+/// without its own link falls back to the client's.
+struct PaymentUrl;
+
+impl HelperDef for PaymentUrl {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        _: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let field = rc.evaluate(ctx, "payment_url")?;
+        let url = match field.as_json() {
+            Value::String(url) if !url.is_empty() => Value::String(url.clone()),
+            _ => ctx.data().get("payment_url").cloned().unwrap_or(Value::Null),
+        };
+        Ok(ScopedJson::Derived(url))
+    }
+}
+
+/// `{{payment_qr [size=180]}}` writes the `<img>` of the payment QR code the worker embeds
+/// in the email (root `payment_qr`, a `cid:` URL). Renders nothing when the business has
+/// no QR configured.
+struct PaymentQrHelper;
+
+impl HelperDef for PaymentQrHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let Some(src) = ctx.data().get("payment_qr").and_then(|v| v.as_str()).filter(|s| !s.is_empty()) else {
+            return Ok(());
+        };
+        let size = h.hash_get("size").and_then(|v| v.value().as_u64()).unwrap_or(180).clamp(80, 400);
+        out.write(&format!(
+            r#"<img src="{}" alt="{}" width="{size}" height="{size}" style="display: block; width: {size}px; height: {size}px; border: 0;">"#,
+            handlebars::html_escape(src),
+            email_language(ctx).text(Message::PaymentQrAlt),
+        ))?;
+        Ok(())
+    }
+}
+
+/// `{{date value [format]}}` re-renders a date. `format` is one of the
+/// `output_date_format` tokens (DD-MM-AAAA...) or a chrono format string; by default the
+/// business's `output_date_format` (root `output_date_format`) is used. Date fields the
+/// worker already re-rendered are read from their `<field>_iso` sibling; other values
+/// are read in the business output format when ambiguous. An invalid chrono format
+/// fails the render.
+struct Date;
+
+impl HelperDef for Date {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let value = h.param(0).map(|p| p.value()).unwrap_or(&Value::Null);
+        let output = ctx.data().get("output_date_format").and_then(|v| v.as_str());
+        let iso = h.param(0)
+            .and_then(|p| p.relative_path())
+            .and_then(|path| rc.evaluate(ctx, &format!("{}_iso", path)).ok())
+            .and_then(|iso| parse_date(iso.as_json(), Some(DateFormat::YearMonthDay)));
+        let Some(date) = iso.or_else(|| parse_date(value, output.and_then(DateFormat::from_strftime))) else {
+            // Unparseable values are shown as they are
+            return Ok(ScopedJson::Derived(Value::String(value_text(value))));
+        };
+
+        let formatted = match h.param(1).and_then(|p| p.value().as_str()).or(output) {
+            Some(fmt) => match DateFormat::parse(fmt) {
+                Some(known) => known.format(date),
+                None => format_date(date, fmt)?,
+            },
+            None => DateFormat::default().format(date),
+        };
+
+        Ok(ScopedJson::Derived(Value::String(formatted)))
+    }
+}
+
+/// `date` in a chrono format string from the template; chrono panics on unknown
+/// specifiers, so they are rejected first
+fn format_date(date: NaiveDate, fmt: &str) -> Result<String, RenderError> {
+    if chrono::format::StrftimeItems::new(fmt).any(|item| item == chrono::format::Item::Error) {
+        return Err(RenderErrorReason::Other(format!("Invalid date format \"{}\"", fmt)).into());
+    }
+    Ok(date.format(fmt).to_string())
+}
+
+/// `{{currency amount ["USD"] [decimals=0] [symbol=false]}}` formats an amount like the
+/// pre-formatted amount fields (root `amount_format`, the business settings), in the
+/// currency of the current invoice, then of the root; the arguments override them.
+/// Amounts that aren't numbers are shown as they are and reported. `{{currency}}`
+/// without arguments prints the currency code.
+struct Currency;
+
+impl HelperDef for Currency {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let local = rc.evaluate(ctx, "currency")?.as_json().as_str().map(str::to_string);
+        let root_currency = ctx.data().get("currency").and_then(|v| v.as_str()).map(str::to_string);
+
+        if h.params().is_empty() {
+            let code = local.or(root_currency).unwrap_or_else(|| crate::formatting::DEFAULT_CURRENCY.to_string());
+            return Ok(ScopedJson::Derived(Value::String(code)));
+        }
+
+        let param = h.param(0);
+        let value = param.map(|p| p.value()).unwrap_or(&Value::Null);
+        let Some(amount) = parse_number(value) else {
+            // Never print an amount the data doesn't hold
+            let field = param.and_then(|p| p.relative_path()).cloned().unwrap_or_else(|| "currency".to_string());
+            warn(field, value_text(value), "Not a number; shown as written");
+            return Ok(ScopedJson::Derived(Value::String(value_text(value))));
+        };
+        let code = h.param(1).and_then(|p| p.value().as_str().map(str::to_string))
+            .or_else(|| h.hash_get("code").and_then(|v| v.value().as_str().map(str::to_string)))
+            .or(local);
+
+        // Without the business settings (e.g. the payment QR content) the currency's own
+        // format is used, with its symbol
+        let business = ctx.data().get("amount_format")
+            .and_then(|v| serde_json::from_value::<AmountFormat>(v.clone()).ok())
+            .unwrap_or_else(|| AmountFormat {
+                currency: root_currency.unwrap_or_else(|| crate::formatting::DEFAULT_CURRENCY.to_string()).trim().to_uppercase(),
+                locale: ctx.data().get("locale").and_then(|v| v.as_str()).map(str::to_string),
+                decimals: None,
+                symbol: true,
+            });
+        let mut format = business.for_currency(code.as_deref());
+        if let Some(decimals) = h.hash_get("decimals").and_then(|v| v.value().as_u64()) {
+            format.decimals = Some(decimals as u32);
+        }
+        if let Some(symbol) = h.hash_get("symbol").and_then(|v| v.value().as_bool()) {
+            format.symbol = symbol;
+        }
+
+        Ok(ScopedJson::Derived(Value::String(format.format(amount))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn render(template: &str, data: &Value) -> String {
+        let mut hb = Handlebars::new();
+        hb.register_escape_fn(handlebars::no_escape);
+        register(&mut hb);
+        hb.render_template(template, data).unwrap()
+    }
+
+    #[test]
+    fn test_parse_number_formats() {
+        assert_eq!(parse_number(&json!(1500.5)), Some(1500.5));
+        assert_eq!(parse_number(&json!("1.500.000")), Some(1_500_000.0));
+        assert_eq!(parse_number(&json!("$ 1,500,000.50")), Some(1_500_000.5));
+        assert_eq!(parse_number(&json!("1.500.000,75")), Some(1_500_000.75));
+        assert_eq!(parse_number(&json!("12.5")), Some(12.5));
+        assert_eq!(parse_number(&json!("abc")), None);
+    }
+
+    #[test]
+    fn test_currency_helper() {
+        let data = json!({ "total": 1500000, "usd": "2500.5" });
+        assert_eq!(render("{{currency total}}", &data), "$1.500.000,00");
+        assert_eq!(render("{{currency total decimals=0}}", &data), "$1.500.000");
+        assert_eq!(render(r#"{{currency usd "USD"}}"#, &data), "$2,500.50");
+        assert_eq!(render(r#"{{currency usd code="EUR" symbol=false}}"#, &data), "2.500,50");
+    }
+
+    #[test]
+    fn test_currency_helper_follows_the_business_format() {
+        let config = crate::models::CollectionConfig {
+            show_currency_symbol: true,
+            amount_decimals: Some(0),
+            ..Default::default()
+        };
+        let data = json!({
+            "amount_format": AmountFormat::from_config(&config),
+            "currency": "COP",
+            "total": 1500000.4,
+            "invoices": [{ "currency": "USD", "amount_due_value": 2500.5 }]
+        });
+        assert_eq!(render("{{currency total}}", &data), "$1.500.000");
+        assert_eq!(render("{{currency total symbol=false decimals=2}}", &data), "1.500.000,40");
+        // The invoice currency brings its own minor units, as in the pre-formatted fields
+        assert_eq!(render("{{#each invoices}}{{currency amount_due_value}}{{/each}}", &data), "$2,500.50");
+
+        take_warnings();
+        assert_eq!(render("{{currency pending}} {{currency \"pendiente\"}}", &data), " pendiente");
+        let warnings = take_warnings();
+        assert_eq!(warnings.len(), 2);
+        assert_eq!((warnings[0].field.as_str(), warnings[0].value.as_str()), ("pending", ""));
+        assert_eq!(warnings[1].value, "pendiente");
+    }
+
+    #[test]
+    fn test_date_and_days_overdue_helpers() {
+        let data = json!({ "due": "2026-01-10", "today": "2026-02-09T12:00:00Z", "days_overdue": "7" });
+        assert_eq!(render("{{date due}}", &data), "10-01-2026");
+        assert_eq!(render(r#"{{date due "MM/DD/AAAA"}}"#, &data), "01/10/2026");
+        assert_eq!(render(r#"{{date due "%Y.%m.%d"}}"#, &data), "2026.01.10");
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        let error = hb.render_template(r#"{{date due "%Q"}}"#, &data).unwrap_err();
+        assert!(error.to_string().contains(r#"Invalid date format "%Q""#), "{}", error);
+        assert_eq!(render("{{days_overdue due today}}", &data), "30");
+        assert_eq!(render("{{days_overdue today due}}", &data), "0");
+        // Without arguments the existing field keeps working
+        assert_eq!(render("{{days_overdue}}", &data), "7");
+
+        // Counted as of the business day the worker sets
+        let data = json!({ "due": "2026-01-10", "as_of_date_iso": "2026-01-20" });
+        assert_eq!(render("{{days_overdue due}}", &data), "10");
+    }
+
+    #[test]
+    fn test_date_reads_dates_the_worker_already_formatted() {
+        // Fields re-rendered as MM-DD-AAAA: 03-04-2026 is March 4
+        let data = json!({
+            "output_date_format": "%m-%d-%Y",
+            "due_date": "03-04-2026",
+            "due_date_iso": "2026-03-04",
+            "invoices": [{ "due_date": "03-05-2026", "due_date_iso": "2026-03-05" }]
+        });
+        assert_eq!(render(r#"{{date due_date "AAAA-MM-DD"}}"#, &data), "2026-03-04");
+        assert_eq!(render(r#"{{#each invoices}}{{date due_date "AAAA-MM-DD"}}{{/each}}"#, &data), "2026-03-05");
+        assert_eq!(render(r#"{{date "03-06-2026" "AAAA-MM-DD"}}"#, &data), "2026-03-06", "literals follow the output format");
+    }
+
+    #[test]
+    fn test_days_overdue_field_inside_each() {
+        let data = json!({ "invoices": [{ "days_overdue": "12" }, { "days_overdue": "40" }] });
+        assert_eq!(render("{{#each invoices}}{{days_overdue}};{{/each}}", &data), "12;40;");
+    }
+
+    #[test]
+    fn test_sum_and_comparisons() {
+        let data = json!({
+            "invoices": [{ "amount_due": "1.000.000" }, { "amount_due": 250000 }],
+            "limit": 1000000
+        });
+        assert_eq!(render(r#"{{currency (sum invoices "amount_due") decimals=0}}"#, &data), "$1.250.000");
+        assert_eq!(render(r#"{{#if (gt (sum invoices "amount_due") limit)}}alto{{else}}bajo{{/if}}"#, &data), "alto");
+        assert_eq!(render("{{#if (lt limit 10)}}si{{else}}no{{/if}}", &data), "no");
+        assert_eq!(render(r#"{{#if (eq "abc" "abc")}}si{{/if}}"#, &data), "si");
+        assert_eq!(render(r#"{{#if (eq "2" 2)}}si{{/if}}"#, &data), "si");
+    }
+
+    #[test]
+    fn test_pluralize_and_default() {
+        let data = json!({ "one": [1], "many": [1, 2], "empty": "", "name": "ACME" });
+        assert_eq!(render(r#"{{pluralize one "factura" "facturas"}}"#, &data), "factura");
+        assert_eq!(render(r#"{{pluralize many "factura"}}"#, &data), "facturas");
+        assert_eq!(render(r#"{{default empty "Cliente"}}"#, &data), "Cliente");
+        assert_eq!(render(r#"{{default missing name}}"#, &data), "ACME");
+    }
+
+    #[test]
+    fn test_payment_url_falls_back_to_client_link() {
+        let data = json!({
+            "payment_url": "https://pagos.example.com/p/cliente",
+            "invoices": [{ "payment_url": "https://pagos.example.com/p/f1" }, { "invoice_number": "" }]
+        });
+        assert_eq!(
+            render(r#"{{payment_url}}|{{#each invoices}}{{payment_url}};{{/each}}"#, &data),
+            "https://pagos.example.com/p/cliente|https://pagos.example.com/p/f1;https://pagos.example.com/p/cliente;"
+        );
+        assert_eq!(render("{{#if payment_url}}ok{{/if}}", &json!({})), "");
+    }
+
+    #[test]
+    fn test_payment_qr_helper() {
+        let data = json!({ "payment_qr": "cid:payment-qr.png", "language": "en" });
+        assert_eq!(
+            render("{{payment_qr size=120}}", &data),
+            r#"<img src="cid:payment-qr.png" alt="Payment QR code" width="120" height="120" style="display: block; width: 120px; height: 120px; border: 0;">"#
+        );
+        assert_eq!(render("{{payment_qr}}", &json!({})), "");
+    }
+
+    #[test]
+    fn test_invoice_table_helper() {
+        let data = json!({
+            "language": "en",
+            "invoice_table_config": { "columns": [{ "field": "invoice_number", "label": "No." }, { "field": "amount_due" }] },
+            "amount_format": { "currency": "USD", "locale": "en-US", "decimals": 2, "symbol": true },
+            "invoices": [
+                { "invoice_number": "A-1", "amount_due": "$10.00", "amount_due_value": 10.0 },
+                { "invoice_number": "A-2", "amount_due": 5.5 }
+            ]
+        });
+
+        let html = render("{{invoice_table}}", &data);
+        assert!(html.starts_with("<table class=\"invoice-table\""), "{}", html);
+        assert!(html.contains(">No.</th>") && html.contains(">Amount</th>"));
+        assert!(html.contains(">$5.50</td>"), "unformatted amounts use the business format: {}", html);
+        assert!(html.contains(">$15.50</td>"), "{}", html);
+
+        let html = render(r#"{{invoice_table columns="amount_due,invoice_number" sort="amount_due" totals=false}}"#, &data);
+        assert!(html.find("A-2").unwrap() < html.find("A-1").unwrap());
+        assert!(html.find(">Amount</th>").unwrap() < html.find(">No.</th>").unwrap());
+        assert!(!html.contains(">Total<"));
+    }
+
+    #[test]
+    fn test_language_helpers() {
+        let template = r#"{{#lang "en"}}Dear {{name}}{{else}}Estimado {{name}}{{/lang}} - {{t "fallback_name"}}"#;
+        assert_eq!(render(template, &json!({ "language": "en", "name": "ACME" })), "Dear ACME - Customer");
+        assert_eq!(render(template, &json!({ "language": "es", "name": "ACME" })), "Estimado ACME - Cliente");
+        assert_eq!(render(template, &json!({ "name": "ACME" })), "Estimado ACME - Cliente");
+        assert_eq!(render(r#"{{t "unknown"}}"#, &json!({})), "");
+    }
+
+    #[test]
+    fn test_length_paths() {
+        assert_eq!(rewrite_length_paths("{{invoices.length}}"), "{{length invoices}}");
+        assert_eq!(rewrite_length_paths("{{{ client.invoices.length }}}"), "{{{ length client.invoices }}}");
+        assert_eq!(
+            rewrite_length_paths("{{#if (gt invoices.length 1)}}x{{/if}}"),
+            "{{#if (gt (length invoices) 1)}}x{{/if}}"
+        );
+        assert_eq!(rewrite_length_paths("{{! invoices.length }}"), "{{! invoices.length }}");
+
+        let data = json!({ "invoices": [1, 2, 3] });
+        assert_eq!(render(&rewrite_length_paths("{{invoices.length}} facturas"), &data), "3 facturas");
+        assert_eq!(
+            render(&rewrite_length_paths("{{#if (gt invoices.length 2)}}muchas{{/if}}"), &data),
+            "muchas"
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use serde_json::Value;
+
+use crate::models::CollectionConfig;
+
+/// Languages of the text the worker adds around templates (footer, legal notice,
+/// fallbacks). Spanish is the default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Language {
+    #[default]
+    Es,
+    En,
+}
+
+/// System text with a translation in every [`Language`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message {
+    Footer,
+    Legal,
+    TextBodyPlaceholder,
+    FallbackName,
+    LogoAlt,
+    InvoiceNumber,
+    InvoiceDate,
+    DueDate,
+    DaysOverdue,
+    AmountDue,
+    Total,
+    PaymentQrAlt,
+}
+
+impl Language {
+    /// Parses "es", "en", "en-US", "es_CO"...; unsupported languages return None
+    pub fn parse(tag: &str) -> Option<Self> {
+        let primary = tag.trim().split(['-', '_']).next().unwrap_or_default().to_lowercase();
+        match primary.as_str() {
+            "es" => Some(Language::Es),
+            "en" => Some(Language::En),
+            _ => None,
+        }
+    }
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Language::Es => "es",
+            Language::En => "en",
+        }
+    }
+
+    pub fn text(self, message: Message) -> &'static str {
+        match (self, message) {
+            (Language::Es, Message::Footer) => "Por favor responda a este correo o comuníquese directamente con el comercio a través del contacto compartido",
+            (Language::En, Message::Footer) => "Please reply to this email or contact the business directly through the shared contact details",
+            (Language::Es, Message::Legal) => "Notificacion automatica de Carteras - APX - Plataforma para la gestión inteligente de Cartera, propiedad de BORLS © 2026 Todos los derechos reservados",
+            (Language::En, Message::Legal) => "Automated collections notice - APX - Platform for smart accounts receivable management, owned by BORLS © 2026 All rights reserved",
+            (Language::Es, Message::TextBodyPlaceholder) => "Por favor habilite HTML para ver este correo.",
+            (Language::En, Message::TextBodyPlaceholder) => "Please enable HTML to view this email.",
+            (Language::Es, Message::FallbackName) => "Cliente",
+            (Language::En, Message::FallbackName) => "Customer",
+            (Language::Es, Message::LogoAlt) => "Logo",
+            (Language::En, Message::LogoAlt) => "Logo",
+            (Language::Es, Message::InvoiceNumber) => "Factura",
+            (Language::En, Message::InvoiceNumber) => "Invoice",
+            (Language::Es, Message::InvoiceDate) => "Fecha",
+            (Language::En, Message::InvoiceDate) => "Date",
+            (Language::Es, Message::DueDate) => "Vencimiento",
+            (Language::En, Message::DueDate) => "Due date",
+            (Language::Es, Message::DaysOverdue) => "Días vencidos",
+            (Language::En, Message::DaysOverdue) => "Days overdue",
+            (Language::Es, Message::AmountDue) => "Valor",
+            (Language::En, Message::AmountDue) => "Amount",
+            (Language::Es, Message::Total) => "Total",
+            (Language::En, Message::Total) => "Total",
+            (Language::Es, Message::PaymentQrAlt) => "Código QR de pago",
+            (Language::En, Message::PaymentQrAlt) => "Payment QR code",
+        }
+    }
+}
+
+impl Message {
+    /// Catalogue key used by the `{{t "footer"}}` template helper
+    pub fn from_key(key: &str) -> Option<Self> {
+        match key {
+            "footer" => Some(Message::Footer),
+            "legal" => Some(Message::Legal),
+            "text_body_placeholder" => Some(Message::TextBodyPlaceholder),
+            "fallback_name" => Some(Message::FallbackName),
+            "logo_alt" => Some(Message::LogoAlt),
+            "invoice_number" => Some(Message::InvoiceNumber),
+            "invoice_date" => Some(Message::InvoiceDate),
+            "due_date" => Some(Message::DueDate),
+            "days_overdue" => Some(Message::DaysOverdue),
+            "amount_due" => Some(Message::AmountDue),
+            "total" => Some(Message::Total),
+            "payment_qr_alt" => Some(Message::PaymentQrAlt),
+            _ => None,
+        }
+    }
+}
+
+/// Language of a client's email: `language` or `locale` of the client's custom_data,
+/// then the business `language` or `locale`, then Spanish
+pub fn resolve(custom_data: Option<&Value>, config: &CollectionConfig) -> Language {
+    let client = ["language", "locale"].into_iter()
+        .filter_map(|key| custom_data.and_then(|d| d.get(key)).and_then(|v| v.as_str()));
+    let business = [config.language.as_deref(), config.locale.as_deref()].into_iter().flatten();
+
+    client.chain(business)
+        .find_map(Language::parse)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Language::parse("en-US"), Some(Language::En));
+        assert_eq!(Language::parse(" ES_co "), Some(Language::Es));
+        assert_eq!(Language::parse("pt-BR"), None);
+    }
+
+    #[test]
+    fn test_resolve_precedence() {
+        let config = CollectionConfig { locale: Some("es-CO".to_string()), ..Default::default() };
+        assert_eq!(resolve(None, &config), Language::Es);
+        assert_eq!(resolve(Some(&json!({ "locale": "en-US" })), &config), Language::En);
+        assert_eq!(resolve(Some(&json!({ "language": "fr", "locale": "en" })), &config), Language::En);
+
+        let config = CollectionConfig { language: Some("en".to_string()), locale: Some("es-CO".to_string()), ..Default::default() };
+        assert_eq!(resolve(Some(&json!({})), &config), Language::En);
+        assert_eq!(resolve(Some(&json!({ "language": "es" })), &config), Language::Es);
+    }
+}
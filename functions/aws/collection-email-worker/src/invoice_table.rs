@@ -0,0 +1,274 @@
+use std::cmp::Ordering;
+use handlebars::html_escape;
+use serde_json::Value;
+
+use crate::formatting::{is_amount_field, is_date_field, parse_date, AmountFormat};
+use crate::helpers::parse_number;
+use crate::i18n::{Language, Message};
+use crate::models::{InvoiceTableColumn, InvoiceTableConfig};
+
+/// Columns shown when the business hasn't configured any, if the invoices have them
+const DEFAULT_COLUMNS: [&str; 5] = ["invoice_number", "invoice_date", "due_date", "days_overdue", "amount_due"];
+
+const CELL_STYLE: &str = "padding: 8px; border: 1px solid #e5e7eb; font-size: 14px;";
+const HEADER_STYLE: &str = "padding: 8px; border: 1px solid #e5e7eb; font-size: 14px; font-weight: 600; background-color: #f9fafb;";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Text,
+    Amount,
+    Date,
+    Number,
+}
+
+impl Format {
+    fn of(column: &InvoiceTableColumn) -> Self {
+        match column.format.as_deref().map(|f| f.trim().to_lowercase()).as_deref() {
+            Some("amount") | Some("currency") => Format::Amount,
+            Some("date") => Format::Date,
+            Some("number") => Format::Number,
+            Some("text") => Format::Text,
+            _ if is_amount_field(&column.field) => Format::Amount,
+            _ if is_date_field(&column.field) => Format::Date,
+            _ if column.field.contains("days") => Format::Number,
+            _ => Format::Text,
+        }
+    }
+}
+
+/// How the table is drawn for one email: the business settings plus the amount and date
+/// formats and language of the email
+pub struct InvoiceTable<'a> {
+    pub config: &'a InvoiceTableConfig,
+    pub amount_format: &'a AmountFormat,
+    /// chrono format of the business `output_date_format`
+    pub date_format: &'a str,
+    pub language: Language,
+}
+
+fn default_label(field: &str, language: Language) -> String {
+    let message = match field {
+        "invoice_number" => Message::InvoiceNumber,
+        "invoice_date" => Message::InvoiceDate,
+        "due_date" => Message::DueDate,
+        "days_overdue" => Message::DaysOverdue,
+        "amount_due" => Message::AmountDue,
+        _ => {
+            let words = field.replace(['_', '-'], " ");
+            let mut chars = words.trim().chars();
+            return chars.next().map(|c| c.to_uppercase().chain(chars).collect()).unwrap_or_default();
+        }
+    };
+    language.text(message).to_string()
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Value used to sort by `field`: the raw `_value`/`_iso` kept next to formatted fields
+fn sort_key<'v>(invoice: &'v Value, field: &str) -> &'v Value {
+    invoice.get(format!("{}_value", field))
+        .or_else(|| invoice.get(format!("{}_iso", field)))
+        .or_else(|| invoice.get(field))
+        .unwrap_or(&Value::Null)
+}
+
+fn as_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Numbers compare numerically, everything else as text; missing values sort last
+fn compare(a: &Value, b: &Value) -> Ordering {
+    match (as_number(a), as_number(b)) {
+        (Some(x), Some(y)) => x.partial_cmp(&y).unwrap_or(Ordering::Equal),
+        _ => match (a, b) {
+            (Value::Null, Value::Null) => Ordering::Equal,
+            (Value::Null, _) => Ordering::Greater,
+            (_, Value::Null) => Ordering::Less,
+            _ => text(a).cmp(&text(b)),
+        },
+    }
+}
+
+impl InvoiceTable<'_> {
+    fn columns(&self, invoices: &[Value]) -> Vec<InvoiceTableColumn> {
+        if !self.config.columns.is_empty() {
+            return self.config.columns.clone();
+        }
+        DEFAULT_COLUMNS.iter()
+            .filter(|field| invoices.iter().any(|i| i.get(**field).is_some_and(|v| !v.is_null())))
+            .map(|field| InvoiceTableColumn { field: field.to_string(), ..Default::default() })
+            .collect()
+    }
+
+    /// Cell text of `field`. Amount and date fields arrive formatted by the worker (their
+    /// raw value kept as `_value`/`_iso`); other values are formatted here.
+    fn cell(&self, invoice: &Value, field: &str, format: Format) -> String {
+        let value = invoice.get(field).unwrap_or(&Value::Null);
+        match format {
+            Format::Amount if invoice.get(format!("{}_value", field)).is_none() => {
+                parse_number(value).map(|n| self.amount_format.format(n)).unwrap_or_else(|| text(value))
+            }
+            Format::Date if invoice.get(format!("{}_iso", field)).is_none() => {
+                parse_date(value, None).map(|d| d.format(self.date_format).to_string()).unwrap_or_else(|| text(value))
+            }
+            _ => text(value),
+        }
+    }
+
+    /// Renders `invoices` as an email-safe table; no invoices render nothing
+    pub fn render(&self, invoices: &[Value]) -> String {
+        if invoices.is_empty() {
+            return String::new();
+        }
+
+        let columns = self.columns(invoices);
+        if columns.is_empty() {
+            return String::new();
+        }
+        let formats: Vec<Format> = columns.iter().map(Format::of).collect();
+        let aligns: Vec<&str> = columns.iter().zip(&formats).map(|(column, format)| {
+            match column.align.as_deref().map(str::trim) {
+                Some("center") => "center",
+                Some("right") => "right",
+                Some("left") => "left",
+                _ if matches!(format, Format::Amount | Format::Number) => "right",
+                _ => "left",
+            }
+        }).collect();
+
+        let mut rows: Vec<&Value> = invoices.iter().collect();
+        if let Some(field) = self.config.sort_by.as_deref().filter(|f| !f.trim().is_empty()) {
+            rows.sort_by(|a, b| compare(sort_key(a, field), sort_key(b, field)));
+            if self.config.sort_order.as_deref().is_some_and(|o| o.trim().eq_ignore_ascii_case("desc")) {
+                rows.reverse();
+            }
+        }
+
+        let mut html = String::from(
+            r#"<table class="invoice-table" width="100%" cellpadding="0" cellspacing="0" border="0" style="width: 100%; border-collapse: collapse;"><thead><tr>"#,
+        );
+        for (column, align) in columns.iter().zip(&aligns) {
+            let label = column.label.clone().unwrap_or_else(|| default_label(&column.field, self.language));
+            html.push_str(&format!(
+                r#"<th align="{align}" style="{HEADER_STYLE} text-align: {align};">{}</th>"#,
+                html_escape(&label)
+            ));
+        }
+        html.push_str("</tr></thead><tbody>");
+
+        for invoice in &rows {
+            html.push_str("<tr>");
+            for ((column, format), align) in columns.iter().zip(&formats).zip(&aligns) {
+                html.push_str(&format!(
+                    r#"<td align="{align}" style="{CELL_STYLE} text-align: {align};">{}</td>"#,
+                    html_escape(&self.cell(invoice, &column.field, *format))
+                ));
+            }
+            html.push_str("</tr>");
+        }
+
+        if self.config.show_totals.unwrap_or(true) && formats.contains(&Format::Amount) {
+            html.push_str(&self.totals_row(&rows, &columns, &formats, &aligns));
+        }
+        html.push_str("</tbody></table>");
+        html
+    }
+
+    /// Sums each amount column. Columns mixing invoice currencies are left blank, since
+    /// their sum has no meaning.
+    fn totals_row(&self, rows: &[&Value], columns: &[InvoiceTableColumn], formats: &[Format], aligns: &[&str]) -> String {
+        let mixed_currencies = rows.iter()
+            .filter_map(|i| i.get("currency").and_then(|c| c.as_str()))
+            .any(|c| !c.trim().eq_ignore_ascii_case(&self.amount_format.currency));
+        let label = self.config.totals_label.clone().unwrap_or_else(|| self.language.text(Message::Total).to_string());
+
+        let mut html = String::from("<tr>");
+        for (index, ((column, format), align)) in columns.iter().zip(formats).zip(aligns).enumerate() {
+            let content = match format {
+                Format::Amount if !mixed_currencies => {
+                    let total: f64 = rows.iter()
+                        .filter_map(|i| parse_number(sort_key(i, &column.field)))
+                        .sum();
+                    self.amount_format.format(total)
+                }
+                _ if index == 0 => label.clone(),
+                _ => String::new(),
+            };
+            html.push_str(&format!(
+                r#"<td align="{align}" style="{HEADER_STYLE} text-align: {align};">{}</td>"#,
+                html_escape(&content)
+            ));
+        }
+        html.push_str("</tr>");
+        html
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn table(config: &InvoiceTableConfig, language: Language) -> InvoiceTable<'_> {
+        static FORMAT: std::sync::LazyLock<AmountFormat> = std::sync::LazyLock::new(AmountFormat::default);
+        InvoiceTable { config, amount_format: &FORMAT, date_format: "%d-%m-%Y", language }
+    }
+
+    fn invoices() -> Vec<Value> {
+        vec![
+            json!({ "invoice_number": "F-2", "due_date": "15-02-2026", "due_date_iso": "2026-02-15", "amount_due": "2.500", "amount_due_value": 2500.0 }),
+            json!({ "invoice_number": "F-1<b>", "due_date": "31-01-2026", "due_date_iso": "2026-01-31", "amount_due": "1.500.000", "amount_due_value": 1500000.0 }),
+        ]
+    }
+
+    #[test]
+    fn test_default_columns_and_totals() {
+        let config = InvoiceTableConfig::default();
+        let html = table(&config, Language::Es).render(&invoices());
+
+        assert!(html.contains(">Factura</th>"), "{}", html);
+        assert!(html.contains(">Vencimiento</th>"));
+        assert!(!html.contains(">Fecha</th>"), "columns missing from every invoice are skipped");
+        assert!(html.contains("F-1&lt;b&gt;"), "values are escaped");
+        assert!(html.contains(r#"<td align="right" style="padding: 8px; border: 1px solid #e5e7eb; font-size: 14px; text-align: right;">2.500</td>"#));
+        assert!(html.contains(">Total</td>"));
+        assert!(html.contains(">1.502.500</td>"), "{}", html);
+        assert_eq!(table(&config, Language::En).render(&[]), "");
+    }
+
+    #[test]
+    fn test_configured_columns_and_sorting() {
+        let config = InvoiceTableConfig {
+            columns: vec![
+                InvoiceTableColumn { field: "due_date".to_string(), label: Some("Vence".to_string()), ..Default::default() },
+                InvoiceTableColumn { field: "invoice_number".to_string(), align: Some("center".to_string()), ..Default::default() },
+                InvoiceTableColumn { field: "amount_due".to_string(), ..Default::default() },
+            ],
+            sort_by: Some("due_date".to_string()),
+            sort_order: Some("desc".to_string()),
+            show_totals: Some(false),
+            ..Default::default()
+        };
+        let html = table(&config, Language::En).render(&invoices());
+
+        assert!(html.contains(">Vence</th>"));
+        assert!(html.contains(">Amount</th>"));
+        assert!(html.find("F-2").unwrap() < html.find("F-1").unwrap(), "newest due date first");
+        assert!(html.contains(r#"align="center""#));
+        assert!(!html.contains(">Total<"));
+
+        let config = InvoiceTableConfig { sort_by: Some("amount_due".to_string()), ..Default::default() };
+        let html = table(&config, Language::En).render(&invoices());
+        assert!(html.find("F-2").unwrap() < html.find("F-1").unwrap(), "2.500 sorts before 1.500.000");
+    }
+}
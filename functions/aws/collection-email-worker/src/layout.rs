@@ -0,0 +1,337 @@
+use std::sync::LazyLock;
+use regex::{Captures, Regex};
+
+use crate::i18n::{Language, Message};
+use crate::models::EmailLayout;
+
+const DEFAULT_BACKGROUND: &str = "#f4f4f4";
+const DEFAULT_LINK: &str = "blue";
+const DEFAULT_WIDTH: u32 = 720;
+const DEFAULT_BUTTON: &str = "#2563eb";
+
+// Dark-mode palette: off-black surfaces and off-white text, which clients that invert
+// colours leave alone, unlike pure #000/#fff
+const DARK_BACKGROUND: &str = "#121212";
+const DARK_SURFACE: &str = "#1e1e1e";
+const DARK_HEADER: &str = "#2a2a2a";
+const DARK_BORDER: &str = "#3a3a3a";
+const DARK_TEXT: &str = "#e5e5e5";
+const DARK_MUTED: &str = "#a0a0a0";
+const DARK_LINK: &str = "#8ab4f8";
+
+/// A whole paragraph, or a link outside any paragraph
+static PARAGRAPH_OR_LINK: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?is)(?P<open><p\b[^>]*>)(?P<body>.*?)</p>|<a\b(?P<attrs>[^>]*)>(?P<label>.*?)</a>").unwrap()
+});
+static LINK: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?is)<a\b(?P<attrs>[^>]*)>(?P<label>.*?)</a>").unwrap());
+static BUTTON_MARKER: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?i)\bclass\s*=\s*"[^"]*\b(?:button|btn)\b[^"]*"|\bdata-type\s*=\s*"button""#).unwrap()
+});
+static HREF: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"(?i)\bhref\s*=\s*"([^"]*)""#).unwrap());
+static TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[^>]*>").unwrap());
+
+const PLATFORM_LINK: &str = r#"<a href="https://apex.borls.com" style="color:#999999;font-size:12px;text-align:center" target="_blank">https://apex.borls.com</a>"#;
+
+/// Accepts `#rgb` and `#rrggbb` only, so stored colours can't break out of the CSS
+fn color(value: Option<&str>) -> Option<&str> {
+    value.map(str::trim).filter(|c| {
+        c.starts_with('#') && matches!(c.len(), 4 | 7) && c[1..].chars().all(|d| d.is_ascii_hexdigit())
+    })
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\n', "<br>")
+}
+
+fn non_empty(value: Option<&str>) -> Option<&str> {
+    value.map(str::trim).filter(|v| !v.is_empty())
+}
+
+fn footer_row(content: &str) -> String {
+    format!(
+        r#"<tr>
+                <td style="font-family:sans-serif;vertical-align:top;padding-bottom:10px;padding-top:10px;color:#999999;font-size:12px;text-align:center" valign="top" align="center">
+                <span class="m_58810963162805476apple-link" style="color:#999999;font-size:12px;text-align:center">{content}</span>
+                </td>
+            </tr>"#
+    )
+}
+
+/// Wraps a rendered body in the business layout: logo, brand colours, footer, contact
+/// details and legal text. Fields the business hasn't set keep the default APX layout,
+/// so `EmailLayout::default()` produces the original wrapper. Default texts are in
+/// `language`.
+pub fn wrap_with_layout(html_body: &str, layout: &EmailLayout, language: Language) -> String {
+    let background = color(layout.background_color.as_deref()).unwrap_or(DEFAULT_BACKGROUND);
+    let link = color(layout.link_color.as_deref()).unwrap_or(DEFAULT_LINK);
+    let text = color(layout.text_color.as_deref())
+        .map(|c| format!(" color: {};", c))
+        .unwrap_or_default();
+    let width = layout.container_width.map(|w| w.clamp(480, 800)).unwrap_or(DEFAULT_WIDTH);
+    let accent = color(layout.primary_color.as_deref())
+        .map(|c| format!(" border-top: 4px solid {};", c))
+        .unwrap_or_default();
+
+    let styles = format!(r###"<style>
+        body {{ font-family: Arial, sans-serif; margin: 0; padding: 0; -webkit-text-size-adjust: 100%; -ms-text-size-adjust: 100%; line-height: 1.6;{text} }}
+        table {{ border-collapse: collapse; mso-table-lspace: 0pt; mso-table-rspace: 0pt; width: 100%; margin: 0 auto; }}
+        th, td {{ border: 1px solid #e5e7eb; text-align: left; font-size: 14px; padding: 8px; }}
+        th {{ background-color: #f9fafb; font-weight: 600; }}
+        tr:nth-child(even) {{ background-color: #f9fafb; }}
+        img {{ max-width: 100%; height: auto; display: block; border: 0; outline: none; text-decoration: none; }}
+        p {{ margin-top: 0; margin-bottom: 0.75em; min-height: 1em; }}
+        .preserve-line-breaks {{ white-space: pre-wrap; }}
+        blockquote {{ border-left: 3px solid #e1e4e9; padding-left: 1rem; margin: 1rem 0; font-style: italic; color: #6b7280; }}
+        .table-no-borders th, .table-no-borders td {{ border: none; }}
+        a {{ color: {link}; text-decoration: underline; }}
+    </style>
+    "###);
+
+    let logo = match non_empty(layout.logo_url.as_deref()) {
+        Some(url) => format!(
+            r#"<tr>
+                            <td align="center" style="padding: 20px 20px 0 20px; border: 0;">
+                                <img src="{}" alt="{}" style="max-width: 200px; max-height: 80px; height: auto; display: block; margin: 0 auto;">
+                            </td>
+                        </tr>
+                        "#,
+            escape(url),
+            escape(non_empty(layout.brand_name.as_deref()).unwrap_or(language.text(Message::LogoAlt))),
+        ),
+        None => String::new(),
+    };
+
+    let mut footer = vec![footer_row(
+        &non_empty(layout.footer_text.as_deref()).map(escape).unwrap_or_else(|| language.text(Message::Footer).to_string()),
+    )];
+
+    let contact: Vec<String> = [
+        non_empty(layout.contact_email.as_deref())
+            .map(|e| format!(r#"<a href="mailto:{0}" style="color:#999999;font-size:12px">{0}</a>"#, escape(e))),
+        non_empty(layout.contact_phone.as_deref()).map(escape),
+        non_empty(layout.contact_address.as_deref()).map(escape),
+    ].into_iter().flatten().collect();
+    if !contact.is_empty() {
+        footer.push(footer_row(&contact.join(" · ")));
+    }
+
+    footer.push(footer_row(
+        &non_empty(layout.legal_text.as_deref())
+            .map(escape)
+            .unwrap_or_else(|| format!("{} | {}", language.text(Message::Legal), PLATFORM_LINK)),
+    ));
+    let footer = footer.join("\n            ");
+
+    format!(
+        r###"<!DOCTYPE html>
+        <html lang="{lang}" xmlns:v="urn:schemas-microsoft-com:vml" xmlns:o="urn:schemas-microsoft-com:office:office">
+        <head>
+        <meta charset="utf-8">
+        <meta name="viewport" content="width=device-width, initial-scale=1.0">
+        <meta http-equiv="X-UA-Compatible" content="IE=edge">
+        <meta name="color-scheme" content="light dark">
+        <meta name="supported-color-schemes" content="light dark">
+        <!--[if mso]>
+        <noscript><xml><o:OfficeDocumentSettings><o:AllowPNG/><o:PixelsPerInch>96</o:PixelsPerInch></o:OfficeDocumentSettings></xml></noscript>
+        <![endif]-->
+        {styles}
+        </head>
+        <body class="email-bg" style="margin: 0; padding: 0; background-color: {background};">
+        <table role="presentation" class="email-bg" bgcolor="{background}" width="100%" cellpadding="0" cellspacing="0" border="0" style="width: 100%; border-collapse: collapse; border: 0; border-spacing: 0; background-color: {background};">
+            <tr>
+                <td align="center" style="padding: 0;">
+                    <!--[if mso]><table role="presentation" align="center" width="{width}" cellpadding="0" cellspacing="0" border="0"><tr><td><![endif]-->
+                    <table role="presentation" class="email-container" bgcolor="#ffffff" width="100%" cellpadding="0" cellspacing="0" border="0" style="width: 100%; max-width: {width}px; border-collapse: collapse; border: 0; border-spacing: 0; background-color: #ffffff;{accent}">
+                        {logo}<tr>
+                            <td style="padding: 20px;">
+                                {html_body}
+                            </td>
+                        </tr>
+                    </table>
+                    <!--[if mso]></td></tr></table><![endif]-->
+                </td>
+            </tr>
+        </table>
+        <table role="presentation" class="email-footer" border="0" cellpadding="0" cellspacing="0" style="border-collapse:separate;width:100%" width="100%">
+            <tbody>
+            {footer}
+            </tbody>
+        </table>
+        </body>
+        </html>"###,
+        lang = language.code(),
+    )
+}
+
+/// Dark-mode overrides, added after CSS inlining because the inliner drops `<style>`
+/// blocks and can't inline media queries. Clients that honour `prefers-color-scheme`
+/// (Apple Mail, iOS, Outlook for Mac) use the `@media` rules; Outlook.com marks its
+/// dark mode with `data-ogsc`/`data-ogsb` instead.
+pub fn add_dark_mode_styles(html: &str, layout: &EmailLayout) -> String {
+    let link = color(layout.link_color.as_deref()).unwrap_or(DARK_LINK);
+    let rules = format!(
+        r#".email-bg {{ background-color: {DARK_BACKGROUND} !important; }}
+            .email-container {{ background-color: {DARK_SURFACE} !important; color: {DARK_TEXT} !important; }}
+            .email-container th {{ background-color: {DARK_HEADER} !important; color: {DARK_TEXT} !important; }}
+            .email-container tr {{ background-color: transparent !important; }}
+            .email-container th, .email-container td {{ border-color: {DARK_BORDER} !important; }}
+            .email-container a {{ color: {link} !important; }}
+            .email-container a.email-button {{ color: #ffffff !important; }}
+            .email-footer td, .email-footer span, .email-footer a {{ color: {DARK_MUTED} !important; }}"#
+    );
+    let ogsc: String = rules.lines()
+        .map(|rule| format!("\n        [data-ogsc] {}", rule.trim().replace(", .", ", [data-ogsc] .")))
+        .collect();
+    let style = format!(
+        r#"<style>
+        :root {{ color-scheme: light dark; supported-color-schemes: light dark; }}
+        @media (prefers-color-scheme: dark) {{
+            {rules}
+        }}{ogsc}
+        </style>
+        "#
+    );
+
+    match html.find("</head>") {
+        Some(index) => format!("{}{}{}", &html[..index], style, &html[index..]),
+        None => html.to_string(),
+    }
+}
+
+/// Turns links marked as buttons (`class="button"`, `class="btn"` or
+/// `data-type="button"`) into bulletproof buttons: a padded table cell for most
+/// clients and a VML rounded rectangle for desktop Outlook, which ignores padding and
+/// border-radius on links. Colours come from the business primary colour. A table can't
+/// sit inside a `<p>`, so paragraphs are split around their buttons.
+pub fn bulletproof_buttons(html_body: &str, layout: &EmailLayout) -> String {
+    let fill = color(layout.primary_color.as_deref()).unwrap_or(DEFAULT_BUTTON);
+
+    PARAGRAPH_OR_LINK.replace_all(html_body, |caps: &Captures| {
+        let (Some(open), Some(body)) = (caps.name("open"), caps.name("body")) else {
+            return button(&caps["attrs"], &caps["label"], fill).unwrap_or_else(|| caps[0].to_string());
+        };
+
+        let mut html = String::new();
+        let mut text_start = 0;
+        for link in LINK.captures_iter(body.as_str()) {
+            let Some(button) = button(&link["attrs"], &link["label"], fill) else {
+                continue;
+            };
+            let whole = link.get(0).unwrap();
+            let text = &body.as_str()[text_start..whole.start()];
+            if !text.trim().is_empty() {
+                html.push_str(&format!("{}{}</p>", open.as_str(), text));
+            }
+            html.push_str(&button);
+            text_start = whole.end();
+        }
+        if text_start == 0 {
+            return caps[0].to_string();
+        }
+        let text = &body.as_str()[text_start..];
+        if !text.trim().is_empty() {
+            html.push_str(&format!("{}{}</p>", open.as_str(), text));
+        }
+        html
+    }).to_string()
+}
+
+/// The bulletproof version of a link, if it is marked as a button and has an href
+fn button(attrs: &str, label: &str, fill: &str) -> Option<String> {
+    if !BUTTON_MARKER.is_match(attrs) {
+        return None;
+    }
+    let href = HREF.captures(attrs)?[1].to_string();
+    let label = TAG.replace_all(label, "").trim().to_string();
+
+    Some(format!(
+        r#"<table role="presentation" align="center" cellpadding="0" cellspacing="0" border="0" style="width: auto; margin: 16px auto; border-collapse: separate;">
+                <tr>
+                    <td align="center" bgcolor="{fill}" style="border: 0; border-radius: 6px; padding: 0; text-align: center; background-color: {fill};">
+                        <!--[if mso]><v:roundrect xmlns:v="urn:schemas-microsoft-com:vml" xmlns:w="urn:schemas-microsoft-com:office:word" href="{href}" style="height:44px;v-text-anchor:middle;width:240px;" arcsize="14%" stroke="f" fillcolor="{fill}"><w:anchorlock/><center style="color:#ffffff;font-family:Arial,sans-serif;font-size:16px;font-weight:bold;">{label}</center></v:roundrect><![endif]-->
+                        <!--[if !mso]><!--><a href="{href}" class="email-button" target="_blank" style="display: inline-block; padding: 12px 24px; font-family: Arial, sans-serif; font-size: 16px; font-weight: bold; line-height: 20px; color: #ffffff; background-color: {fill}; border-radius: 6px; text-decoration: none;">{label}</a><!--<![endif]-->
+                    </td>
+                </tr>
+            </table>"#
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_layout_keeps_the_apx_footer() {
+        let html = wrap_with_layout("<p>Hola</p>", &EmailLayout::default(), Language::Es);
+
+        assert!(html.contains("width: 720px"));
+        assert!(html.contains("background-color: #f4f4f4"));
+        assert!(html.contains("a { color: blue;"));
+        assert!(html.contains("Por favor responda a este correo"));
+        assert!(html.contains("https://apex.borls.com"));
+        assert!(!html.contains("<img"));
+
+        let html = wrap_with_layout("<p>Hello</p>", &EmailLayout::default(), Language::En);
+        assert!(html.contains("Please reply to this email"));
+        assert!(html.contains("All rights reserved | <a href=\"https://apex.borls.com\""));
+    }
+
+    #[test]
+    fn test_business_branding() {
+        let layout = EmailLayout {
+            brand_name: Some("Ferretería \"El Tornillo\"".to_string()),
+            logo_url: Some("https://x.supabase.co/storage/v1/object/public/business-media/images/apex/b1/logo.png".to_string()),
+            primary_color: Some("#0a7cff".to_string()),
+            background_color: Some("#eeeeee".to_string()),
+            link_color: Some("red; } body { display:none".to_string()),
+            container_width: Some(2000),
+            footer_text: Some("Cartera <Tornillo>".to_string()),
+            contact_email: Some("cartera@tornillo.co".to_string()),
+            contact_phone: Some("+57 300 000 0000".to_string()),
+            legal_text: Some("NIT 900.000.000-1".to_string()),
+            ..Default::default()
+        };
+        let html = wrap_with_layout("<p>Hola</p>", &layout, Language::Es);
+
+        assert!(html.contains(r#"alt="Ferretería &quot;El Tornillo&quot;""#));
+        assert!(html.contains("business-media/images/apex/b1/logo.png"));
+        assert!(html.contains("border-top: 4px solid #0a7cff"));
+        assert!(html.contains("background-color: #eeeeee"));
+        assert!(html.contains("a { color: blue;"), "invalid colours fall back");
+        assert!(html.contains("width: 800px"));
+        assert!(html.contains("Cartera &lt;Tornillo&gt;"));
+        assert!(html.contains("mailto:cartera@tornillo.co"));
+        assert!(html.contains("+57 300 000 0000"));
+        assert!(html.contains("NIT 900.000.000-1"));
+        assert!(!html.contains("apex.borls.com"));
+    }
+
+    #[test]
+    fn test_bulletproof_buttons() {
+        let layout = EmailLayout { primary_color: Some("#0a7cff".to_string()), ..Default::default() };
+        let html = bulletproof_buttons(
+            r#"<p><a data-type="button" href="https://pay.example">Pagar</a></p><p>Ver <a href="https://example.com">detalle</a> o <a class="btn" href="https://x.example">ir</a></p>"#,
+            &layout,
+        );
+
+        assert!(html.starts_with("<table role=\"presentation\""), "a lone button replaces its paragraph: {}", html);
+        assert!(html.contains(r##"fillcolor="#0a7cff""##));
+        assert!(html.contains(r#"<p>Ver <a href="https://example.com">detalle</a> o </p><table"#), "{}", html);
+        assert!(html.ends_with("</table>"), "{}", html);
+
+        let html = bulletproof_buttons(
+            r#"<p class="lead">Antes <a class="button" href="https://pay.example">Pagar</a> después</p><div><a class="btn" href="https://x.example">ir</a></div>"#,
+            &layout,
+        );
+        assert!(html.starts_with(r#"<p class="lead">Antes </p><table"#), "{}", html);
+        assert!(html.contains(r#"</table><p class="lead"> después</p><div><table"#), "{}", html);
+        for paragraph in html.split("<p").skip(1) {
+            let inside = paragraph.split("</p>").next().unwrap();
+            assert!(!inside.contains("<table"), "no table inside a paragraph: {}", html);
+        }
+    }
+}
@@ -2,10 +2,9 @@ use lambda_runtime::{service_fn, LambdaEvent};
 use serde_json::{Value, json};
 use simple_logger::SimpleLogger;
 use log::{info, error, warn};
-use handlebars::Handlebars;
 use std::error::Error;
+use std::sync::LazyLock;
 use regex::Regex;
-use rusty_money::{Money, iso};
 use css_inline::{CSSInliner, InlineOptions};
 use aws_config::BehaviorVersion;
 use aws_sdk_scheduler::{Client as SchedulerClient, types::{Target, FlexibleTimeWindow, FlexibleTimeWindowMode, ActionAfterCompletion}};
@@ -19,11 +18,37 @@ mod email_provider;
 mod factory;
 mod providers;
 mod control_tower;
+mod metrics;
+mod outbox;
+mod prioritization;
+mod fair_scheduler;
+mod tiptap;
+mod helpers;
+mod formatting;
+mod plain_text;
+mod template_cache;
+mod template_lint;
+mod escaping;
+mod layout;
+mod i18n;
+mod invoice_table;
+mod partials;
+mod aging;
+mod payment_link;
+mod payment_qr;
+mod storage_images;
+#[cfg(test)]
+mod golden;
 
 use supabase::SupabaseService;
-use email_provider::{EmailProvider, EmailMessage};
+use email_provider::{EmailProvider, EmailMessage, InlineImage};
 
 use control_tower::ExecutionLogger;
+use metrics::{Metrics, Unit};
+use outbox::OutboxClaim;
+use prioritization::PriorityStrategy;
+use template_cache::{BatchTemplates, CompiledTemplate};
+use formatting::{AmountFormat, DateFormat, RenderWarning};
 
 #[tokio::main]
 async fn main() -> Result<(), lambda_runtime::Error> {
@@ -34,146 +59,44 @@ async fn main() -> Result<(), lambda_runtime::Error> {
     Ok(())
 }
 
-fn format_currency(val: f64) -> String {
-    let money = Money::from_str(&format!("{:.2}", val), iso::COP).unwrap_or_else(|_| {
-        Money::from_major(val as i64, iso::COP)
-    });
-    
-    let amount_str = money.amount().to_string(); 
-    let parts: Vec<&str> = amount_str.split('.').collect();
-    let integer_part = parts[0];
-    
-    let mut result = String::new();
-    let mut count = 0;
-    for c in integer_part.chars().rev() {
-        if count > 0 && count % 3 == 0 {
-            result.push('.');
-        }
-        result.push(c);
-        count += 1;
-    }
-    
-    result.chars().rev().collect::<String>()
-}
+static MUSTACHE_EXPRESSION: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\{\{\{?[^}]*\}?\}\}").unwrap());
+static TIPTAP_TABLE_OPEN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"(?i)<table\b[^>]*class="[^"]*\btiptap-table\b[^"]*"[^>]*>"#).unwrap());
+static TABLE_TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?i)<table\b[^>]*>|</table\s*>").unwrap());
+static TABLE_WIDTH: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?i)\swidth\s*=").unwrap());
+static EMPTY_PARAGRAPH: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"(?i)<p(?P<attrs>[^>]*)>\s*</p>"#).unwrap());
 
-fn get_f64(v: &serde_json::Value) -> f64 {
-    if let Some(f) = v.as_f64() {
-        f
-    } else if let Some(s) = v.as_str() {
-        s.replace(",", "").parse::<f64>().unwrap_or(0.0)
-    } else {
-        0.0
-    }
+/// Subject used when the subject template fails: the literal text with every
+/// `{{...}}` expression removed, so placeholders never reach the inbox
+fn fallback_subject(subject: &str) -> String {
+    plain_text::subject_line(&MUSTACHE_EXPRESSION.replace_all(subject, " "))
 }
 
-fn fix_table_colspan(html: &str) -> String {
-    let mut processed = html.to_string();
+/// Gives TipTap tables a horizontally scrollable wrapper on mobile clients. The wrapper
+/// div is hidden from desktop Outlook, which ignores its overflow and breaks the table
+/// width, and the table gets a `width` attribute Outlook honours.
+fn enhance_invoice_tables(html: &str) -> String {
+    if !TIPTAP_TABLE_OPEN.is_match(html) {
+        return html.to_string();
+    }
 
-    // Fix 1: Replace rows with 5+ colspan="0" cells containing Handlebars helpers
-    // Pattern: <tr>...<td colspan="0"...><p>{{#each invoices}}</p></td><td colspan="0"...><p></p></td>...</tr>
-    let re_helper_row = Regex::new(
-        r#"(?is)<tr[^>]*>(.*?)</tr>"#
-    ).unwrap();
-    
-    let re_colspan_zero_cell = Regex::new(
-        r#"(?is)<td[^>]*colspan=["']0["'][^>]*>"#
-    ).unwrap();
-    
-    processed = re_helper_row.replace_all(&processed, |caps: &regex::Captures| {
-        let row_content = &caps[1];
-        let full_row = caps[0].to_string();
-        
-        // Count colspan="0" cells in this row
-        let colspan_zero_count = re_colspan_zero_cell.find_iter(row_content).count();
-        
-        // If 5+ colspan="0" cells, consolidate them
-        if colspan_zero_count >= 5 {
-            // Check if this row contains a Handlebars helper
-            let re_helper = Regex::new(r#"\{\{[/#!]?\s*\w+[^}]*\}\}"#).unwrap();
-            
-            if re_helper.is_match(row_content) {
-                // Find all helpers in this row
-                let helpers: Vec<_> = re_helper.find_iter(row_content).map(|m| m.as_str()).collect();
-                
-                if !helpers.is_empty() {
-                    // Replace the entire row with properly formatted cells
-                    let mut new_row = String::from("<tr>");
-                    for helper in helpers {
-                        new_row.push_str(&format!(
-                            r#"<td colspan="5" style="padding: 8px; border: 1px solid rgb(229, 231, 235);"><p>{}</p></td>"#,
-                            helper
-                        ));
-                    }
-                    new_row.push_str("</tr>");
-                    return new_row;
-                }
+    // Tables can nest, so each close tag is matched with its own open tag
+    let mut open_tables = Vec::new();
+    TABLE_TAG.replace_all(html, |caps: &regex::Captures| {
+        let tag = &caps[0];
+        if !tag.starts_with("</") {
+            let tiptap = TIPTAP_TABLE_OPEN.is_match(tag);
+            open_tables.push(tiptap);
+            if !tiptap {
+                return tag.to_string();
             }
+            let tag = if TABLE_WIDTH.is_match(tag) { tag.to_string() } else { tag.replacen("<table", r#"<table width="100%""#, 1) };
+            return format!(r#"<!--[if !mso]><!--><div style="margin: 0 auto; overflow-x: auto;"><!--<![endif]-->{}"#, tag);
         }
-        
-        full_row
-    }).to_string();
-
-    // Fix 2: Clean up remaining consecutive empty colspan="0" cells
-    let re_empty_cells = Regex::new(
-        r#"(?is)(<td[^>]*colspan=["']0["'][^>]*>\s*<p>\s*</p>\s*</td>)(?:\s*<td[^>]*colspan=["']0["'][^>]*>\s*<p>\s*</p>\s*</td>){2,}"#
-    ).unwrap();
-
-    processed = re_empty_cells.replace_all(&processed, |caps: &regex::Captures| {
-        // Replace with a single empty colspan="5" cell
-        r#"<td colspan="5" style="padding: 8px; border: 1px solid rgb(229, 231, 235);"><p>&nbsp;</p></td>"#.to_string()
-    }).to_string();
-
-    processed
-}
-
-fn preprocess_tiptap_template(template_str: &str) -> String {
-    let mut processed = template_str.to_string();
-
-    // Fix colspan="0" issues before processing helpers
-    processed = fix_table_colspan(&processed);
-
-    // Strip <tr><td[...]>[<p>]{{#each}}|{{/each}}[</p>]</td><td></td|<td><p></p></td>*</tr>
-    // TipTap wraps cell content in <p> tags and empty cells become <td><p></p></td>
-    let re_helper = Regex::new(
-        r"(?is)<tr[^>]*>\s*<td[^>]*>(?:\s*<[^>]+>)*\s*(\{\{[/#!][^}]+\}\})\s*(?:</[^>]+>)*\s*</td>(?:\s*<td[^>]*>(?:\s*<[^>]+>\s*</[^>]+>)?\s*</td>)*\s*</tr>"
-    ).unwrap();
-    processed = re_helper.replace_all(&processed, "$1").to_string();
-
-    processed = processed.replace("\n{{#", "{{#").replace("\n{{/", "{{/");
-
-    processed
-}
-
-fn render_template(template_str: &str, data: &serde_json::Value) -> Result<String, Box<dyn Error + Send + Sync>> {
-    let mut handlebars = Handlebars::new();
-    handlebars.register_escape_fn(handlebars::no_escape);
-    
-    let processed_template = preprocess_tiptap_template(template_str);
-    
-    let rendered = handlebars.render_template(&processed_template, data)?;
-    Ok(rendered)
-}
-
-fn enhance_invoice_tables(html: &str) -> String {
-    let mut processed = html.to_string();
-    
-    // Find and enhance invoice tables specifically
-    let table_regex = Regex::new(r"(?i)<table[^>]*class=.*tiptap-table.*>").unwrap();
-    if table_regex.is_match(&processed) {
-        // Wrap tables in a container for better width control
-        processed = table_regex.replace_all(&processed, |caps: &regex::Captures| {
-            let table_tag = &caps[0];
-            format!(r#"<div style="margin: 0 auto; overflow-x: auto;">{}"#, table_tag)
-        }).to_string();
-        
-        // Close the div after the table
-        processed = Regex::new(r"(?i)</table>")
-            .unwrap()
-            .replace_all(&processed, "</table></div>")
-            .to_string();
-    }
-    
-    processed
+        match open_tables.pop() {
+            Some(true) => format!("{}<!--[if !mso]><!--></div><!--<![endif]-->", tag),
+            _ => tag.to_string(),
+        }
+    }).to_string()
 }
 
 fn preserve_line_breaks(html: &str) -> String {
@@ -190,8 +113,7 @@ fn fix_empty_paragraphs(html: &str) -> String {
     let mut processed = html.to_string();
     
     // Replace empty <p> with &nbsp; to prevent collapse
-    let re_empty_p = Regex::new(r#"(?i)<p(?P<attrs>[^>]*)>\s*</p>"#).unwrap();
-    processed = re_empty_p.replace_all(&processed, |caps: &regex::Captures| {
+    processed = EMPTY_PARAGRAPH.replace_all(&processed, |caps: &regex::Captures| {
         let attrs = &caps["attrs"];
         if attrs.trim().is_empty() {
             "<p>&nbsp;</p>".to_string()
@@ -213,65 +135,8 @@ fn inline_css(html: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
     Ok(inlined)
 }
 
-fn wrap_with_styles(html_body: &str) -> String {
-    let styles = r###"<style>
-        body { font-family: Arial, sans-serif; margin: 0; padding: 0; -webkit-text-size-adjust: 100%; -ms-text-size-adjust: 100%; line-height: 1.6; }
-        table { border-collapse: collapse; mso-table-lspace: 0pt; mso-table-rspace: 0pt; width: 100%; margin: 0 auto; }
-        th, td { border: 1px solid #e5e7eb; text-align: left; font-size: 14px; padding: 8px; }
-        th { background-color: #f9fafb; font-weight: 600; }
-        tr:nth-child(even) { background-color: #f9fafb; }
-        img { max-width: 100%; height: auto; display: block; border: 0; outline: none; text-decoration: none; }
-        p { margin-top: 0; margin-bottom: 0.75em; min-height: 1em; }
-        .preserve-line-breaks { white-space: pre-wrap; }
-        blockquote { border-left: 3px solid #e1e4e9; padding-left: 1rem; margin: 1rem 0; font-style: italic; color: #6b7280; }
-        .table-no-borders th, .table-no-borders td { border: none; }
-        a { color: blue; text-decoration: underline; }
-    </style>
-    "###;
-        
-    format!(
-        r###"<!DOCTYPE html>
-        <html>
-        <head>
-        <meta charset="utf-8">
-        <meta name="viewport" content="width=device-width, initial-scale=1.0">
-        {styles}
-        </head>
-        <body style="margin: 0; padding: 0; background-color: #f4f4f4;">
-        <table role="presentation" style="width: 100%; border-collapse: collapse; border: 0; border-spacing: 0; background-color: #f4f4f4;">
-            <tr>
-                <td align="center" style="padding: 0;">
-                    <table role="presentation" style="width: 720px; max-width: 720px; border-collapse: collapse; border: 0; border-spacing: 0; background-color: #ffffff;">
-                        <tr>
-                            <td style="padding: 20px;">
-                                {html_body}
-                            </td>
-                        </tr>
-                    </table>
-                </td>
-            </tr>
-        </table>
-        <table role="presentation" border="0" cellpadding="0" cellspacing="0" style="border-collapse:separate;width:100%" width="100%">
-            <tbody>
-            <tr>
-                <td style="font-family:sans-serif;vertical-align:top;padding-bottom:10px;padding-top:10px;color:#999999;font-size:12px;text-align:center" valign="top" align="center">
-                <span class="m_58810963162805476apple-link" style="color:#999999;font-size:12px;text-align:center">Por favor responda a este correo o comuníquese directamente con el comercio a través del contacto compartido</span>
-                </td>
-            </tr>
-            <tr>
-                <td style="font-family:sans-serif;vertical-align:top;padding-bottom:10px;padding-top:10px;color:#999999;font-size:12px;text-align:center" valign="top" align="center">
-                <span class="m_58810963162805476apple-link" style="color:#999999;font-size:12px;text-align:center">Notificacion automatica de Carteras - APX - Plataforma para la gestión inteligente de Cartera, propiedad de BORLS © 2026 Todos los derechos reservados | <a href="https://apex.borls.com" style="color:#999999;font-size:12px;text-align:center" target="_blank">https://apex.borls.com</a></span>
-                </td>
-            </tr>
-            </tbody>
-        </table>
-        </body>
-        </html>"###, 
-    )
-}
-
 async fn func(event: LambdaEvent<Value>) -> Result<Value, lambda_runtime::Error> {
-    let (payload, _context) = event.into_parts();
+    let (payload, context) = event.into_parts();
     let worker_id = uuid::Uuid::new_v4().to_string();
     
     // Log environment configuration
@@ -314,6 +179,7 @@ async fn func(event: LambdaEvent<Value>) -> Result<Value, lambda_runtime::Error>
                 provider.as_ref(),
                 &scheduler_client,
                 &logger,
+                batch_stop_at(context.deadline),
             ).await {
                 Ok(count) => processed = count,
                 Err(e) => {
@@ -322,6 +188,92 @@ async fn func(event: LambdaEvent<Value>) -> Result<Value, lambda_runtime::Error>
                 }
             }
         }
+        ("dry_run", Some(exec_id)) => {
+            info!("Action 'dry_run' for execution {}", exec_id);
+            let client_ids: Option<Vec<String>> = payload.get("client_ids")
+                .and_then(|v| serde_json::from_value(v.clone()).ok());
+            let limit = payload.get("limit").and_then(|v| v.as_u64()).unwrap_or(5).clamp(1, 50) as usize;
+
+            match dry_run_execution(exec_id, client_ids, limit, &supabase, provider.as_ref()).await {
+                Ok(previews) => {
+                    return Ok(json!({
+                        "status": "completed",
+                        "worker_id": worker_id,
+                        "dry_run": previews
+                    }));
+                }
+                Err(e) => {
+                    error!("dry_run failed for {}: {}", exec_id, e);
+                    failed = 1;
+                }
+            }
+        }
+        ("validate_template", exec_id) => {
+            let Some(template_id) = payload.get("template_id").and_then(|v| v.as_str()) else {
+                warn!("validate_template requires a template_id. Payload: {:?}", payload);
+                return Ok(json!({ "status": "failed", "worker_id": worker_id, "error": "template_id is required" }));
+            };
+            info!("Action 'validate_template' for template {} (sample execution: {:?})", template_id, exec_id);
+
+            let business_id = payload.get("business_id").and_then(|v| v.as_str());
+            match validate_template_from_db(template_id, exec_id, business_id, &supabase).await {
+                Ok(diagnostics) => {
+                    let valid = !diagnostics.iter().any(|d| d.severity == template_lint::Severity::Error);
+                    return Ok(json!({
+                        "status": "completed",
+                        "worker_id": worker_id,
+                        "template_id": template_id,
+                        "valid": valid,
+                        "diagnostics": diagnostics
+                    }));
+                }
+                Err(e) => {
+                    error!("validate_template failed for {}: {}", template_id, e);
+                    failed = 1;
+                }
+            }
+        }
+        ("verify_payment_token", _) => {
+            // For the payment page: checks a token from a `payment_url` before showing the amount
+            let token = payload.get("token").and_then(|v| v.as_str()).unwrap_or_default();
+            let secret = std::env::var("PAYMENT_LINK_SECRET").unwrap_or_default();
+            if secret.trim().is_empty() {
+                error!("verify_payment_token: PAYMENT_LINK_SECRET is not set");
+                return Ok(json!({ "status": "failed", "worker_id": worker_id, "error": "Payment links are not configured" }));
+            }
+
+            return Ok(match payment_link::verify(token, secret.as_bytes(), Utc::now()) {
+                Ok(claims) => json!({
+                    "status": "completed",
+                    "worker_id": worker_id,
+                    "valid": true,
+                    "client_id": claims.client_id,
+                    "invoice_number": claims.invoice_number,
+                    "amount": claims.amount,
+                    "expires_at": DateTime::from_timestamp(claims.expires_at, 0).map(|dt| dt.to_rfc3339())
+                }),
+                Err(e) => {
+                    warn!("verify_payment_token rejected a token: {}", e);
+                    json!({ "status": "completed", "worker_id": worker_id, "valid": false, "error": e.to_string() })
+                }
+            });
+        }
+        ("reconcile_outbox", exec_id) => {
+            info!("Action 'reconcile_outbox' (execution filter: {:?})", exec_id);
+            match outbox::reconcile(&supabase, provider.as_ref(), &logger, exec_id).await {
+                Ok(summary) => {
+                    return Ok(json!({
+                        "status": "completed",
+                        "worker_id": worker_id,
+                        "reconcile": summary
+                    }));
+                }
+                Err(e) => {
+                    error!("reconcile_outbox failed: {}", e);
+                    failed = 1;
+                }
+            }
+        }
         _ => {
             warn!("Unexpected action '{}' or missing execution_id. Payload: {:?}", action, payload);
         }
@@ -335,13 +287,152 @@ async fn func(event: LambdaEvent<Value>) -> Result<Value, lambda_runtime::Error>
     }))
 }
 
+/// Renders the emails of up to `limit` clients of an execution without sending anything
+/// or changing any status. Returns subject, bodies and render warnings per client.
+async fn dry_run_execution(
+    execution_id: &str,
+    client_ids: Option<Vec<String>>,
+    limit: usize,
+    supabase: &SupabaseService,
+    provider: &dyn EmailProvider,
+) -> Result<Vec<Value>, Box<dyn Error + Send + Sync>> {
+    let execution = supabase.get_execution(execution_id).await?;
+    let config = supabase.get_collection_config(&execution.business_id).await.unwrap_or_else(|e| {
+        warn!("[dry_run] Failed to load collection config for business {}: {}", execution.business_id, e);
+        Default::default()
+    });
+
+    let mut clients = match client_ids {
+        Some(ids) => supabase.get_clients_by_ids(&ids).await?,
+        None => supabase.get_execution_clients(execution_id, limit).await?,
+    };
+    clients.truncate(limit);
+
+    let email_layout = supabase.get_email_layout(&execution.business_id).await.unwrap_or_else(|e| {
+        warn!("[dry_run] Failed to load email layout for business {}: {}", execution.business_id, e);
+        None
+    }).unwrap_or_default();
+    let template_partials = supabase.get_template_partials(&execution.business_id).await.unwrap_or_else(|e| {
+        warn!("[dry_run] Failed to load template partials for business {}: {}", execution.business_id, e);
+        Vec::new()
+    });
+    let timezone = supabase.get_business_timezone(&execution.business_id).await.unwrap_or_else(|e| {
+        warn!("[dry_run] Failed to load timezone for business {}: {}", execution.business_id, e);
+        None
+    });
+    // Same provider capabilities and limits as a real send, so the preview matches it
+    let batch = BatchContext::new(&config, timezone.as_deref(), provider.supports_inline_images());
+    let mut templates = BatchTemplates::new(config.rich_text_fields(), template_partials);
+    let mut storage_images = (config.inline_storage_images.unwrap_or(false) && provider.supports_inline_images()).then(|| {
+        storage_images::StorageImages::new(supabase.base_url(), storage_images::EmbedLimits::for_provider(provider.provider_name()))
+    });
+    let mut previews = Vec::with_capacity(clients.len());
+
+    for client in &clients {
+        let Some(template_id) = client.email_template_id.clone().or_else(|| execution.email_template_id.clone()) else {
+            previews.push(json!({ "client_id": client.id, "error": "No email template configured" }));
+            continue;
+        };
+
+        let template = match templates.get(supabase, &template_id).await {
+            Ok(template) => template,
+            Err(e) => {
+                previews.push(json!({
+                    "client_id": client.id,
+                    "template_id": template_id,
+                    "error": e
+                }));
+                continue;
+            }
+        };
+
+        let mut rendered = render_with_mode(&mut templates, supabase, &template, client, &config, &email_layout, &batch).await;
+        if let Some(images) = &mut storage_images {
+            embed_storage_images(images, supabase, &mut rendered).await;
+        }
+        previews.push(json!({
+            "client_id": client.id,
+            "template_id": rendered.template_id,
+            "to": client.emails(),
+            "subject": rendered.subject,
+            "html_body": rendered.html_body,
+            "text_body": rendered.text_body,
+            "render_error": rendered.render_error,
+            "fallback_used": rendered.fallback_used,
+            "partials": rendered.partials,
+            // Inline images as data URIs, for previews that can't resolve `cid:` sources
+            "inline_images": rendered.inline_images.iter().map(|image| json!({
+                "content_id": image.content_id,
+                "data_uri": format!(
+                    "data:{};base64,{}",
+                    image.content_type,
+                    base64::Engine::encode(&base64::engine::general_purpose::STANDARD, &image.data)
+                )
+            })).collect::<Vec<_>>(),
+            "warnings": rendered.warnings
+        }));
+    }
+
+    info!("[dry_run] Rendered {} previews for execution {}", previews.len(), execution_id);
+    Ok(previews)
+}
+
+/// Lints a stored template. Variables are checked against the standard client and invoice
+/// schema, extended with the data of up to 50 clients of `execution_id` when given.
+/// Partials are checked against those of `business_id`, or of the execution's business.
+async fn validate_template_from_db(
+    template_id: &str,
+    execution_id: Option<&str>,
+    business_id: Option<&str>,
+    supabase: &SupabaseService,
+) -> Result<Vec<template_lint::Diagnostic>, Box<dyn Error + Send + Sync>> {
+    let template = supabase.get_template(template_id).await?;
+
+    let mut schema = template_lint::TemplateSchema::default();
+    let mut business_id = business_id.map(str::to_string);
+    if let Some(exec_id) = execution_id {
+        let clients = supabase.get_execution_clients(exec_id, 50).await?;
+        schema = schema.with_clients(&clients);
+        if business_id.is_none() {
+            business_id = Some(supabase.get_execution(exec_id).await?.business_id);
+        }
+    }
+    if let Some(business_id) = business_id {
+        let partials = supabase.get_template_partials(&business_id).await?;
+        schema = schema.with_partials(&partials);
+    }
+
+    let diagnostics = template_lint::validate_template(&template, &schema);
+    info!("[validate_template] Template {}: {} diagnostics", template_id, diagnostics.len());
+    Ok(diagnostics)
+}
+
+/// Last moment a batch may start a send: the Lambda deadline (ms since the epoch) minus
+/// BATCH_DEADLINE_RESERVE_SECONDS (default 60), which covers the retries of the last
+/// send and rescheduling the rest of the batch. None without a deadline.
+fn batch_stop_at(deadline_ms: u64) -> Option<tokio::time::Instant> {
+    if deadline_ms == 0 {
+        return None;
+    }
+    let reserve_seconds = std::env::var("BATCH_DEADLINE_RESERVE_SECONDS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(60);
+    let now_ms = Utc::now().timestamp_millis().max(0) as u64;
+    let left = std::time::Duration::from_millis(deadline_ms.saturating_sub(now_ms))
+        .saturating_sub(std::time::Duration::from_secs(reserve_seconds));
+    Some(tokio::time::Instant::now() + left)
+}
+
 /// Main orchestrator: claim the next due batch, process it, schedule the next one.
+/// Sends stop at `stop_at`; the unsent clients go back to a pending batch.
 async fn process_execution_from_db(
     execution_id: &str,
     supabase: &SupabaseService,
     provider: &dyn EmailProvider,
     scheduler_client: &SchedulerClient,
     logger: &ExecutionLogger,
+    stop_at: Option<tokio::time::Instant>,
 ) -> Result<i32, Box<dyn Error + Send + Sync>> {
     info!("[process_execution_from_db] Starting execution_id={}", execution_id);
     
@@ -356,7 +447,30 @@ async fn process_execution_from_db(
     }
 
     // Find the first pending batch with scheduled_for <= now
-    let pending_batches = supabase.get_pending_batches_for_execution(execution_id).await?;
+    let mut pending_batches = supabase.get_pending_batches_for_execution(execution_id).await?;
+
+    // Before the first batch is picked up, move the most important debtors to the earliest batches
+    let strategy = PriorityStrategy::parse(execution.priority_strategy.as_deref());
+    if strategy != PriorityStrategy::None {
+        let all_batches = supabase.get_execution_batches(execution_id).await?;
+        let not_started = all_batches.iter()
+            .all(|b| b.get("status").and_then(|s| s.as_str()) == Some("pending"));
+
+        if not_started {
+            match prioritization::prioritize_pending_batches(supabase, execution_id, strategy, &pending_batches).await {
+                Ok(0) => {}
+                Ok(rewritten) => {
+                    let _ = logger.log_event(execution_id, None, "PRIORITIZED", Some(json!({
+                        "strategy": execution.priority_strategy,
+                        "batches_rewritten": rewritten
+                    }))).await;
+                    pending_batches = supabase.get_pending_batches_for_execution(execution_id).await?;
+                }
+                Err(e) => error!("Failed to prioritize execution {}: {}", execution_id, e),
+            }
+        }
+    }
+
     info!("[process_execution_from_db] Found {} pending batches for execution {}", 
           pending_batches.len(), execution_id);
     
@@ -388,7 +502,7 @@ async fn process_execution_from_db(
         None => {
             info!("No due batch found for execution {} right now (now={})", execution_id, now.to_rfc3339());
             // Still try to schedule next future batch if any
-            if let Err(e) = schedule_next_batch(execution_id, supabase, scheduler_client).await {
+            if let Err(e) = schedule_next_batch(execution_id, supabase, scheduler_client, None).await {
                 error!("Failed to schedule next batch for {}: {}", execution_id, e);
             }
             return Ok(0);
@@ -407,7 +521,16 @@ async fn process_execution_from_db(
     let _ = logger.log_event(execution_id, Some(&batch.id), "PICKED_UP", None).await;
 
     // Process the batch
+    let batch_started_at = Utc::now();
     let business_name = supabase.get_business_name(&execution.business_id).await;
+    // Pace the batch by the execution's share of the provider rate; without a share the
+    // batch is sent as fast as the provider allows
+    let mut pacer = fair_scheduler::pacer_for_execution(supabase, execution_id, &execution.business_id).await
+        .unwrap_or_else(|e| {
+            error!("[fair_scheduler] Failed to compute share for {}: {}", execution_id, e);
+            fair_scheduler::Pacer::new(0.0)
+        })
+        .stop_at(stop_at);
     let result = process_batch_from_db(
         supabase,
         provider,
@@ -417,16 +540,52 @@ async fn process_execution_from_db(
         &batch.client_ids,
         &business_name,
         &execution,
+        logger,
+        &mut pacer,
     ).await;
 
     match result {
-        Ok(count) => {
+        Ok(run) if run.unsent > 0 => {
+            // Out of time: the batch goes back to pending and the next run skips the
+            // clients already processed
+            warn!("Batch {} stopped before the Lambda deadline with {} clients unsent", batch.id, run.unsent);
+            supabase.update_batch_status(&batch.id, "pending").await?;
+            let _ = logger.log_event(execution_id, Some(&batch.id), "DEADLINE_REACHED", Some(json!({
+                "sent": run.sent,
+                "unsent": run.unsent
+            }))).await;
+
+            let not_before = apply_fair_share(
+                supabase,
+                logger,
+                execution_id,
+                &execution.business_id,
+                batch_started_at,
+                (batch.client_ids.len() - run.unsent) as i64,
+            ).await;
+            if let Err(e) = schedule_next_batch(execution_id, supabase, scheduler_client, not_before).await {
+                error!("Failed to reschedule batch {} for {}: {}", batch.id, execution_id, e);
+            }
+            Ok(run.sent)
+        }
+        Ok(run) => {
+            let count = run.sent;
             supabase.update_batch_status(&batch.id, "completed").await?;
             let _ = logger.log_event(execution_id, Some(&batch.id), "COMPLETED", None).await;
             info!("Batch {} completed ({} emails sent)", batch.id, count);
 
+            // Share the provider rate with the other businesses sending right now
+            let not_before = apply_fair_share(
+                supabase,
+                logger,
+                execution_id,
+                &execution.business_id,
+                batch_started_at,
+                batch.client_ids.len() as i64,
+            ).await;
+
             // Schedule the next pending batch (if any)
-            if let Err(e) = schedule_next_batch(execution_id, supabase, scheduler_client).await {
+            if let Err(e) = schedule_next_batch(execution_id, supabase, scheduler_client, not_before).await {
                 error!("Failed to schedule next batch for {}: {}", execution_id, e);
             }
 
@@ -439,7 +598,7 @@ async fn process_execution_from_db(
             let _ = logger.log_event(execution_id, Some(&batch.id), "FAILED", Some(json!({"error": e.to_string()}))).await;
 
             // Still try to schedule next batch so execution can continue
-            if let Err(e2) = schedule_next_batch(execution_id, supabase, scheduler_client).await {
+            if let Err(e2) = schedule_next_batch(execution_id, supabase, scheduler_client, None).await {
                 error!("Failed to schedule next batch after failure for {}: {}", execution_id, e2);
             }
 
@@ -448,13 +607,59 @@ async fn process_execution_from_db(
     }
 }
 
+/// Computes this execution's share of the provider rate, records its expected completion
+/// time and returns the earliest time the next batch may start. Failures only disable the
+/// deferral; they never block the execution.
+async fn apply_fair_share(
+    supabase: &SupabaseService,
+    logger: &ExecutionLogger,
+    execution_id: &str,
+    business_id: &str,
+    batch_started_at: DateTime<Utc>,
+    batch_size: i64,
+) -> Option<DateTime<Utc>> {
+    let remaining: i64 = match supabase.get_pending_batches_for_execution(execution_id).await {
+        Ok(batches) => batches.iter().map(|b| b.client_ids.len() as i64).sum(),
+        Err(e) => {
+            error!("[fair_scheduler] Failed to count remaining clients for {}: {}", execution_id, e);
+            return None;
+        }
+    };
+
+    let plan = match fair_scheduler::plan_for_execution(
+        supabase, execution_id, business_id, batch_started_at, batch_size, remaining,
+    ).await {
+        Ok(plan) => plan,
+        Err(e) => {
+            error!("[fair_scheduler] Failed to plan execution {}: {}", execution_id, e);
+            return None;
+        }
+    };
+
+    let expected = plan.expected_completion_at.to_rfc3339();
+    if let Err(e) = supabase.update_execution_expected_completion(execution_id, &expected).await {
+        error!("[fair_scheduler] Failed to store expected completion for {}: {}", execution_id, e);
+    }
+    let _ = logger.log_event(execution_id, None, "FAIR_SHARE", Some(json!({
+        "share_per_second": plan.share_per_second,
+        "remaining_clients": remaining,
+        "next_batch_not_before": plan.next_batch_not_before.to_rfc3339(),
+        "expected_completion_at": expected
+    }))).await;
+
+    Some(plan.next_batch_not_before)
+}
+
 /// Schedule an EventBridge One-time schedule for the next pending batch of an execution.
 /// The cron expression is built in the batch's local timezone — matching how the TypeScript
 /// side uses `Intl.DateTimeFormat` to convert UTC → local before extracting time fields.
+/// `not_before` defers the batch when the execution has used up its fair share of the
+/// provider rate; the new time is written back to the batch.
 async fn schedule_next_batch(
     execution_id: &str,
     supabase: &SupabaseService,
     scheduler_client: &SchedulerClient,
+    not_before: Option<DateTime<Utc>>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     let next_batch = supabase.get_next_pending_batch(execution_id).await?;
 
@@ -470,6 +675,15 @@ async fn schedule_next_batch(
     let utc_time: DateTime<Utc> = DateTime::parse_from_rfc3339(scheduled_for)?
         .with_timezone(&Utc);
 
+    let utc_time = match not_before {
+        Some(not_before) if not_before > utc_time => {
+            info!("[fair_scheduler] Deferring batch {} from {} to {}", batch.id, utc_time.to_rfc3339(), not_before.to_rfc3339());
+            supabase.update_batch_scheduled_for(&batch.id, &not_before.to_rfc3339()).await?;
+            not_before
+        }
+        _ => utc_time,
+    };
+
     // EventBridge minimum is 1 minute in the future
     let utc_time = if utc_time <= Utc::now() + chrono::Duration::minutes(1) {
         Utc::now() + chrono::Duration::minutes(2)
@@ -545,280 +759,148 @@ async fn schedule_next_batch(
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Convert a UTC datetime to a local cron expression for EventBridge Scheduler.
+/// Must produce the same result as the TypeScript:
+///   new Intl.DateTimeFormat({ timeZone, ... }).formatToParts(date)
+/// Returns (cron_expr, local_datetime_string) for logging/testing.
+pub fn build_eventbridge_cron(utc: &DateTime<Utc>, timezone_str: &str) -> (String, String) {
+    let tz: Tz = timezone_str.parse().unwrap_or(chrono_tz::America::Bogota);
+    let local = utc.with_timezone(&tz);
+    let cron = format!(
+        "cron({} {} {} {} ? {})",
+        local.minute(), local.hour(), local.day(), local.month(), local.year()
+    );
+    (cron, local.to_rfc3339())
+}
 
-    #[test]
-    fn test_format_currency() {
-        assert_eq!(format_currency(1500000.0), "1.500.000");
+#[allow(clippy::too_many_arguments)]
+async fn process_batch_from_db(
+    supabase: &SupabaseService,
+    provider: &dyn EmailProvider,
+    execution_id: &str,
+    business_id: &str,
+    batch_id: &str,
+    client_ids: &[String],
+    business_name: &str,
+    execution: &models::CollectionExecution,
+    logger: &ExecutionLogger,
+    pacer: &mut fair_scheduler::Pacer,
+) -> Result<BatchRun, Box<dyn Error + Send + Sync>> {
+    info!("[process_batch_from_db] Starting batch_id={} with {} client_ids", batch_id, client_ids.len());
+    
+    if client_ids.is_empty() {
+        warn!("[process_batch_from_db] No client_ids provided for batch {}, returning 0", batch_id);
+        return Ok(BatchRun { sent: 0, unsent: 0 });
     }
+    
+    let mut clients = supabase.get_clients_by_ids(client_ids).await?;
+    info!("[process_batch_from_db] Fetched {} clients from Supabase", clients.len());
 
-    #[test]
-    fn test_preprocess_bare_td() {
-        // Basic: no p-wrapper, no extra cells
-        let input = "<tr><td>{{#each invoices}}</td></tr>";
-        assert_eq!(preprocess_tiptap_template(input), "{{#each invoices}}");
-    }
+    // PostgREST returns rows in arbitrary order; send the most important debtors first
+    let strategy = PriorityStrategy::parse(execution.priority_strategy.as_deref());
+    let threshold_days = prioritization::load_threshold_days(supabase, strategy, &clients).await.unwrap_or_else(|e| {
+        warn!("[process_batch_from_db] Failed to load thresholds for prioritization: {}", e);
+        Default::default()
+    });
+    prioritization::sort_clients(&mut clients, strategy, &threshold_days);
 
-    #[test]
-    fn test_preprocess_bare_td_empty_siblings() {
-        // Legacy snippet: 4 bare empty <td></td> siblings
-        let input = "<tr><td style=\"color:gray\">{{#each invoices}}</td><td></td><td></td><td></td><td></td></tr>";
-        assert_eq!(preprocess_tiptap_template(input), "{{#each invoices}}");
-    }
+    let attachments = if let Some(ids) = &execution.attachment_ids {
+        supabase.get_attachments(ids).await.unwrap_or_default()
+    } else {
+        vec![]
+    };
 
-    #[test]
-    fn test_preprocess_p_wrapper_inside_td() {
-        // TipTap wraps content in <p>; empty siblings become <td><p></p></td>
-        let input = "<tr><td style=\"color:gray\"><p>{{#each invoices}}</p></td><td><p></p></td><td><p></p></td><td><p></p></td><td><p></p></td></tr>";
-        assert_eq!(preprocess_tiptap_template(input), "{{#each invoices}}");
-    }
+    let config = supabase.get_collection_config(business_id).await.unwrap_or_else(|e| {
+        warn!("[process_batch_from_db] Failed to load collection config for business {}: {}", business_id, e);
+        Default::default()
+    });
+    let email_layout = supabase.get_email_layout(business_id).await.unwrap_or_else(|e| {
+        warn!("[process_batch_from_db] Failed to load email layout for business {}: {}", business_id, e);
+        None
+    }).unwrap_or_default();
+    let template_partials = supabase.get_template_partials(business_id).await.unwrap_or_else(|e| {
+        warn!("[process_batch_from_db] Failed to load template partials for business {}: {}", business_id, e);
+        Vec::new()
+    });
+    // Invoices age by the business's calendar day, not the Lambda's UTC one
+    let timezone = supabase.get_business_timezone(business_id).await.unwrap_or_else(|e| {
+        warn!("[process_batch_from_db] Failed to load timezone for business {}: {}", business_id, e);
+        None
+    });
+    let batch = BatchContext::new(&config, timezone.as_deref(), provider.supports_inline_images());
+    // Each template is fetched and compiled once per batch, not once per client
+    let mut templates = BatchTemplates::new(config.rich_text_fields(), template_partials);
+    // Storage images are downloaded once per batch, within the provider's size limits
+    let mut storage_images = (config.inline_storage_images.unwrap_or(false) && provider.supports_inline_images()).then(|| {
+        storage_images::StorageImages::new(supabase.base_url(), storage_images::EmbedLimits::for_provider(provider.provider_name()))
+    });
 
-    #[test]
-    fn test_preprocess_end_helper_with_p_wrapper() {
-        // Same pattern for closing {{/each}}
-        let input = "<tr><td style=\"color:gray\"><p>{{/each}}</p></td><td><p></p></td><td><p></p></td></tr>";
-        assert_eq!(preprocess_tiptap_template(input), "{{/each}}");
-    }
+    let is_dev = std::env::var("APP_ENV").unwrap_or_else(|_| "pro".to_string()) == "dev";
+    let mut sent_count = 0i32;
+    let mut unsent = 0usize;
 
-    // ─────────────────────────────────────────────────────────────────────────────
-    // Table colspan="0" fix tests
-    // ─────────────────────────────────────────────────────────────────────────────
+    let mut metrics = Metrics::new()
+        .with_dimension("Provider", provider.provider_name())
+        .with_dimension("BusinessId", business_id);
 
-    #[test]
-    fn test_fix_table_colspan_zero_basic() {
-        // Input: Multiple colspan="0" cells should be consolidated
-        let input = r#"<tr><td colspan="0" rowspan="1"><p>{{#each invoices}}</p></td><td colspan="0" rowspan="1"><p></p></td><td colspan="0" rowspan="1"><p></p></td><td colspan="0" rowspan="1"><p></p></td><td colspan="0" rowspan="1"><p></p></td></tr>"#;
-        let result = fix_table_colspan(input);
-        
-        // Should have colspan="5" and only one cell
-        assert!(result.contains(r#"colspan="5""#), "Result should have colspan=5: {}", result);
-        assert!(result.contains("{{#each invoices}}"), "Result should preserve the helper: {}", result);
-        
-        // Count <td tags - should only be 1
-        let td_count = result.matches("<td").count();
-        assert_eq!(td_count, 1, "Should have only 1 td tag, found {}: {}", td_count, result);
-    }
+    let total_clients = clients.len();
+    info!("[process_batch_from_db] Processing {} clients for batch {}", total_clients, batch_id);
 
-    #[test]
-    fn test_fix_table_colspan_zero_mixed_with_content() {
-        // Real-world scenario from the database
-        let input = r#"<tr><td colspan="0" rowspan="1" style="padding: 8px;"><p>{{invoice_number}}</p></td><td colspan="0" rowspan="1" style="padding: 8px;"><p>{{amount_due}}</p></td><td colspan="0" rowspan="1"><p></p></td><td colspan="0" rowspan="1"><p></p></td><td colspan="0" rowspan="1"><p></p></td><td colspan="1" rowspan="1"><p></p></td></tr>"#;
-        let result = fix_table_colspan(input);
-        
-        // The invoice data cells should remain, empty ones should be consolidated
-        assert!(result.contains("{{invoice_number}}"), "Should preserve invoice_number: {}", result);
-        assert!(result.contains("{{amount_due}}"), "Should preserve amount_due: {}", result);
-    }
+    for (index, client) in clients.into_iter().enumerate() {
+        info!("[process_batch_from_db] Processing client {}/{}: id={}", index + 1, total_clients, client.id);
 
-    #[test]
-    fn test_fix_table_colspan_no_change_for_valid_colspan() {
-        // Valid colspan="5" should not be modified
-        let input = r#"<tr><td colspan="5" style="padding: 8px;"><p>{{#each invoices}}</p></td></tr>"#;
-        let result = fix_table_colspan(input);
+        // Stop before claiming a client that could not be sent in time
+        if !pacer.has_time() {
+            unsent = total_clients - index;
+            warn!("[process_batch_from_db] Lambda deadline near; leaving {} clients of batch {} for the next run", unsent, batch_id);
+            break;
+        }
         
-        assert_eq!(result, input, "Valid colspan=5 should not be modified");
-    }
+        if is_dev && index > 0 {
+            tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+        }
 
-    #[test]
-    fn test_full_pipeline_with_colspan_zero() {
-        // Test the full preprocess pipeline
-        let input = r#"<table><tr><td colspan="0" rowspan="1"><p>{{#each invoices}}</p></td><td colspan="0" rowspan="1"><p></p></td><td colspan="0" rowspan="1"><p></p></td><td colspan="0" rowspan="1"><p></p></td><td colspan="0" rowspan="1"><p></p></td></tr><tr><td colspan="0" rowspan="1"><p>{{invoice_number}}</p></td><td colspan="0" rowspan="1"><p>{{amount_due}}</p></td><td colspan="0" rowspan="1"><p></p></td><td colspan="0" rowspan="1"><p></p></td><td colspan="0" rowspan="1"><p></p></td></tr><tr><td colspan="0" rowspan="1"><p>{{/each}}</p></td><td colspan="0" rowspan="1"><p></p></td><td colspan="0" rowspan="1"><p></p></td><td colspan="0" rowspan="1"><p></p></td><td colspan="0" rowspan="1"><p></p></td></tr></table>"#;
-        
-        let result = preprocess_tiptap_template(input);
-        
-        // Should have Handlebars helpers extracted
-        assert!(result.contains("{{#each invoices}}"), "Should contain {{#each invoices}}: {}", result);
-        assert!(result.contains("{{/each}}"), "Should contain {{/each}}: {}", result);
-        
-        // The invoice row should still be a proper table row
-        assert!(result.contains("<tr>"), "Should preserve table structure: {}", result);
-        assert!(result.contains("{{invoice_number}}"), "Should preserve invoice_number: {}", result);
-    }
+        // ========== IDEMPOTENCY CHECK #1: Verify if client was already processed ==========
+        match supabase.check_client_processed(&client.id).await {
+            Ok((true, Some(message_id))) => {
+                info!("[IDEMPOTENCY] Client {} already processed with message_id: {}. Skipping.", client.id, message_id);
+                metrics.count("EmailsSkipped", 1);
+                continue;
+            }
+            Ok((true, None)) => {
+                info!("[IDEMPOTENCY] Client {} already processed (status: {}). Skipping.", client.id, client.status);
+                metrics.count("EmailsSkipped", 1);
+                continue;
+            }
+            Ok((false, _)) => {
+                // Client not processed yet, continue with processing
+            }
+            Err(e) => {
+                error!("[IDEMPOTENCY] Failed to check client {} status: {}. Will attempt to process anyway.", client.id, e);
+            }
+        }
 
-    // ─────────────────────────────────────────────────────────────────────────────
-    // EventBridge timezone cron tests
-    //
-    // Core property: cron fields MUST reflect the LOCAL time in the target timezone.
-    // ScheduleExpressionTimezone tells EventBridge how to interpret the cron, so the
-    // fields in the cron expression must already be in that timezone — NOT UTC.
-    //
-    // This mirrors how TypeScript does it:
-    //   new Intl.DateTimeFormat({ timeZone }).formatToParts(utcDate)
-    // ─────────────────────────────────────────────────────────────────────────────
-
-    /// Helper to parse a UTC RFC3339 string into DateTime<Utc>
-    fn utc(s: &str) -> DateTime<Utc> {
-        DateTime::parse_from_rfc3339(s)
-            .unwrap()
-            .with_timezone(&Utc)
-    }
-
-    #[test]
-    fn test_bogota_is_utc_minus_5() {
-        // America/Bogota = UTC-5 (no DST)
-        // UTC 15:30 → Bogotá 10:30 same day
-        let t = utc("2026-03-15T15:30:00Z");
-        let (cron, local) = build_eventbridge_cron(&t, "America/Bogota");
-
-        assert_eq!(cron, "cron(30 10 15 3 ? 2026)",
-            "Bogotá is UTC-5: 15:30 UTC should become 10:30 local. Got local={}", local);
-        assert!(local.contains("10:30"), "Local time should be 10:30, got: {}", local);
-    }
-
-    #[test]
-    fn test_bogota_midnight_boundary() {
-        // UTC 02:00 on March 16 → Bogotá 21:00 on March 15 (day changes!)
-        let t = utc("2026-03-16T02:00:00Z");
-        let (cron, local) = build_eventbridge_cron(&t, "America/Bogota");
-
-        assert_eq!(cron, "cron(0 21 15 3 ? 2026)",
-            "UTC 02:00 March 16 = Bogotá 21:00 March 15. Got local={}", local);
-    }
-
-    #[test]
-    fn test_new_york_dst_utc_minus_4() {
-        // America/New_York in summer (EDT = UTC-4)
-        // UTC 20:00 July 1 → New York 16:00
-        let t = utc("2026-07-01T20:00:00Z");
-        let (cron, local) = build_eventbridge_cron(&t, "America/New_York");
-
-        assert_eq!(cron, "cron(0 16 1 7 ? 2026)",
-            "EDT is UTC-4: 20:00 UTC = 16:00 New York. Got local={}", local);
-    }
-
-    #[test]
-    fn test_madrid_cet_utc_plus_1() {
-        // Europe/Madrid in winter (CET = UTC+1)
-        // UTC 09:00 Jan 10 → Madrid 10:00
-        let t = utc("2026-01-10T09:00:00Z");
-        let (cron, local) = build_eventbridge_cron(&t, "Europe/Madrid");
-
-        assert_eq!(cron, "cron(0 10 10 1 ? 2026)",
-            "CET is UTC+1: 09:00 UTC = 10:00 Madrid. Got local={}", local);
-    }
-
-    #[test]
-    fn test_invalid_timezone_falls_back_to_bogota() {
-        // An invalid TZ string should silently fall back to America/Bogota (UTC-5)
-        // UTC 15:00 → Bogotá 10:00
-        let t = utc("2026-06-01T15:00:00Z");
-        let (cron, _) = build_eventbridge_cron(&t, "Not/A_Valid_Timezone");
-
-        assert_eq!(cron, "cron(0 10 1 6 ? 2026)",
-            "Fallback to Bogota (UTC-5): 15:00 UTC = 10:00 local");
-    }
-
-    #[test]
-    fn test_utc_vs_local_cron_differ_when_offset_nonzero() {
-        // Prove by example that UTC-based cron != local cron for any non-UTC timezone.
-        // If someone accidentally uses UTC fields with ScheduleExpressionTimezone=Bogota,
-        // EventBridge would fire 5 hours LATE.
-        let t = utc("2026-03-15T15:30:00Z");
-        let (local_cron, _) = build_eventbridge_cron(&t, "America/Bogota");
-        let utc_cron = format!(
-            "cron({} {} {} {} ? {})",
-            t.minute(), t.hour(), t.day(), t.month(), t.year()
-        );
-
-        assert_ne!(local_cron, utc_cron,
-            "UTC cron and local cron must differ for non-UTC timezones: {} vs {}", local_cron, utc_cron);
-        assert_eq!(utc_cron,  "cron(30 15 15 3 ? 2026)"); // The WRONG value that would be sent
-        assert_eq!(local_cron, "cron(30 10 15 3 ? 2026)"); // The CORRECT local value
-    }
-}
-
-/// Convert a UTC datetime to a local cron expression for EventBridge Scheduler.
-/// Must produce the same result as the TypeScript:
-///   new Intl.DateTimeFormat({ timeZone, ... }).formatToParts(date)
-/// Returns (cron_expr, local_datetime_string) for logging/testing.
-pub fn build_eventbridge_cron(utc: &DateTime<Utc>, timezone_str: &str) -> (String, String) {
-    let tz: Tz = timezone_str.parse().unwrap_or(chrono_tz::America::Bogota);
-    let local = utc.with_timezone(&tz);
-    let cron = format!(
-        "cron({} {} {} {} ? {})",
-        local.minute(), local.hour(), local.day(), local.month(), local.year()
-    );
-    (cron, local.to_rfc3339())
-}
-
-async fn process_batch_from_db(
-    supabase: &SupabaseService,
-    provider: &dyn EmailProvider,
-    execution_id: &str,
-    business_id: &str,
-    batch_id: &str,
-    client_ids: &[String],
-    business_name: &str,
-    execution: &models::CollectionExecution,
-) -> Result<i32, Box<dyn Error + Send + Sync>> {
-    info!("[process_batch_from_db] Starting batch_id={} with {} client_ids", batch_id, client_ids.len());
-    
-    if client_ids.is_empty() {
-        warn!("[process_batch_from_db] No client_ids provided for batch {}, returning 0", batch_id);
-        return Ok(0);
-    }
-    
-    let clients = supabase.get_clients_by_ids(client_ids).await?;
-    info!("[process_batch_from_db] Fetched {} clients from Supabase", clients.len());
-
-    let attachments = if let Some(ids) = &execution.attachment_ids {
-        supabase.get_attachments(ids).await.unwrap_or_default()
-    } else {
-        vec![]
-    };
-
-    let is_dev = std::env::var("APP_ENV").unwrap_or_else(|_| "pro".to_string()) == "dev";
-    let mut sent_count = 0i32;
-
-    let total_clients = clients.len();
-    info!("[process_batch_from_db] Processing {} clients for batch {}", total_clients, batch_id);
-
-    for (index, client) in clients.into_iter().enumerate() {
-        info!("[process_batch_from_db] Processing client {}/{}: id={}", index + 1, total_clients, client.id);
-        
-        if is_dev && index > 0 {
-            tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-        }
-
-        // ========== IDEMPOTENCY CHECK #1: Verify if client was already processed ==========
-        match supabase.check_client_processed(&client.id).await {
-            Ok((true, Some(message_id))) => {
-                info!("[IDEMPOTENCY] Client {} already processed with message_id: {}. Skipping.", client.id, message_id);
-                continue;
-            }
-            Ok((true, None)) => {
-                info!("[IDEMPOTENCY] Client {} already processed (status: {}). Skipping.", client.id, client.status);
-                continue;
-            }
-            Ok((false, _)) => {
-                // Client not processed yet, continue with processing
-            }
-            Err(e) => {
-                error!("[IDEMPOTENCY] Failed to check client {} status: {}. Will attempt to process anyway.", client.id, e);
-            }
-        }
-
-        // ========== IDEMPOTENCY CHECK #2: Atomically claim the client ==========
-        match supabase.claim_client(&client.id).await {
-            Ok(true) => {
-                info!("[IDEMPOTENCY] Successfully claimed client {} for processing", client.id);
-            }
-            Ok(false) => {
-                warn!("[IDEMPOTENCY] Client {} was already claimed by another worker or not in pending status. Skipping.", client.id);
-                continue;
-            }
-            Err(e) => {
-                error!("[IDEMPOTENCY] Failed to claim client {}: {}. Will attempt to process anyway.", client.id, e);
-            }
-        }
+        // ========== IDEMPOTENCY CHECK #2: Atomically claim the client ==========
+        match supabase.claim_client(&client.id).await {
+            Ok(true) => {
+                info!("[IDEMPOTENCY] Successfully claimed client {} for processing", client.id);
+            }
+            Ok(false) => {
+                warn!("[IDEMPOTENCY] Client {} was already claimed by another worker or not in pending status. Skipping.", client.id);
+                metrics.count("EmailsSkipped", 1);
+                continue;
+            }
+            Err(e) => {
+                error!("[IDEMPOTENCY] Failed to claim client {}: {}. Will attempt to process anyway.", client.id, e);
+            }
+        }
 
         let emails = client.emails();
         info!("[process_batch_from_db] Client {} has {} emails: {:?}", client.id, emails.len(), emails);
         
         if emails.is_empty() {
             warn!("[process_batch_from_db] Client {} has no emails, skipping", client.id);
+            metrics.count("EmailsSkipped", 1);
             continue;
         }
 
@@ -831,23 +913,57 @@ async fn process_batch_from_db(
             let _ = supabase.update_client_status(&client.id, "failed", Some(json!({
                 "error": "No email template configured"
             }))).await;
+            metrics.count("EmailsFailed", 1);
             continue;
         };
 
-        let template = match supabase.get_template(&template_id).await {
+        let template = match templates.get(supabase, &template_id).await {
             Ok(t) => t,
             Err(e) => {
-                error!("Failed to fetch template {} for client {}: {}", template_id, client.id, e);
+                error!("Template {} unavailable for client {}: {}", template_id, client.id, e);
                 let _ = supabase.update_client_status(&client.id, "failed", Some(json!({
-                    "error": format!("Failed to fetch template: {}", e)
+                    "error": e
                 }))).await;
+                metrics.count("EmailsFailed", 1);
                 continue;
             }
         };
 
+        let mut rendered = render_with_mode(&mut templates, supabase, &template, &client, &config, &email_layout, &batch).await;
+        if rendered.fallback_used {
+            metrics.count("RenderFallbacks", 1);
+        }
+        if !rendered.warnings.is_empty() {
+            warn!("[process_batch_from_db] {} render warnings for client {}: {:?}", rendered.warnings.len(), client.id, rendered.warnings);
+            let _ = logger.log_event(execution_id, Some(batch_id), "RENDER_WARNING", Some(json!({
+                "client_id": client.id,
+                "template_id": template_id,
+                "warnings": rendered.warnings
+            }))).await;
+        }
+        if let Some(render_error) = &rendered.render_error {
+            // Never send an unrendered template
+            error!("[process_batch_from_db] Template {} failed to render for client {}: {}", template_id, client.id, render_error);
+            let mut custom_data = client.custom_data.clone().unwrap_or(json!({}));
+            if let Some(obj) = custom_data.as_object_mut() {
+                obj.insert("error".into(), json!(format!("Template render failed: {}", render_error)));
+                obj.insert("template_id".into(), json!(&template_id));
+            }
+            let _ = supabase.update_client_status(&client.id, "failed", Some(custom_data)).await;
+            metrics.count("RenderFailures", 1);
+            metrics.count("EmailsFailed", 1);
+            continue;
+        }
+        if let Some(images) = &mut storage_images {
+            embed_storage_images(images, supabase, &mut rendered).await;
+        }
+
+        pacer.wait().await;
+
         // Send with retry: max 5 attempts, 5s between each
         let mut last_err: Option<String> = None;
         let mut success = false;
+        let mut in_doubt = false;
         let mut final_message_id: Option<String> = None;
 
         info!("[process_batch_from_db] Sending email to client {} (attempt 1/5)", client.id);
@@ -855,6 +971,7 @@ async fn process_batch_from_db(
         for attempt in 1u8..=5 {
             // ========== IDEMPOTENCY CHECK #3: Before each retry, verify if another worker already sent it ==========
             if attempt > 1 {
+                metrics.count("SendRetries", 1);
                 match supabase.check_client_processed(&client.id).await {
                     Ok((true, Some(msg_id))) => {
                         info!("[IDEMPOTENCY] Client {} was already processed by another worker during retry. Message ID: {}. Stopping retries.", 
@@ -874,14 +991,21 @@ async fn process_batch_from_db(
                 }
             }
 
-            match send_client_email(supabase, provider, &template, &client, &emails, &attachments, execution_id, business_name).await {
-                Ok(message_id) => {
+            match send_client_email(supabase, provider, &rendered, &client, &emails, &attachments, execution_id, business_id, business_name, &mut metrics).await {
+                Ok(SendOutcome::InDoubt) => {
+                    warn!("[OUTBOX] Client {} has an in-doubt send from a previous run. Leaving it for the reconciler.", client.id);
+                    in_doubt = true;
+                    break;
+                }
+                Ok(SendOutcome::Sent(sent)) | Ok(SendOutcome::AlreadySent(sent)) => {
+                    let message_id = sent.message_id;
                     info!("[process_batch_from_db] Email sent successfully to client {}: message_id={}", client.id, message_id);
                     
                     // ========== IDEMPOTENCY CHECK #4: Verify if event already exists before marking as accepted ==========
-                    match supabase.check_event_exists(&client.id, "email_sent", &message_id).await {
+                    // The event handler records SES "Send" events under our own Message-ID
+                    match supabase.check_event_exists(&client.id, "Send", &message_id).await {
                         Ok(true) => {
-                            warn!("[IDEMPOTENCY] Event email_sent for client {} with message_id {} already exists. Duplicate detected, skipping status update.", 
+                            warn!("[IDEMPOTENCY] Send event for client {} with message_id {} already exists. Duplicate detected, skipping status update.", 
                                 client.id, message_id);
                             // Still count as success but don't insert duplicate
                             success = true;
@@ -893,13 +1017,18 @@ async fn process_batch_from_db(
                             let mut custom_data = client.custom_data.clone().unwrap_or(json!({}));
                             if let Some(obj) = custom_data.as_object_mut() {
                                 obj.insert("message_id".into(), json!(message_id));
+                                obj.insert("provider_message_id".into(), json!(sent.provider_message_id));
                                 obj.insert("email_sent_at".into(), json!(Utc::now().to_rfc3339()));
                                 obj.insert("template_id".into(), json!(&template_id));
+                                if !rendered.partials.is_empty() {
+                                    obj.insert("template_partials".into(), json!(&rendered.partials));
+                                }
                                 if let Some(tid) = &client.threshold_id {
                                     obj.insert("threshold_id".into(), json!(tid));
                                 }
                             }
                             let _ = supabase.update_client_status(&client.id, "accepted", Some(custom_data)).await;
+                            metrics.count("EmailsSent", 1);
                             sent_count += 1;
                             success = true;
                             final_message_id = Some(message_id);
@@ -912,13 +1041,18 @@ async fn process_batch_from_db(
                             let mut custom_data = client.custom_data.clone().unwrap_or(json!({}));
                             if let Some(obj) = custom_data.as_object_mut() {
                                 obj.insert("message_id".into(), json!(message_id));
+                                obj.insert("provider_message_id".into(), json!(sent.provider_message_id));
                                 obj.insert("email_sent_at".into(), json!(Utc::now().to_rfc3339()));
                                 obj.insert("template_id".into(), json!(&template_id));
+                                if !rendered.partials.is_empty() {
+                                    obj.insert("template_partials".into(), json!(&rendered.partials));
+                                }
                                 if let Some(tid) = &client.threshold_id {
                                     obj.insert("threshold_id".into(), json!(tid));
                                 }
                             }
                             let _ = supabase.update_client_status(&client.id, "accepted", Some(custom_data)).await;
+                            metrics.count("EmailsSent", 1);
                             sent_count += 1;
                             success = true;
                             final_message_id = Some(message_id);
@@ -938,7 +1072,11 @@ async fn process_batch_from_db(
             }
         }
 
-        if !success {
+        if success {
+            info!("[process_batch_from_db] Client {} done, message_id={:?}", client.id, final_message_id);
+        } else if in_doubt {
+            metrics.count("EmailsSkipped", 1);
+        } else {
             let err_msg = last_err.unwrap_or_else(|| "Unknown error".to_string());
             error!("All 5 attempts failed for client {}: {}", client.id, err_msg);
             
@@ -952,12 +1090,22 @@ async fn process_batch_from_db(
                         "error": err_msg,
                         "template_id": &template_id
                     }))).await;
+                    metrics.count("EmailsFailed", 1);
                 }
             }
         }
     }
 
-    Ok(sent_count)
+    metrics.flush();
+
+    Ok(BatchRun { sent: sent_count, unsent })
+}
+
+/// Result of processing a batch
+struct BatchRun {
+    sent: i32,
+    /// Clients not attempted because the Lambda deadline was near
+    unsent: usize,
 }
 
 async fn check_and_complete_execution(supabase: &SupabaseService, execution_id: &str) {
@@ -984,20 +1132,81 @@ async fn check_and_complete_execution(supabase: &SupabaseService, execution_id:
     }
 }
 
-async fn send_client_email(
-    _supabase: &SupabaseService,
-    provider: &dyn EmailProvider,
-    template: &models::EmailTemplate,
+/// Ids of a message accepted by the provider: our RFC 5322 Message-ID, used for
+/// correlation, and the id the provider assigned (if known)
+struct SentMessage {
+    message_id: String,
+    provider_message_id: Option<String>,
+}
+
+/// Outcome of a single send attempt for one client
+enum SendOutcome {
+    /// The provider accepted the message in this attempt
+    Sent(SentMessage),
+    /// The outbox shows a previous run already sent it
+    AlreadySent(SentMessage),
+    /// A previous run crashed mid-send; the reconciler must resolve it first
+    InDoubt,
+}
+
+/// Subject and bodies rendered for one client
+struct RenderedEmail {
+    /// Template the bodies were rendered from (the fallback one if it was used)
+    template_id: String,
+    subject: String,
+    html_body: String,
+    text_body: String,
+    /// The body template failed; there is nothing to send
+    render_error: Option<String>,
+    /// The business fallback template replaced a failed template
+    fallback_used: bool,
+    /// Partials included by the template, as `name@version`
+    partials: Vec<String>,
+    /// Images the HTML body references by `cid:` (the payment QR code and, when enabled,
+    /// the storage images)
+    inline_images: Vec<InlineImage>,
+    warnings: Vec<RenderWarning>,
+}
+
+/// Values shared by every email of a batch besides the business settings
+struct BatchContext {
+    /// Today in the business timezone
+    today: chrono::NaiveDate,
+    /// Signs `payment_url`s; None when the business has no payment page
+    payment_links: Option<payment_link::PaymentLinks>,
+    /// Draws the `{{payment_qr}}` image; an invalid QR content is reported per client
+    payment_qr: Result<Option<payment_qr::PaymentQr>, String>,
+    /// The provider can send `cid:` images (`EmailProvider::supports_inline_images`)
+    inline_images: bool,
+}
+
+impl BatchContext {
+    fn new(config: &models::CollectionConfig, timezone: Option<&str>, inline_images: bool) -> Self {
+        Self {
+            today: aging::today_in(timezone),
+            payment_links: payment_link::PaymentLinks::from_config(config),
+            payment_qr: payment_qr::PaymentQr::from_config(config),
+            inline_images,
+        }
+    }
+}
+
+/// Builds the template data of a client (amounts, dates, name fallback) and renders the
+/// subject and body. A body that fails to render is reported through `render_error`;
+/// other problems through `warnings`.
+fn render_client_email(
+    compiled: &CompiledTemplate,
     client: &models::CollectionClient,
-    emails: &[String],
-    attachments: &[models::Attachment],
-    execution_id: &str,
-    business_name: &str,
-) -> Result<String, Box<dyn Error + Send + Sync>> {
-    info!("[send_client_email] Preparing email for client {}: emails={:?}, template={}", 
-          client.id, emails, template.id);
-    
+    config: &models::CollectionConfig,
+    email_layout: &models::EmailLayout,
+    batch: &BatchContext,
+) -> RenderedEmail {
+    let template = &compiled.source;
+    let mut warnings = Vec::new();
+    // Helper warnings are collected per thread; drop any left by an earlier render
+    helpers::take_warnings();
     let mut template_data = client.custom_data.clone().unwrap_or(serde_json::json!({}));
+    escaping::sanitize_rich_fields(&mut template_data, config.rich_text_fields());
     
     if let Some(invoices) = &client.invoices {
         template_data["invoices"] = invoices.clone();
@@ -1005,63 +1214,688 @@ async fn send_client_email(
         template_data["invoices"] = serde_json::json!([]);
     }
     
-    let total_amount = get_f64(template_data.get("total_amount_due").unwrap_or(&serde_json::json!(client.amount_due())));
-    template_data["total_amount_due"] = serde_json::Value::String(format_currency(total_amount));
+    let total_amount = helpers::parse_number(template_data.get("total_amount_due").unwrap_or(&serde_json::json!(client.amount_due()))).unwrap_or(0.0);
+    template_data["total_amount_due"] = serde_json::json!(total_amount);
+
+    // Amounts are formatted with the client's currency (custom_data.currency) or the business one;
+    // invoices may override it. The raw numbers stay available as `<field>_value`.
+    let amount_format = AmountFormat::from_config(config)
+        .for_currency(template_data.get("currency").and_then(|c| c.as_str()));
+
+    // Days overdue, aging buckets and their totals, from the raw due dates and amounts
+    let input_date_format = config.input_date_format.as_deref().and_then(DateFormat::parse);
+    aging::add_computed_fields(&mut template_data, batch.today, input_date_format, &amount_format.currency, &mut warnings);
+    if let Some(links) = &batch.payment_links {
+        links.add_payment_urls(&mut template_data, &client.id, batch.today);
+    }
+
+    // The QR encodes raw values (amounts without separators, ISO dates)
+    let mut inline_images = Vec::new();
+    let qr = match &batch.payment_qr {
+        Ok(Some(qr)) => qr.render(&template_data),
+        Ok(None) => Ok(None),
+        Err(e) => Err(e.clone()),
+    };
+    match qr {
+        Ok(Some(_)) if !batch.inline_images => warnings.push(RenderWarning {
+            field: "payment_qr_content".to_string(),
+            value: config.payment_qr_content.clone().unwrap_or_default(),
+            message: "Payment QR code not generated: the email provider can't send inline images".to_string(),
+        }),
+        Ok(Some(image)) => {
+            template_data["payment_qr"] = serde_json::Value::String(format!("cid:{}", image.content_id));
+            inline_images.push(image);
+        }
+        Ok(None) => {}
+        Err(e) => warnings.push(RenderWarning {
+            field: "payment_qr_content".to_string(),
+            value: config.payment_qr_content.clone().unwrap_or_default(),
+            message: format!("Payment QR code not generated: {}", e),
+        }),
+    }
     
+    // System text follows the client's language, then the business's
+    let language = i18n::resolve(client.custom_data.as_ref(), config);
+    template_data["language"] = serde_json::Value::String(language.code().to_string());
+
     if template_data.get("full_name").is_none() {
-        template_data["full_name"] = serde_json::Value::String(client.full_name().unwrap_or("Cliente").to_string());
+        let fallback_name = language.text(i18n::Message::FallbackName);
+        template_data["full_name"] = serde_json::Value::String(client.full_name().unwrap_or(fallback_name).to_string());
+    }
+
+    template_data["currency"] = serde_json::Value::String(amount_format.currency.clone());
+    if let Some(locale) = &amount_format.locale {
+        template_data["locale"] = serde_json::Value::String(locale.clone());
     }
+    formatting::format_amount_fields(&mut template_data, &amount_format);
+    // Read by the {{invoice_table}} helper
+    template_data["amount_format"] = serde_json::to_value(&amount_format).unwrap_or_default();
+    if let Some(table) = &config.invoice_table {
+        template_data["invoice_table_config"] = serde_json::to_value(table).unwrap_or_default();
+    }
+
+    // Dates are re-rendered in the business's output_date_format; ISO dates stay in `<field>_iso`
+    let output_date_format = match config.output_date_format.as_deref() {
+        Some(value) => DateFormat::parse(value).unwrap_or_else(|| {
+            warnings.push(RenderWarning {
+                field: "output_date_format".to_string(),
+                value: value.to_string(),
+                message: "Unsupported output date format, using DD-MM-AAAA".to_string(),
+            });
+            DateFormat::default()
+        }),
+        None => DateFormat::default(),
+    };
+    template_data["output_date_format"] = serde_json::Value::String(output_date_format.strftime().to_string());
+    formatting::format_date_fields(&mut template_data, "", input_date_format, output_date_format, &mut warnings);
     
     if let Some(invoices) = template_data.get_mut("invoices").and_then(|v| v.as_array_mut()) {
-        for invoice in invoices {
-            if let Some(amount_val) = invoice.get("amount_due") {
-                invoice["amount_due"] = serde_json::Value::String(format_currency(get_f64(amount_val)));
-            }
+        for (i, invoice) in invoices.iter_mut().enumerate() {
+            formatting::format_amount_fields(invoice, &amount_format);
+            formatting::format_date_fields(invoice, &format!("invoices[{}]", i), input_date_format, output_date_format, &mut warnings);
         }
     }
     
-    let html_body = match render_template(&template.content, &template_data) {
+    let mut render_error = None;
+    let html_body = match compiled.render_content(&template_data) {
         Ok(rendered) => {
             let fixed_empty = fix_empty_paragraphs(&rendered);
             let with_line_breaks = preserve_line_breaks(&fixed_empty);
             let enhanced_tables = enhance_invoice_tables(&with_line_breaks);
-            let wrapped = wrap_with_styles(&enhanced_tables);
-            match inline_css(&wrapped) {
+            let with_buttons = layout::bulletproof_buttons(&enhanced_tables, email_layout);
+            let wrapped = layout::wrap_with_layout(&with_buttons, email_layout, language);
+            let inlined = match inline_css(&wrapped) {
                 Ok(inlined) => inlined,
                 Err(e) => {
                     error!("Inline CSS error: {}", e);
                     wrapped
                 }
-            }
+            };
+            layout::add_dark_mode_styles(&inlined, email_layout)
         },
         Err(e) => {
-            error!("Render error: {}", e);
-            template.content
-                .replace("{{nombre}}", client.full_name().unwrap_or("Cliente"))
-                .replace("{{monto}}", &format_currency(client.amount_due()))
+            error!("Render error for template {}: {}", template.id, e);
+            render_error = Some(e.to_string());
+            String::new()
+        }
+    };
+
+    let subject = match compiled.render_subject(&template_data).map(|s| plain_text::subject_line(&s)) {
+        Ok(subject) if subject.is_empty() && !template.subject.trim().is_empty() => {
+            warnings.push(RenderWarning {
+                field: "subject".to_string(),
+                value: template.subject.clone(),
+                message: "Subject template rendered an empty subject".to_string(),
+            });
+            subject
+        }
+        Ok(subject) => subject,
+        Err(e) => {
+            error!("Subject render error for template {}: {}", template.id, e);
+            warnings.push(RenderWarning {
+                field: "subject".to_string(),
+                value: template.subject.clone(),
+                message: format!("Subject template error: {}", e),
+            });
+            fallback_subject(&template.subject)
         }
     };
     
-    let text_body = "Por favor habilite HTML para ver este correo.";
+    let mut text_body = plain_text::html_to_text(&html_body);
+    if text_body.is_empty() && !html_body.is_empty() {
+        text_body = language.text(i18n::Message::TextBodyPlaceholder).to_string();
+    }
+    // Images the template doesn't place would show up as attachments
+    inline_images.retain(|image| html_body.contains(&format!("cid:{}", image.content_id)));
+    warnings.extend(helpers::take_warnings());
+
+    RenderedEmail {
+        template_id: template.id.clone(),
+        subject,
+        text_body,
+        html_body,
+        render_error,
+        fallback_used: false,
+        partials: compiled.partials.clone(),
+        inline_images,
+        warnings,
+    }
+}
+
+/// Renders a client's email under the business render mode: in fallback mode a failed
+/// template is replaced by the business fallback template, otherwise the failure stands
+async fn render_with_mode(
+    templates: &mut BatchTemplates,
+    supabase: &SupabaseService,
+    compiled: &CompiledTemplate,
+    client: &models::CollectionClient,
+    config: &models::CollectionConfig,
+    email_layout: &models::EmailLayout,
+    batch: &BatchContext,
+) -> RenderedEmail {
+    let rendered = render_client_email(compiled, client, config, email_layout, batch);
+    let Some(error) = rendered.render_error.clone() else {
+        return rendered;
+    };
+    let Some(fallback_id) = config.fallback_template().filter(|id| *id != compiled.source.id) else {
+        return rendered;
+    };
+
+    match templates.get(supabase, fallback_id).await {
+        Ok(fallback) => with_fallback(rendered, render_client_email(&fallback, client, config, email_layout, batch)),
+        Err(e) => RenderedEmail {
+            render_error: Some(format!("{}; fallback template {} unavailable: {}", error, fallback_id, e)),
+            ..rendered
+        },
+    }
+}
+
+/// Combines a failed render with the render of the fallback template, keeping the
+/// warnings of both
+fn with_fallback(primary: RenderedEmail, fallback: RenderedEmail) -> RenderedEmail {
+    let mut warnings = primary.warnings;
+    warnings.extend(fallback.warnings);
+
+    if let Some(fallback_error) = fallback.render_error {
+        return RenderedEmail {
+            render_error: Some(format!(
+                "{}; fallback template {} also failed: {}",
+                primary.render_error.unwrap_or_default(), fallback.template_id, fallback_error
+            )),
+            warnings,
+            ..primary
+        };
+    }
+
+    warnings.push(RenderWarning {
+        field: "content".to_string(),
+        value: primary.template_id.clone(),
+        message: format!(
+            "Template {} failed ({}); rendered fallback template {}",
+            primary.template_id, primary.render_error.unwrap_or_default(), fallback.template_id
+        ),
+    });
+    RenderedEmail { fallback_used: true, warnings, ..fallback }
+}
+
+/// Replaces the storage images of a rendered body with inline `cid:` parts, after the
+/// payment QR in the size budget
+async fn embed_storage_images(
+    images: &mut storage_images::StorageImages,
+    supabase: &SupabaseService,
+    rendered: &mut RenderedEmail,
+) {
+    if rendered.render_error.is_some() {
+        return;
+    }
+    let used_bytes = rendered.inline_images.iter().map(|image| image.data.len()).sum();
+    let (html_body, embedded) = images.embed(supabase, &rendered.html_body, used_bytes).await;
+    rendered.html_body = html_body;
+    rendered.inline_images.extend(embedded);
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn send_client_email(
+    supabase: &SupabaseService,
+    provider: &dyn EmailProvider,
+    rendered: &RenderedEmail,
+    client: &models::CollectionClient,
+    emails: &[String],
+    attachments: &[models::Attachment],
+    execution_id: &str,
+    business_id: &str,
+    business_name: &str,
+    metrics: &mut Metrics,
+) -> Result<SendOutcome, Box<dyn Error + Send + Sync>> {
+    info!("[send_client_email] Preparing email for client {}: emails={:?}", client.id, emails);
+
+    let idempotency_key = outbox::idempotency_key(execution_id, &client.id);
     
-    let email_message = EmailMessage {
+    let mut email_message = EmailMessage {
         to: emails.to_vec(),
-        subject: template.subject.clone(),
-        html_body: html_body.clone(),
-        text_body: text_body.to_string(),
+        subject: rendered.subject.clone(),
+        html_body: rendered.html_body.clone(),
+        text_body: rendered.text_body.clone(),
         from: format!("{} - Cartera <siesa@borls.com>", business_name),
         attachments: attachments.to_vec(),
+        inline_images: rendered.inline_images.clone(),
         client_id: Some(client.id.clone()),
         execution_id: Some(execution_id.to_string()),
         message_id: None,
+        idempotency_key: Some(idempotency_key.clone()),
     };
+
+    // Write-ahead outbox entry: never call the provider without one
+    let message_hash = outbox::message_hash(&email_message);
+    let message_id = match outbox::begin(supabase, &idempotency_key, &client.id, execution_id, business_id, &message_hash).await? {
+        OutboxClaim::Claimed { message_id } => message_id,
+        OutboxClaim::AlreadySent { message_id, provider_message_id } => {
+            info!("[OUTBOX] Client {} was already sent as {} by a previous run", client.id, message_id);
+            return Ok(SendOutcome::AlreadySent(SentMessage { message_id, provider_message_id }));
+        }
+        OutboxClaim::InDoubt => return Ok(SendOutcome::InDoubt),
+    };
+    email_message.message_id = Some(message_id.clone());
     
     info!("[send_client_email] Sending email via provider to {:?}", emails);
     
-    let result = provider.send_email(email_message).await?;
+    let started = std::time::Instant::now();
+    let result = provider.send_email(email_message).await;
+    metrics.record("ProviderLatency", Unit::Milliseconds, started.elapsed().as_secs_f64() * 1000.0);
+
+    let result = match result {
+        Ok(r) => r,
+        Err(e) => {
+            if let Err(oe) = outbox::fail(supabase, &idempotency_key, &e.to_string(), "worker").await {
+                error!("[OUTBOX] Failed to record failure for client {}: {}", client.id, oe);
+            }
+            return Err(e);
+        }
+    };
     
-    info!("[send_client_email] Email sent successfully: message_id={}, provider={}", 
-          result.message_id, result.provider);
+    info!("[send_client_email] Email sent successfully: message_id={}, provider_message_id={}, provider={}", 
+          message_id, result.message_id, result.provider);
+
+    if let Err(e) = outbox::complete(supabase, &idempotency_key, &result.provider, &result.message_id, "worker").await {
+        // The send succeeded; the reconciler will complete the entry from events
+        error!("[OUTBOX] Failed to complete entry for client {}: {}", client.id, e);
+    }
     
-    Ok(result.message_id)
+    Ok(SendOutcome::Sent(SentMessage {
+        message_id,
+        provider_message_id: Some(result.message_id),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch() -> BatchContext {
+        BatchContext {
+            today: chrono::NaiveDate::from_ymd_opt(2026, 10, 19).unwrap(),
+            payment_links: None,
+            payment_qr: Ok(None),
+            inline_images: true,
+        }
+    }
+
+    fn render_fixture(output_date_format: &str) -> RenderedEmail {
+        render_fixture_with_subject(
+            "Factura {{invoices.0.invoice_number}} vencida el {{invoices.0.due_date}}",
+            output_date_format,
+        )
+    }
+
+    fn render_fixture_with_subject(subject: &str, output_date_format: &str) -> RenderedEmail {
+        let template = models::EmailTemplate {
+            id: "tpl-1".to_string(),
+            subject: subject.to_string(),
+            content: "<p>Hola {{full_name}}</p>{{#each invoices}}<p>{{invoice_number}} {{due_date}} {{amount_due}}</p>{{/each}}".to_string(),
+        };
+        let client = models::CollectionClient {
+            id: "client-1".to_string(),
+            execution_id: "exec-1".to_string(),
+            status: "pending".to_string(),
+            invoices: Some(json!([
+                { "invoice_number": "F-1", "due_date": "2026-01-31", "amount_due": "1500000" },
+                { "invoice_number": "F-2", "due_date": "pronto", "amount_due": 2500 }
+            ])),
+            custom_data: Some(json!({ "full_name": "ACME SAS", "total_amount_due": 1502500 })),
+            email_template_id: None,
+            threshold_id: None,
+        };
+        let config = models::CollectionConfig {
+            output_date_format: Some(output_date_format.to_string()),
+            ..Default::default()
+        };
+        render_client_email(&CompiledTemplate::compile(template, &[], &[]), &client, &config, &Default::default(), &batch())
+    }
+
+    #[test]
+    fn test_render_applies_output_date_format_to_body_and_subject() {
+        let rendered = render_fixture("MM/DD/AAAA");
+
+        assert_eq!(rendered.subject, "Factura F-1 vencida el 01/31/2026");
+        assert!(rendered.html_body.contains("F-1 01/31/2026 1.500.000"), "{}", rendered.html_body);
+        assert!(rendered.html_body.contains("F-2 pronto 2.500"), "{}", rendered.html_body);
+        assert!(rendered.render_error.is_none());
+        assert_eq!(rendered.warnings.len(), 1);
+        assert_eq!(rendered.warnings[0].field, "invoices[1].due_date");
+    }
+
+    #[test]
+    fn test_render_reports_unsupported_output_date_format() {
+        let rendered = render_fixture("YYYY.MM.DD");
+
+        assert_eq!(rendered.subject, "Factura F-1 vencida el 31-01-2026");
+        assert!(rendered.warnings.iter().any(|w| w.field == "output_date_format"));
+    }
+
+    #[test]
+    fn test_payment_qr_needs_inline_image_support() {
+        let template = models::EmailTemplate {
+            id: "tpl-qr".to_string(),
+            subject: "Pague aquí".to_string(),
+            content: "<p>Escanee el código</p>{{payment_qr}}".to_string(),
+        };
+        let client = models::CollectionClient {
+            id: "client-1".to_string(),
+            execution_id: "exec-1".to_string(),
+            status: "pending".to_string(),
+            invoices: None,
+            custom_data: Some(json!({ "full_name": "ACME SAS", "nit": "900123456" })),
+            email_template_id: None,
+            threshold_id: None,
+        };
+        let config = models::CollectionConfig {
+            payment_qr_content: Some("Ref. {{nit}}".to_string()),
+            ..Default::default()
+        };
+        let compiled = CompiledTemplate::compile(template, &[], &[]);
+        let render = |inline_images| {
+            let batch = BatchContext { payment_qr: payment_qr::PaymentQr::from_config(&config), inline_images, ..batch() };
+            render_client_email(&compiled, &client, &config, &Default::default(), &batch)
+        };
+
+        let rendered = render(true);
+        assert!(rendered.html_body.contains(r#"src="cid:payment-qr.png""#), "{}", rendered.html_body);
+        assert_eq!(rendered.inline_images.len(), 1);
+        assert!(rendered.warnings.is_empty());
+
+        let rendered = render(false);
+        assert!(!rendered.html_body.contains("cid:"), "{}", rendered.html_body);
+        assert!(rendered.inline_images.is_empty());
+        assert_eq!(rendered.warnings.len(), 1);
+        assert_eq!(rendered.warnings[0].field, "payment_qr_content");
+    }
+
+    #[test]
+    fn test_customer_data_is_escaped_in_tiptap_layout() {
+        let template = models::EmailTemplate {
+            id: "tpl-escape".to_string(),
+            subject: "Hola {{full_name}}".to_string(),
+            content: concat!(
+                r#"<p>Hola {{full_name}}</p><p>{{agent_message}}</p><table class="tiptap-table"><tbody>"#,
+                r#"<tr><th><p>Factura</p></th><th><p>Valor</p></th></tr>"#,
+                r#"<tr><td colspan="2"><p>{{#each invoices}}</p></td></tr>"#,
+                r#"<tr><td><p>{{invoice_number}}</p></td><td><p>{{amount_due}}</p></td></tr>"#,
+                r#"<tr><td colspan="2"><p>{{/each}}</p></td></tr></tbody></table>"#,
+            ).to_string(),
+        };
+        let client = models::CollectionClient {
+            id: "client-1".to_string(),
+            execution_id: "exec-1".to_string(),
+            status: "pending".to_string(),
+            invoices: Some(json!([{ "invoice_number": "<a href=\"https://evil.example\">F-1</a>", "amount_due": 1500 }])),
+            custom_data: Some(json!({
+                "full_name": "<script>alert(1)</script>ACME",
+                "agent_message": "<b>Gracias</b><script>alert(2)</script>"
+            })),
+            email_template_id: None,
+            threshold_id: None,
+        };
+        let config = models::CollectionConfig {
+            rich_text_fields: Some(vec!["agent_message".to_string()]),
+            ..Default::default()
+        };
+        let rendered = render_client_email(&CompiledTemplate::compile(template, config.rich_text_fields(), &[]), &client, &config, &Default::default(), &batch());
+
+        assert!(rendered.render_error.is_none());
+        assert!(!rendered.html_body.contains("<script"), "{}", rendered.html_body);
+        assert!(!rendered.html_body.contains("evil.example\">"), "{}", rendered.html_body);
+        assert!(rendered.html_body.contains("&lt;script&gt;alert(1)&lt;/script&gt;ACME"), "{}", rendered.html_body);
+        assert!(rendered.html_body.contains("<b>Gracias</b>"), "{}", rendered.html_body);
+        assert!(rendered.html_body.contains("1.500"), "{}", rendered.html_body);
+        assert_eq!(rendered.subject, "Hola <script>alert(1)</script>ACME");
+    }
+
+    #[test]
+    fn test_render_error_never_yields_the_raw_template() {
+        let template = models::EmailTemplate {
+            id: "broken".to_string(),
+            subject: "Hola".to_string(),
+            content: "<p>Hola {{nombre}} {{#if monto}}</p>".to_string(),
+        };
+        let client = models::CollectionClient {
+            id: "client-1".to_string(),
+            execution_id: "exec-1".to_string(),
+            status: "pending".to_string(),
+            invoices: None,
+            custom_data: Some(json!({ "full_name": "ACME SAS" })),
+            email_template_id: None,
+            threshold_id: None,
+        };
+        let rendered = render_client_email(&CompiledTemplate::compile(template, &[], &[]), &client, &Default::default(), &Default::default(), &batch());
+
+        assert!(rendered.render_error.is_some());
+        assert!(rendered.html_body.is_empty());
+        assert!(rendered.text_body.is_empty());
+    }
+
+    #[test]
+    fn test_fallback_render_replaces_failed_template() {
+        let failed = RenderedEmail {
+            template_id: "broken".to_string(),
+            subject: "Hola".to_string(),
+            html_body: String::new(),
+            text_body: String::new(),
+            render_error: Some("unclosed block".to_string()),
+            fallback_used: false,
+            partials: vec![],
+            inline_images: vec![],
+            warnings: vec![],
+        };
+        let fallback = render_fixture("DD-MM-AAAA");
+
+        let combined = with_fallback(failed, fallback);
+        assert!(combined.fallback_used);
+        assert!(combined.render_error.is_none());
+        assert_eq!(combined.template_id, "tpl-1");
+        assert!(combined.html_body.contains("Hola ACME SAS"));
+        assert!(combined.warnings.last().unwrap().message.contains("rendered fallback template tpl-1"));
+
+        let failed_again = RenderedEmail { render_error: Some("boom".to_string()), ..render_fixture("DD-MM-AAAA") };
+        let primary = RenderedEmail { render_error: Some("unclosed block".to_string()), ..render_fixture("DD-MM-AAAA") };
+        let combined = with_fallback(primary, failed_again);
+        assert!(!combined.fallback_used);
+        assert!(combined.render_error.unwrap().contains("also failed: boom"));
+    }
+
+    #[test]
+    fn test_fallback_template_requires_fallback_mode() {
+        let mut config = models::CollectionConfig {
+            fallback_template_id: Some("tpl-fallback".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(config.fallback_template(), None);
+
+        config.render_mode = Some("fallback".to_string());
+        assert_eq!(config.fallback_template(), Some("tpl-fallback"));
+    }
+
+    #[test]
+    fn test_subject_is_rendered_as_plain_text() {
+        let rendered = render_fixture_with_subject(
+            "Recordatorio: <b>{{full_name}}</b> tiene\n{{invoices.length}} {{pluralize invoices \"factura\" \"facturas\"}} pendientes",
+            "DD-MM-AAAA",
+        );
+
+        assert_eq!(rendered.subject, "Recordatorio: ACME SAS tiene 2 facturas pendientes");
+        assert!(!rendered.warnings.iter().any(|w| w.field == "subject"));
+    }
+
+    #[test]
+    fn test_subject_errors_are_reported_and_placeholders_dropped() {
+        let rendered = render_fixture_with_subject("Estado de cuenta {{#if full_name}}{{full_name}}", "DD-MM-AAAA");
+
+        assert_eq!(rendered.subject, "Estado de cuenta");
+        let warning = rendered.warnings.iter().find(|w| w.field == "subject").unwrap();
+        assert!(warning.message.starts_with("Subject template error"), "{}", warning.message);
+    }
+
+    #[test]
+    fn test_enhance_invoice_tables_wraps_only_tiptap_tables() {
+        let html = enhance_invoice_tables(r#"<table class="tiptap-table"><tr><td><table><tr><td>x</td></tr></table></td></tr></table><table><tr><td>y</td></tr></table>"#);
+
+        assert_eq!(
+            html,
+            concat!(
+                r#"<!--[if !mso]><!--><div style="margin: 0 auto; overflow-x: auto;"><!--<![endif]--><table width="100%" class="tiptap-table">"#,
+                r#"<tr><td><table><tr><td>x</td></tr></table></td></tr></table><!--[if !mso]><!--></div><!--<![endif]-->"#,
+                r#"<table><tr><td>y</td></tr></table>"#,
+            )
+        );
+    }
+
+    fn render_client_compat_fixture(config: &models::CollectionConfig, layout: &models::EmailLayout) -> RenderedEmail {
+        let template = models::EmailTemplate {
+            id: "tpl-compat".to_string(),
+            subject: "Estado de cuenta".to_string(),
+            content: concat!(
+                r#"<p>Hola {{full_name}}</p><table class="tiptap-table" style="min-width: 50px"><tbody>"#,
+                r#"<tr><th><p>Factura</p></th><th><p>Valor</p></th></tr>"#,
+                r#"<tr><td colspan="2"><p>{{#each invoices}}</p></td></tr>"#,
+                r#"<tr><td><p>{{invoice_number}}</p></td><td><p>{{amount_due}}</p></td></tr>"#,
+                r#"<tr><td colspan="2"><p>{{/each}}</p></td></tr></tbody></table>"#,
+                r#"<p><a class="button" href="https://pagos.example.com/acme">Pagar <b>ahora</b></a></p>"#,
+                r#"<p><a href="https://example.com">Ver detalle</a></p>"#,
+            ).to_string(),
+        };
+        let client = models::CollectionClient {
+            id: "client-1".to_string(),
+            execution_id: "exec-1".to_string(),
+            status: "pending".to_string(),
+            invoices: Some(json!([
+                { "invoice_number": "F-1", "amount_due": 1500000 },
+                { "invoice_number": "F-2", "amount_due": 2500 }
+            ])),
+            custom_data: Some(json!({ "full_name": "ACME SAS" })),
+            email_template_id: None,
+            threshold_id: None,
+        };
+        render_client_email(&CompiledTemplate::compile(template, &[], &[]), &client, config, layout, &batch())
+    }
+
+    #[test]
+    fn test_outlook_and_dark_mode_output_default_layout() {
+        let rendered = render_client_compat_fixture(&Default::default(), &Default::default());
+
+        assert!(rendered.render_error.is_none());
+        assert!(rendered.html_body.contains(r#"<meta content="light dark" name="color-scheme">"#));
+        assert!(rendered.html_body.contains("<!--[if mso]><table"), "ghost table around the container");
+        assert!(rendered.html_body.contains("<v:roundrect"), "VML button for Outlook");
+        assert!(rendered.html_body.contains("@media (prefers-color-scheme: dark)"));
+        golden::assert_golden("outlook_dark_mode_default.html", &rendered.html_body);
+    }
+
+    #[test]
+    fn test_outlook_and_dark_mode_output_branded_layout() {
+        let config = models::CollectionConfig { language: Some("en".to_string()), ..Default::default() };
+        let layout = models::EmailLayout {
+            brand_name: Some("ACME".to_string()),
+            logo_url: Some("https://x.supabase.co/storage/v1/object/public/business-media/images/apex/b1/logo.png".to_string()),
+            primary_color: Some("#0a7cff".to_string()),
+            link_color: Some("#ff6600".to_string()),
+            container_width: Some(600),
+            contact_email: Some("cartera@acme.co".to_string()),
+            ..Default::default()
+        };
+        let rendered = render_client_compat_fixture(&config, &layout);
+
+        assert!(rendered.html_body.contains(r#"width="600""#), "Outlook ghost table uses the business width");
+        assert!(rendered.html_body.contains(r##"fillcolor="#0a7cff""##));
+        golden::assert_golden("outlook_dark_mode_branded.html", &rendered.html_body);
+    }
+
+    // ─────────────────────────────────────────────────────────────────────────────
+    // EventBridge timezone cron tests
+    //
+    // Core property: cron fields MUST reflect the LOCAL time in the target timezone.
+    // ScheduleExpressionTimezone tells EventBridge how to interpret the cron, so the
+    // fields in the cron expression must already be in that timezone — NOT UTC.
+    //
+    // This mirrors how TypeScript does it:
+    //   new Intl.DateTimeFormat({ timeZone }).formatToParts(utcDate)
+    // ─────────────────────────────────────────────────────────────────────────────
+
+    /// Helper to parse a UTC RFC3339 string into DateTime<Utc>
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_bogota_is_utc_minus_5() {
+        // America/Bogota = UTC-5 (no DST)
+        // UTC 15:30 → Bogotá 10:30 same day
+        let t = utc("2026-03-15T15:30:00Z");
+        let (cron, local) = build_eventbridge_cron(&t, "America/Bogota");
+
+        assert_eq!(cron, "cron(30 10 15 3 ? 2026)",
+            "Bogotá is UTC-5: 15:30 UTC should become 10:30 local. Got local={}", local);
+        assert!(local.contains("10:30"), "Local time should be 10:30, got: {}", local);
+    }
+
+    #[test]
+    fn test_bogota_midnight_boundary() {
+        // UTC 02:00 on March 16 → Bogotá 21:00 on March 15 (day changes!)
+        let t = utc("2026-03-16T02:00:00Z");
+        let (cron, local) = build_eventbridge_cron(&t, "America/Bogota");
+
+        assert_eq!(cron, "cron(0 21 15 3 ? 2026)",
+            "UTC 02:00 March 16 = Bogotá 21:00 March 15. Got local={}", local);
+    }
+
+    #[test]
+    fn test_new_york_dst_utc_minus_4() {
+        // America/New_York in summer (EDT = UTC-4)
+        // UTC 20:00 July 1 → New York 16:00
+        let t = utc("2026-07-01T20:00:00Z");
+        let (cron, local) = build_eventbridge_cron(&t, "America/New_York");
+
+        assert_eq!(cron, "cron(0 16 1 7 ? 2026)",
+            "EDT is UTC-4: 20:00 UTC = 16:00 New York. Got local={}", local);
+    }
+
+    #[test]
+    fn test_madrid_cet_utc_plus_1() {
+        // Europe/Madrid in winter (CET = UTC+1)
+        // UTC 09:00 Jan 10 → Madrid 10:00
+        let t = utc("2026-01-10T09:00:00Z");
+        let (cron, local) = build_eventbridge_cron(&t, "Europe/Madrid");
+
+        assert_eq!(cron, "cron(0 10 10 1 ? 2026)",
+            "CET is UTC+1: 09:00 UTC = 10:00 Madrid. Got local={}", local);
+    }
+
+    #[test]
+    fn test_invalid_timezone_falls_back_to_bogota() {
+        // An invalid TZ string should silently fall back to America/Bogota (UTC-5)
+        // UTC 15:00 → Bogotá 10:00
+        let t = utc("2026-06-01T15:00:00Z");
+        let (cron, _) = build_eventbridge_cron(&t, "Not/A_Valid_Timezone");
+
+        assert_eq!(cron, "cron(0 10 1 6 ? 2026)",
+            "Fallback to Bogota (UTC-5): 15:00 UTC = 10:00 local");
+    }
+
+    #[test]
+    fn test_utc_vs_local_cron_differ_when_offset_nonzero() {
+        // Prove by example that UTC-based cron != local cron for any non-UTC timezone.
+        // If someone accidentally uses UTC fields with ScheduleExpressionTimezone=Bogota,
+        // EventBridge would fire 5 hours LATE.
+        let t = utc("2026-03-15T15:30:00Z");
+        let (local_cron, _) = build_eventbridge_cron(&t, "America/Bogota");
+        let utc_cron = format!(
+            "cron({} {} {} {} ? {})",
+            t.minute(), t.hour(), t.day(), t.month(), t.year()
+        );
+
+        assert_ne!(local_cron, utc_cron,
+            "UTC cron and local cron must differ for non-UTC timezones: {} vs {}", local_cron, utc_cron);
+        assert_eq!(utc_cron,  "cron(30 15 15 3 ? 2026)"); // The WRONG value that would be sent
+        assert_eq!(local_cron, "cron(30 10 15 3 ? 2026)"); // The CORRECT local value
+    }
 }
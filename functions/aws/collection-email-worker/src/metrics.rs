@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+use serde_json::{json, Map, Value};
+use chrono::Utc;
+
+/// Maximum number of values per metric allowed by CloudWatch in a single EMF document
+const MAX_VALUES_PER_METRIC: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unit {
+    Count,
+    Milliseconds,
+}
+
+impl Unit {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Unit::Count => "Count",
+            Unit::Milliseconds => "Milliseconds",
+        }
+    }
+}
+
+/// Collects metrics for one dimension set and writes them to stdout using the
+/// CloudWatch Embedded Metric Format (EMF). Lambda ships stdout to CloudWatch Logs,
+/// which extracts the metrics without any extra API calls.
+///
+/// Counters are summed into a single value; timings keep every sample so that
+/// CloudWatch can compute percentiles.
+pub struct Metrics {
+    namespace: String,
+    dimensions: BTreeMap<String, String>,
+    counters: BTreeMap<String, f64>,
+    samples: BTreeMap<String, (Unit, Vec<f64>)>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let namespace = std::env::var("METRICS_NAMESPACE")
+            .unwrap_or_else(|_| "Apex/Collection".to_string());
+
+        Self {
+            namespace,
+            dimensions: BTreeMap::new(),
+            counters: BTreeMap::new(),
+            samples: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_dimension(mut self, name: &str, value: &str) -> Self {
+        self.dimensions.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// Adds `value` to a counter metric
+    pub fn count(&mut self, name: &str, value: u32) {
+        *self.counters.entry(name.to_string()).or_insert(0.0) += value as f64;
+    }
+
+    /// Records a single sample (e.g. a latency) for a metric
+    pub fn record(&mut self, name: &str, unit: Unit, value: f64) {
+        let entry = self.samples.entry(name.to_string()).or_insert((unit, Vec::new()));
+        entry.1.push(value);
+
+        if entry.1.len() >= MAX_VALUES_PER_METRIC {
+            self.flush();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty() && self.samples.is_empty()
+    }
+
+    /// Builds the EMF document for the collected values, or None if nothing was recorded
+    pub fn to_emf(&self, timestamp_ms: i64) -> Option<Value> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let mut root = Map::new();
+        let mut definitions = Vec::new();
+
+        for (name, value) in &self.counters {
+            definitions.push(json!({ "Name": name, "Unit": Unit::Count.as_str() }));
+            root.insert(name.clone(), json!(value));
+        }
+
+        for (name, (unit, values)) in &self.samples {
+            definitions.push(json!({ "Name": name, "Unit": unit.as_str() }));
+            root.insert(name.clone(), json!(values));
+        }
+
+        for (name, value) in &self.dimensions {
+            root.insert(name.clone(), json!(value));
+        }
+
+        let dimension_names: Vec<&String> = self.dimensions.keys().collect();
+
+        root.insert("_aws".to_string(), json!({
+            "Timestamp": timestamp_ms,
+            "CloudWatchMetrics": [{
+                "Namespace": self.namespace,
+                "Dimensions": [dimension_names],
+                "Metrics": definitions
+            }]
+        }));
+
+        Some(Value::Object(root))
+    }
+
+    /// Writes the collected metrics as a single EMF log line and resets them
+    pub fn flush(&mut self) {
+        if let Some(doc) = self.to_emf(Utc::now().timestamp_millis()) {
+            println!("{}", doc);
+        }
+        self.counters.clear();
+        self.samples.clear();
+    }
+}
+
+impl Drop for Metrics {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_emf_document_shape() {
+        let mut metrics = Metrics::new()
+            .with_dimension("Provider", "ses")
+            .with_dimension("BusinessId", "biz-1");
+        metrics.count("EmailsSent", 1);
+        metrics.count("EmailsSent", 2);
+        metrics.record("ProviderLatency", Unit::Milliseconds, 120.0);
+        metrics.record("ProviderLatency", Unit::Milliseconds, 80.0);
+
+        let line = metrics.to_emf(1_700_000_000_000).unwrap().to_string();
+        let doc: Value = serde_json::from_str(&line).unwrap();
+
+        assert_eq!(doc["EmailsSent"], json!(3.0));
+        assert_eq!(doc["ProviderLatency"], json!([120.0, 80.0]));
+        assert_eq!(doc["Provider"], "ses");
+        assert_eq!(doc["BusinessId"], "biz-1");
+
+        let cw = &doc["_aws"]["CloudWatchMetrics"][0];
+        assert_eq!(doc["_aws"]["Timestamp"], 1_700_000_000_000i64);
+        assert_eq!(cw["Dimensions"], json!([["BusinessId", "Provider"]]));
+
+        let names: Vec<&str> = cw["Metrics"].as_array().unwrap()
+            .iter()
+            .map(|m| m["Name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["EmailsSent", "ProviderLatency"]);
+        assert_eq!(cw["Metrics"][1]["Unit"], "Milliseconds");
+    }
+
+    #[test]
+    fn test_empty_metrics_emit_nothing() {
+        let metrics = Metrics::new().with_dimension("Provider", "ses");
+        assert!(metrics.to_emf(0).is_none());
+    }
+
+    #[test]
+    fn test_flush_resets_values() {
+        let mut metrics = Metrics::new().with_dimension("Provider", "ses");
+        metrics.count("EmailsFailed", 1);
+        metrics.flush();
+        assert!(metrics.is_empty());
+    }
+}
@@ -8,6 +8,9 @@ pub struct CollectionExecution {
     pub email_template_id: Option<String>,
     pub execution_mode: String,
     pub attachment_ids: Option<Vec<String>>,
+    /// 'none' | 'amount_due' | 'days_overdue' | 'threshold'
+    #[serde(default)]
+    pub priority_strategy: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -74,10 +77,12 @@ impl CollectionClient {
         self.custom_data.as_ref()?.get("full_name")?.as_str()
     }
 
+    #[allow(dead_code)]
     pub fn nit(&self) -> Option<&str> {
         self.custom_data.as_ref()?.get("nit")?.as_str()
     }
 
+    #[allow(dead_code)]
     pub fn company_name(&self) -> Option<&str> {
         self.custom_data.as_ref()?.get("company_name")?.as_str()
     }
@@ -98,12 +103,24 @@ pub struct EmailTemplate {
     pub content: String,
 }
 
+/// Reusable block of a business's templates, included with `{{> name}}`. `version` is
+/// bumped by the database on every content change.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TemplatePartial {
+    pub name: String,
+    pub content: String,
+    #[serde(default)]
+    pub version: i64,
+}
+
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 pub struct LambdaEvent {
     pub execution_id: String,
 }
 
 // SQS Event Models - AWS SQS events use "Records" with capital R
+#[allow(dead_code)]
 #[derive(Deserialize, Debug, Clone)]
 pub struct SqsMessage {
     #[allow(dead_code)]
@@ -113,6 +130,7 @@ pub struct SqsMessage {
     pub body: Option<String>,
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 pub struct SqsEvent {
     #[serde(rename = "Records")]
@@ -120,6 +138,7 @@ pub struct SqsEvent {
 }
 
 // Batch message from SQS
+#[allow(dead_code)]
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct BatchMessage {
     pub batch_id: String,
@@ -131,6 +150,7 @@ pub struct BatchMessage {
     pub scheduled_for: Option<String>,
 }
 
+#[allow(dead_code)]
 impl BatchMessage {
     pub fn from_body(body: &str) -> Option<Self> {
         serde_json::from_str(body).ok()
@@ -150,6 +170,7 @@ pub struct ExecutionBatch {
 }
 
 // Email Blacklist model
+#[allow(dead_code)]
 #[derive(Deserialize, Debug, Clone)]
 pub struct EmailBlacklist {
     pub id: String,
@@ -160,3 +181,155 @@ pub struct EmailBlacklist {
     pub provider: String,
     pub bounced_at: String,
 }
+
+// Per-business settings from `collection_config` used while rendering emails
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct CollectionConfig {
+    /// ISO 4217 code; None keeps the legacy COP formatting without decimals
+    #[serde(default)]
+    pub currency: Option<String>,
+    /// BCP 47 locale for separators and symbol placement (e.g. "es-CO", "en-US")
+    #[serde(default)]
+    pub locale: Option<String>,
+    /// Language of the system text around templates ("es", "en"); defaults to the locale's
+    #[serde(default)]
+    pub language: Option<String>,
+    /// Overrides the currency's minor units
+    #[serde(default)]
+    pub amount_decimals: Option<u32>,
+    #[serde(default)]
+    pub show_currency_symbol: bool,
+    /// Hint for ambiguous invoice dates ('DD-MM-AAAA', 'MM/DD/AAAA'...)
+    #[serde(default)]
+    pub input_date_format: Option<String>,
+    /// Format dates are rendered in (default 'DD-MM-AAAA')
+    #[serde(default)]
+    pub output_date_format: Option<String>,
+    /// What to do when a template fails to render: 'strict' (default) or 'fallback'
+    #[serde(default)]
+    pub render_mode: Option<String>,
+    /// Template rendered instead in 'fallback' mode
+    #[serde(default)]
+    pub fallback_template_id: Option<String>,
+    /// custom_data fields holding HTML that templates render as markup (sanitized);
+    /// every other value is HTML-escaped
+    #[serde(default)]
+    pub rich_text_fields: Option<Vec<String>>,
+    /// Columns, sorting and totals of the `{{invoice_table}}` helper
+    #[serde(default)]
+    pub invoice_table: Option<InvoiceTableConfig>,
+    /// Payment page the signed `{{payment_url}}` links point to (`<base>/<token>`)
+    #[serde(default)]
+    pub payment_url_base: Option<String>,
+    /// Days payment links stay valid (default 30)
+    #[serde(default)]
+    pub payment_link_ttl_days: Option<i64>,
+    /// Text encoded in the `{{payment_qr}}` image, a Handlebars template over the client
+    /// data (e.g. `{{payment_url}}` or a bank-transfer reference); None disables the QR
+    #[serde(default)]
+    pub payment_qr_content: Option<String>,
+    /// Send `<img>`s hosted on the project's public storage (logos, banners) as inline
+    /// `cid:` parts instead of remote URLs, for clients that block remote images
+    #[serde(default)]
+    pub inline_storage_images: Option<bool>,
+}
+
+/// Settings of the `{{invoice_table}}` helper. Without columns the standard invoice
+/// fields present in the data are shown.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct InvoiceTableConfig {
+    #[serde(default)]
+    pub columns: Vec<InvoiceTableColumn>,
+    /// Invoice field to sort by; invoices keep their order when unset
+    #[serde(default)]
+    pub sort_by: Option<String>,
+    /// 'asc' (default) or 'desc'
+    #[serde(default)]
+    pub sort_order: Option<String>,
+    /// Adds a row with the sum of the amount columns (default true)
+    #[serde(default)]
+    pub show_totals: Option<bool>,
+    #[serde(default)]
+    pub totals_label: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct InvoiceTableColumn {
+    pub field: String,
+    /// Header text; standard fields have translated defaults
+    #[serde(default)]
+    pub label: Option<String>,
+    /// 'text', 'amount', 'date' or 'number'; guessed from the field name when unset
+    #[serde(default)]
+    pub format: Option<String>,
+    /// 'left', 'center' or 'right'; amounts and numbers default to right
+    #[serde(default)]
+    pub align: Option<String>,
+}
+
+/// How a client is handled when its template fails to render. The raw template is
+/// never sent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenderMode {
+    /// Fail the client with the render error
+    Strict,
+    /// Render the business fallback template through the full pipeline instead
+    Fallback,
+}
+
+impl RenderMode {
+    pub fn parse(value: Option<&str>) -> Self {
+        match value.map(|v| v.trim().to_lowercase()).as_deref() {
+            Some("fallback") => RenderMode::Fallback,
+            _ => RenderMode::Strict,
+        }
+    }
+}
+
+impl CollectionConfig {
+    pub fn rich_text_fields(&self) -> &[String] {
+        self.rich_text_fields.as_deref().unwrap_or_default()
+    }
+
+    /// Fallback template to use after a render failure, if the business opted into one
+    pub fn fallback_template(&self) -> Option<&str> {
+        match RenderMode::parse(self.render_mode.as_deref()) {
+            RenderMode::Fallback => self.fallback_template_id.as_deref().filter(|id| !id.trim().is_empty()),
+            RenderMode::Strict => None,
+        }
+    }
+}
+
+// Per-business branding from `collection_email_layouts`; unset fields keep the default layout
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct EmailLayout {
+    /// Shown as the logo's alt text
+    #[serde(default)]
+    pub brand_name: Option<String>,
+    /// Path inside the public `business-media` bucket, or an absolute URL
+    #[serde(default)]
+    pub logo_path: Option<String>,
+    /// Public URL of the logo, resolved from `logo_path` when the layout is loaded
+    #[serde(skip)]
+    pub logo_url: Option<String>,
+    #[serde(default)]
+    pub primary_color: Option<String>,
+    #[serde(default)]
+    pub background_color: Option<String>,
+    #[serde(default)]
+    pub link_color: Option<String>,
+    #[serde(default)]
+    pub text_color: Option<String>,
+    #[serde(default)]
+    pub container_width: Option<u32>,
+    #[serde(default)]
+    pub footer_text: Option<String>,
+    #[serde(default)]
+    pub contact_email: Option<String>,
+    #[serde(default)]
+    pub contact_phone: Option<String>,
+    #[serde(default)]
+    pub contact_address: Option<String>,
+    #[serde(default)]
+    pub legal_text: Option<String>,
+}
@@ -0,0 +1,408 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use chrono::{DateTime, Utc};
+use log::{info, warn, error};
+
+use crate::email_provider::{EmailMessage, EmailProvider};
+use crate::supabase::SupabaseService;
+use crate::control_tower::ExecutionLogger;
+
+/// Row of `collection_email_outbox`. Written before every provider call and
+/// completed afterwards, so a crash in between leaves evidence instead of a guess.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct OutboxRecord {
+    pub id: String,
+    pub idempotency_key: String,
+    pub client_id: String,
+    pub execution_id: String,
+    pub message_hash: String,
+    pub status: String,
+    pub attempt: i32,
+    pub message_id: Option<String>,
+    pub provider_message_id: Option<String>,
+    pub updated_at: Option<String>,
+}
+
+/// Result of trying to open an outbox entry for a send
+#[derive(Debug)]
+pub enum OutboxClaim {
+    /// The worker owns the entry and may call the provider using this Message-ID
+    Claimed { message_id: String },
+    /// A previous run already delivered this message to the provider
+    AlreadySent { message_id: String, provider_message_id: Option<String> },
+    /// A previous run crashed mid-send; only the reconciler may resolve it
+    InDoubt,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ReconcileSummary {
+    pub checked: i32,
+    pub resolved_sent: i32,
+    pub resolved_failed: i32,
+    pub still_pending: i32,
+    /// Past the give-up window without evidence, but the provider cannot prove the
+    /// message was never accepted; an operator has to check it
+    pub needs_operator: i32,
+}
+
+/// Deterministic key for one collection email: the same client in the same
+/// execution always maps to the same key, no matter how many times it is retried.
+pub fn idempotency_key(execution_id: &str, client_id: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(execution_id.as_bytes());
+    hasher.update(b":");
+    hasher.update(client_id.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// RFC 5322 Message-ID generated by the worker for one client and send attempt,
+/// e.g. `<client-uuid.2@apex.borls.com>`. Stable across crashes, so provider events
+/// can always be correlated back to the client without relying on provider ids.
+pub fn rfc_message_id(client_id: &str, attempt: i32) -> String {
+    let domain = std::env::var("MESSAGE_ID_DOMAIN")
+        .unwrap_or_else(|_| "apex.borls.com".to_string());
+    format!("<{}.{}@{}>", client_id, attempt, domain)
+}
+
+/// Hash of the rendered message, stored so operators can tell whether a
+/// re-send would have produced the same email.
+pub fn message_hash(message: &EmailMessage) -> String {
+    let mut hasher = Sha256::new();
+    for to in &message.to {
+        hasher.update(to.as_bytes());
+        hasher.update(b"\n");
+    }
+    hasher.update(message.subject.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(message.html_body.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(message.text_body.as_bytes());
+    for attachment in &message.attachments {
+        hasher.update(attachment.name.as_bytes());
+        hasher.update(&attachment.data);
+    }
+    for image in &message.inline_images {
+        hasher.update(image.content_id.as_bytes());
+        hasher.update(&image.data);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Decides what to do with an outbox row left behind by a previous run
+fn classify_existing(record: &OutboxRecord) -> OutboxClaim {
+    match record.status.as_str() {
+        "sent" => OutboxClaim::AlreadySent {
+            // Rows written before worker-generated ids fall back to the provider id
+            message_id: record.message_id.clone()
+                .or_else(|| record.provider_message_id.clone())
+                .unwrap_or_default(),
+            provider_message_id: record.provider_message_id.clone(),
+        },
+        "failed" => OutboxClaim::Claimed {
+            message_id: rfc_message_id(&record.client_id, record.attempt + 1),
+        },
+        _ => OutboxClaim::InDoubt,
+    }
+}
+
+/// Opens the outbox entry for a send. Must be called before `provider.send_email`;
+/// the returned Message-ID is persisted in the entry before the provider sees it.
+pub async fn begin(
+    supabase: &SupabaseService,
+    key: &str,
+    client_id: &str,
+    execution_id: &str,
+    business_id: &str,
+    hash: &str,
+) -> Result<OutboxClaim, Box<dyn Error + Send + Sync>> {
+    let first_message_id = rfc_message_id(client_id, 1);
+    let inserted = supabase.insert_outbox_record(&json!({
+        "idempotency_key": key,
+        "client_id": client_id,
+        "execution_id": execution_id,
+        "business_id": business_id,
+        "message_hash": hash,
+        "status": "pending",
+        "attempt": 1,
+        "message_id": first_message_id
+    })).await?;
+
+    if inserted {
+        return Ok(OutboxClaim::Claimed { message_id: first_message_id });
+    }
+
+    let Some(existing) = supabase.get_outbox_record(key).await? else {
+        return Err(format!("Outbox entry {} conflicted but could not be read", key).into());
+    };
+
+    match classify_existing(&existing) {
+        OutboxClaim::Claimed { message_id } => {
+            // Re-open a failed entry. Filtering on the previous attempt makes this a
+            // compare-and-swap, so two workers can't both re-open it.
+            let reopened = supabase.update_outbox_record(key, "failed", Some(existing.attempt), &json!({
+                "status": "pending",
+                "attempt": existing.attempt + 1,
+                "message_id": message_id,
+                "message_hash": hash,
+                "error": null
+            })).await?;
+            Ok(if reopened { OutboxClaim::Claimed { message_id } } else { OutboxClaim::InDoubt })
+        }
+        other => Ok(other),
+    }
+}
+
+/// Marks the entry as accepted by the provider
+pub async fn complete(
+    supabase: &SupabaseService,
+    key: &str,
+    provider: &str,
+    message_id: &str,
+    resolved_by: &str,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    supabase.update_outbox_record(key, "pending", None, &json!({
+        "status": "sent",
+        "provider": provider,
+        "provider_message_id": message_id,
+        "resolved_by": resolved_by,
+        "completed_at": Utc::now().to_rfc3339()
+    })).await
+}
+
+/// Marks the entry as rejected by the provider so it can be retried
+pub async fn fail(
+    supabase: &SupabaseService,
+    key: &str,
+    error: &str,
+    resolved_by: &str,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    supabase.update_outbox_record(key, "pending", None, &json!({
+        "status": "failed",
+        "error": error,
+        "resolved_by": resolved_by,
+        "completed_at": Utc::now().to_rfc3339()
+    })).await
+}
+
+/// Resolves in-doubt outbox entries (pending for longer than OUTBOX_IN_DOUBT_AFTER_MINUTES).
+///
+/// Evidence is checked in this order:
+/// 1. The event handler may already have completed the entry from a provider event
+///    tagged with the idempotency key.
+/// 2. The provider is asked whether it accepted a message with that key.
+/// 3. If neither has seen the message after OUTBOX_GIVE_UP_AFTER_HOURS and the provider
+///    leaves evidence of every accepted message (`confirms_every_send`), it never
+///    accepted it, so the entry is failed and the client is marked failed for an
+///    operator to re-send safely. Otherwise the entry stays in doubt for an operator.
+///
+/// A provider event that arrives after the entry was failed still completes it as sent.
+pub async fn reconcile(
+    supabase: &SupabaseService,
+    provider: &dyn EmailProvider,
+    logger: &ExecutionLogger,
+    execution_id: Option<&str>,
+) -> Result<ReconcileSummary, Box<dyn Error + Send + Sync>> {
+    let in_doubt_minutes = env_i64("OUTBOX_IN_DOUBT_AFTER_MINUTES", 15);
+    let give_up_hours = env_i64("OUTBOX_GIVE_UP_AFTER_HOURS", 24);
+
+    let now = Utc::now();
+    let cutoff = now - chrono::Duration::minutes(in_doubt_minutes);
+    let records = supabase.get_stale_outbox_records(&cutoff.to_rfc3339(), execution_id).await?;
+    info!("[outbox] Reconciling {} in-doubt entries", records.len());
+
+    let mut summary = ReconcileSummary::default();
+
+    for record in records {
+        summary.checked += 1;
+
+        // 1. Re-read: the event handler resolves entries as events arrive
+        match supabase.get_outbox_record(&record.idempotency_key).await {
+            Ok(Some(current)) if current.status != "pending" => {
+                info!("[outbox] Entry {} already resolved as {}", record.idempotency_key, current.status);
+                if current.status == "sent" {
+                    mark_client_accepted(supabase, &current).await;
+                    summary.resolved_sent += 1;
+                }
+                continue;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!("[outbox] Failed to re-read entry {}: {}", record.idempotency_key, e);
+                summary.still_pending += 1;
+                continue;
+            }
+        }
+
+        // 2. Ask the provider
+        match provider.find_sent_message(&record.idempotency_key).await {
+            Ok(Some(message_id)) => {
+                info!("[outbox] Provider confirmed entry {} as message {}", record.idempotency_key, message_id);
+                complete(supabase, &record.idempotency_key, provider.provider_name(), &message_id, "provider").await?;
+                let resolved = OutboxRecord {
+                    provider_message_id: Some(message_id.clone()),
+                    ..record.clone()
+                };
+                mark_client_accepted(supabase, &resolved).await;
+                let _ = logger.log_event(&record.execution_id, None, "OUTBOX_RESOLVED", Some(json!({
+                    "client_id": record.client_id,
+                    "outcome": "sent",
+                    "message_id": message_id
+                }))).await;
+                summary.resolved_sent += 1;
+                continue;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!("[outbox] Provider lookup failed for entry {}: {}", record.idempotency_key, e);
+                summary.still_pending += 1;
+                continue;
+            }
+        }
+
+        // 3. No evidence of a send after the give-up window
+        let updated_at = record.updated_at.as_deref()
+            .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+            .map(|dt| dt.with_timezone(&Utc))
+            .unwrap_or(now);
+
+        if updated_at > now - chrono::Duration::hours(give_up_hours) {
+            summary.still_pending += 1;
+        } else if provider.confirms_every_send() {
+            warn!("[outbox] No provider evidence for entry {} after {}h, marking as not sent", record.idempotency_key, give_up_hours);
+            fail(supabase, &record.idempotency_key, "No provider evidence of send", "timeout").await?;
+            update_client(supabase, &record.client_id, "failed", json!({
+                "error": "Send not confirmed by provider; safe to re-send"
+            })).await;
+            let _ = logger.log_event(&record.execution_id, None, "OUTBOX_RESOLVED", Some(json!({
+                "client_id": record.client_id,
+                "outcome": "not_sent"
+            }))).await;
+            summary.resolved_failed += 1;
+        } else {
+            warn!("[outbox] No evidence for entry {} after {}h, but {} cannot confirm every send; leaving it for an operator",
+                record.idempotency_key, give_up_hours, provider.provider_name());
+            summary.needs_operator += 1;
+        }
+    }
+
+    info!("[outbox] Reconcile summary: {:?}", summary);
+    Ok(summary)
+}
+
+async fn mark_client_accepted(supabase: &SupabaseService, record: &OutboxRecord) {
+    let message_id = record.message_id.as_ref().or(record.provider_message_id.as_ref());
+    update_client(supabase, &record.client_id, "accepted", json!({
+        "message_id": message_id,
+        "provider_message_id": record.provider_message_id
+    })).await;
+}
+
+/// Sets the client status, merging `fields` into its existing custom_data
+async fn update_client(supabase: &SupabaseService, client_id: &str, status: &str, fields: serde_json::Value) {
+    let custom_data = match supabase.get_clients_by_ids(&[client_id.to_string()]).await {
+        Ok(clients) => clients.into_iter().next().and_then(|c| c.custom_data),
+        Err(e) => {
+            error!("[outbox] Failed to load client {}: {}", client_id, e);
+            return;
+        }
+    };
+
+    let mut custom_data = custom_data.unwrap_or(json!({}));
+    if let (Some(obj), Some(fields)) = (custom_data.as_object_mut(), fields.as_object()) {
+        obj.extend(fields.clone());
+    }
+
+    if let Err(e) = supabase.update_client_status(client_id, status, Some(custom_data)).await {
+        error!("[outbox] Failed to mark client {} as {}: {}", client_id, status, e);
+    }
+}
+
+fn env_i64(name: &str, default: i64) -> i64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(status: &str, message_id: Option<&str>) -> OutboxRecord {
+        OutboxRecord {
+            id: "row-1".to_string(),
+            idempotency_key: idempotency_key("exec-1", "client-1"),
+            client_id: "client-1".to_string(),
+            execution_id: "exec-1".to_string(),
+            message_hash: "hash".to_string(),
+            status: status.to_string(),
+            attempt: 1,
+            message_id: Some(rfc_message_id("client-1", 1)),
+            provider_message_id: message_id.map(|s| s.to_string()),
+            updated_at: None,
+        }
+    }
+
+    #[test]
+    fn test_idempotency_key_is_deterministic() {
+        let a = idempotency_key("exec-1", "client-1");
+        assert_eq!(a, idempotency_key("exec-1", "client-1"));
+        assert_ne!(a, idempotency_key("exec-1", "client-2"));
+        assert_ne!(a, idempotency_key("exec-2", "client-1"));
+        assert_eq!(a.len(), 64);
+    }
+
+    #[test]
+    fn test_message_hash_changes_with_content() {
+        let mut message = EmailMessage {
+            to: vec!["a@example.com".to_string()],
+            subject: "Recordatorio".to_string(),
+            html_body: "<p>Hola</p>".to_string(),
+            text_body: "Hola".to_string(),
+            from: "APEX <siesa@borls.com>".to_string(),
+            attachments: vec![],
+            inline_images: vec![],
+            client_id: None,
+            execution_id: None,
+            message_id: None,
+            idempotency_key: None,
+        };
+        let before = message_hash(&message);
+        assert_eq!(before, message_hash(&message));
+
+        message.html_body = "<p>Hola de nuevo</p>".to_string();
+        assert_ne!(before, message_hash(&message));
+    }
+
+    #[test]
+    fn test_existing_sent_entry_is_not_resent() {
+        match classify_existing(&record("sent", Some("msg-1"))) {
+            OutboxClaim::AlreadySent { message_id, provider_message_id } => {
+                assert_eq!(message_id, "<client-1.1@apex.borls.com>");
+                assert_eq!(provider_message_id.as_deref(), Some("msg-1"));
+            }
+            other => panic!("expected AlreadySent, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_rfc_message_id_format() {
+        assert_eq!(rfc_message_id("7f3c", 2), "<7f3c.2@apex.borls.com>");
+    }
+
+    #[test]
+    fn test_existing_pending_entry_is_in_doubt() {
+        assert!(matches!(classify_existing(&record("pending", None)), OutboxClaim::InDoubt));
+    }
+
+    #[test]
+    fn test_existing_failed_entry_can_be_retried() {
+        // A retry gets a new Message-ID so provider events of each attempt stay distinct
+        match classify_existing(&record("failed", None)) {
+            OutboxClaim::Claimed { message_id } => assert_eq!(message_id, "<client-1.2@apex.borls.com>"),
+            other => panic!("expected Claimed, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::LazyLock;
+use handlebars::Handlebars;
+use regex::Regex;
+
+use crate::escaping;
+use crate::helpers;
+use crate::models::TemplatePartial;
+use crate::tiptap::preprocess_tiptap_template;
+
+/// `{{> name}}`, `{{~> name}}` and `{{#> name}}...{{/name}}` references
+static PARTIAL_REFERENCE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{\{~?#?>\s*([A-Za-z_][\w-]*)").unwrap());
+
+/// Preprocessing shared by templates and partials: TipTap clean-up, `.length` paths and
+/// raw rich fields
+pub fn preprocess(content: &str, rich_fields: &[String]) -> String {
+    let content = preprocess_tiptap_template(content);
+    escaping::raw_rich_fields(&helpers::rewrite_length_paths(&content), rich_fields)
+}
+
+/// Names of the partials `source` includes directly
+pub fn references(source: &str) -> BTreeSet<String> {
+    PARTIAL_REFERENCE.captures_iter(source).map(|caps| caps[1].to_string()).collect()
+}
+
+/// The business's partials, preprocessed and checked for cycles once per template
+/// compilation. Only the partials a template actually reaches are reported against it,
+/// so a broken partial doesn't fail templates that never include it.
+pub struct PartialSet {
+    contents: BTreeMap<String, (String, i64)>,
+    errors: BTreeMap<String, String>,
+}
+
+impl PartialSet {
+    pub fn new(partials: &[TemplatePartial], rich_fields: &[String]) -> Self {
+        let contents: BTreeMap<String, (String, i64)> = partials.iter()
+            .map(|p| (p.name.clone(), (preprocess(&p.content, rich_fields), p.version)))
+            .collect();
+
+        let mut errors = BTreeMap::new();
+        for name in contents.keys() {
+            if let Some(cycle) = find_cycle(name, &contents) {
+                errors.insert(name.clone(), format!("Partial cycle: {}", cycle.join(" -> ")));
+            }
+        }
+        Self { contents, errors }
+    }
+
+    /// Registers every partial without errors on `handlebars`; compile errors are kept
+    /// and reported for the templates that include the partial
+    pub fn register(&mut self, handlebars: &mut Handlebars) {
+        for (name, (content, _)) in &self.contents {
+            if self.errors.contains_key(name) {
+                continue;
+            }
+            if let Err(e) = handlebars.register_partial(name, content) {
+                self.errors.insert(name.clone(), format!("Partial {}: {}", name, e));
+            }
+        }
+    }
+
+    /// Partials `source` includes, directly or through other partials, as `name@version`,
+    /// or the first problem among them: an unknown name, a cycle or a compile error
+    pub fn resolve(&self, source: &str) -> Result<Vec<String>, String> {
+        let mut seen = BTreeSet::new();
+        let mut pending: Vec<String> = references(source).into_iter().collect();
+
+        while let Some(name) = pending.pop() {
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+            let Some((content, _)) = self.contents.get(&name) else {
+                return Err(format!("Unknown partial '{}'", name));
+            };
+            if let Some(e) = self.errors.get(&name) {
+                return Err(e.clone());
+            }
+            pending.extend(references(content));
+        }
+
+        Ok(seen.into_iter().map(|name| format!("{}@{}", name, self.contents[&name].1)).collect())
+    }
+}
+
+/// Path of partials leading from `start` back to itself, if there is one
+fn find_cycle(start: &str, contents: &BTreeMap<String, (String, i64)>) -> Option<Vec<String>> {
+    fn visit(
+        name: &str,
+        start: &str,
+        contents: &BTreeMap<String, (String, i64)>,
+        path: &mut Vec<String>,
+        visited: &mut BTreeSet<String>,
+    ) -> bool {
+        for next in contents.get(name).map(|(c, _)| references(c)).unwrap_or_default() {
+            if next == start {
+                path.push(next);
+                return true;
+            }
+            if !visited.insert(next.clone()) {
+                continue;
+            }
+            path.push(next.clone());
+            if visit(&next, start, contents, path, visited) {
+                return true;
+            }
+            path.pop();
+        }
+        false
+    }
+
+    let mut path = vec![start.to_string()];
+    visit(start, start, contents, &mut path, &mut BTreeSet::new()).then_some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(name: &str, content: &str, version: i64) -> TemplatePartial {
+        TemplatePartial { name: name.to_string(), content: content.to_string(), version }
+    }
+
+    #[test]
+    fn test_resolve_reports_versions_and_problems() {
+        let partials = vec![
+            partial("firma", "<p>{{> contacto}}</p>", 3),
+            partial("contacto", "<p>Cartera</p>", 1),
+            partial("a", "{{> b}}", 1),
+            partial("b", "{{~> a}}", 2),
+            partial("roto", "{{#if x}}", 1),
+        ];
+        let mut set = PartialSet::new(&partials, &[]);
+        set.register(&mut Handlebars::new());
+
+        assert_eq!(set.resolve("{{> firma}}").unwrap(), vec!["contacto@1", "firma@3"]);
+        assert_eq!(set.resolve("<p>sin parciales</p>").unwrap(), Vec::<String>::new());
+        assert_eq!(set.resolve("{{> a}}").unwrap_err(), "Partial cycle: a -> b -> a");
+        assert_eq!(set.resolve("{{> falta}}").unwrap_err(), "Unknown partial 'falta'");
+        assert!(set.resolve("{{> roto}}").unwrap_err().starts_with("Partial roto:"));
+    }
+}
@@ -0,0 +1,230 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+use crate::helpers::parse_number;
+use crate::models::CollectionConfig;
+
+/// Days a payment link stays valid when the business doesn't set `payment_link_ttl_days`
+const DEFAULT_TTL_DAYS: i64 = 30;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What a payment token vouches for. `invoice_number` is None for the link that pays
+/// the client's whole balance.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PaymentClaims {
+    #[serde(rename = "c")]
+    pub client_id: String,
+    #[serde(rename = "i", default, skip_serializing_if = "Option::is_none")]
+    pub invoice_number: Option<String>,
+    #[serde(rename = "a")]
+    pub amount: f64,
+    /// Unix timestamp (seconds) after which the token is rejected
+    #[serde(rename = "e")]
+    pub expires_at: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PaymentTokenError {
+    Malformed,
+    InvalidSignature,
+    Expired,
+}
+
+impl std::fmt::Display for PaymentTokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PaymentTokenError::Malformed => write!(f, "Malformed payment token"),
+            PaymentTokenError::InvalidSignature => write!(f, "Invalid payment token signature"),
+            PaymentTokenError::Expired => write!(f, "Payment token expired"),
+        }
+    }
+}
+
+impl std::error::Error for PaymentTokenError {}
+
+fn mac(secret: &[u8]) -> HmacSha256 {
+    HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length")
+}
+
+/// Token for `claims`: `<payload>.<signature>`, both base64url without padding. The
+/// payload is the claims as JSON and the signature its HMAC-SHA256 with `secret`.
+pub fn sign(claims: &PaymentClaims, secret: &[u8]) -> String {
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).unwrap_or_default());
+    let mut mac = mac(secret);
+    mac.update(payload.as_bytes());
+    format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes()))
+}
+
+/// Checks a token produced by [`sign`] and returns its claims. The signature is compared
+/// in constant time before the payload is read.
+pub fn verify(token: &str, secret: &[u8], now: DateTime<Utc>) -> Result<PaymentClaims, PaymentTokenError> {
+    let (payload, signature) = token.trim().split_once('.').ok_or(PaymentTokenError::Malformed)?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| PaymentTokenError::Malformed)?;
+
+    let mut mac = mac(secret);
+    mac.update(payload.as_bytes());
+    mac.verify_slice(&signature).map_err(|_| PaymentTokenError::InvalidSignature)?;
+
+    let claims: PaymentClaims = URL_SAFE_NO_PAD.decode(payload).ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or(PaymentTokenError::Malformed)?;
+    if now.timestamp() > claims.expires_at {
+        return Err(PaymentTokenError::Expired);
+    }
+    Ok(claims)
+}
+
+/// Signs payment links for one business: `<payment_url_base>/<token>`
+#[derive(Debug, Clone)]
+pub struct PaymentLinks {
+    base_url: String,
+    secret: Vec<u8>,
+    ttl_days: i64,
+}
+
+impl PaymentLinks {
+    /// None unless the business has a payment page and `PAYMENT_LINK_SECRET` is set
+    pub fn from_config(config: &CollectionConfig) -> Option<Self> {
+        let secret = std::env::var("PAYMENT_LINK_SECRET").ok().filter(|s| !s.trim().is_empty())?;
+        Self::new(config, secret.into_bytes())
+    }
+
+    pub fn new(config: &CollectionConfig, secret: Vec<u8>) -> Option<Self> {
+        let base_url = config.payment_url_base.as_deref()
+            .map(|url| url.trim().trim_end_matches('/'))
+            .filter(|url| url.starts_with("https://") || url.starts_with("http://"))?;
+        Some(Self {
+            base_url: base_url.to_string(),
+            secret,
+            ttl_days: config.payment_link_ttl_days.filter(|d| *d > 0).unwrap_or(DEFAULT_TTL_DAYS),
+        })
+    }
+
+    pub fn url(&self, claims: &PaymentClaims) -> String {
+        format!("{}/{}", self.base_url, sign(claims, &self.secret))
+    }
+
+    /// Adds `payment_url` to the raw client data (the whole balance) and to every invoice
+    /// with a number (that invoice's amount). Amounts that can't be read get no link rather
+    /// than a link for 0. Links expire at the end of the TTL counted from `today`, so
+    /// re-rendering the same day yields the same links.
+    pub fn add_payment_urls(&self, data: &mut Value, client_id: &str, today: NaiveDate) {
+        let expires_at = (today + Duration::days(self.ttl_days + 1))
+            .and_hms_opt(0, 0, 0)
+            .map(|dt| dt.and_utc().timestamp() - 1)
+            .unwrap_or_default();
+
+        if let Some(invoices) = data.get_mut("invoices").and_then(|v| v.as_array_mut()) {
+            for invoice in invoices.iter_mut() {
+                let Some(number) = invoice.get("invoice_number").and_then(|n| match n {
+                    Value::String(s) if !s.trim().is_empty() => Some(s.trim().to_string()),
+                    Value::Number(n) => Some(n.to_string()),
+                    _ => None,
+                }) else {
+                    continue;
+                };
+                let Some(amount) = invoice.get("amount_due").and_then(parse_number) else {
+                    log::warn!("[payment_link] Invoice {} of client {} has no readable amount_due; no payment link", number, client_id);
+                    continue;
+                };
+                let claims = PaymentClaims {
+                    client_id: client_id.to_string(),
+                    invoice_number: Some(number),
+                    amount,
+                    expires_at,
+                };
+                invoice["payment_url"] = json!(self.url(&claims));
+            }
+        }
+
+        let Some(amount) = data.get("total_amount_due").and_then(parse_number) else {
+            log::warn!("[payment_link] Client {} has no readable total_amount_due; no payment link", client_id);
+            return;
+        };
+        let claims = PaymentClaims {
+            client_id: client_id.to_string(),
+            invoice_number: None,
+            amount,
+            expires_at,
+        };
+        data["payment_url"] = json!(self.url(&claims));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"test-secret";
+
+    fn claims() -> PaymentClaims {
+        PaymentClaims {
+            client_id: "client-1".to_string(),
+            invoice_number: Some("F-1".to_string()),
+            amount: 1500000.0,
+            expires_at: 1_800_000_000,
+        }
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let token = sign(&claims(), SECRET);
+
+        assert_eq!(verify(&token, SECRET, now), Ok(claims()));
+        assert_eq!(verify(&token, b"other-secret", now), Err(PaymentTokenError::InvalidSignature));
+        assert_eq!(verify("no-signature", SECRET, now), Err(PaymentTokenError::Malformed));
+
+        let late = DateTime::from_timestamp(1_800_000_001, 0).unwrap();
+        assert_eq!(verify(&token, SECRET, late), Err(PaymentTokenError::Expired));
+
+        // Changing the amount invalidates the signature
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = PaymentClaims { amount: 1.0, ..claims() };
+        let forged_payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap());
+        let forged = format!("{}.{}", forged_payload, signature);
+        assert_eq!(verify(&forged, SECRET, now), Err(PaymentTokenError::InvalidSignature));
+    }
+
+    #[test]
+    fn test_add_payment_urls() {
+        let config = CollectionConfig {
+            payment_url_base: Some("https://pagos.example.com/p/".to_string()),
+            payment_link_ttl_days: Some(7),
+            ..Default::default()
+        };
+        let links = PaymentLinks::new(&config, SECRET.to_vec()).unwrap();
+        let mut data = json!({
+            "total_amount_due": "$ 1.502.500",
+            "invoices": [
+                { "invoice_number": "F-1", "amount_due": "1.500.000" },
+                { "amount_due": 2500 },
+                { "invoice_number": "F-3", "amount_due": "pendiente" }
+            ]
+        });
+        links.add_payment_urls(&mut data, "client-1", NaiveDate::from_ymd_opt(2026, 10, 19).unwrap());
+
+        let now = DateTime::parse_from_rfc3339("2026-10-26T23:59:59Z").unwrap().to_utc();
+        let url = data["invoices"][0]["payment_url"].as_str().unwrap();
+        let token = url.strip_prefix("https://pagos.example.com/p/").unwrap();
+        assert_eq!(verify(token, SECRET, now), Ok(PaymentClaims { expires_at: now.timestamp(), ..claims() }));
+        assert!(data["invoices"][1].get("payment_url").is_none(), "invoices without number get no link");
+        assert!(data["invoices"][2].get("payment_url").is_none(), "unreadable amounts get no link");
+
+        let token = data["payment_url"].as_str().unwrap().rsplit('/').next().unwrap();
+        let client = verify(token, SECRET, now).unwrap();
+        assert_eq!((client.invoice_number, client.amount), (None, 1502500.0));
+
+        let mut data = json!({ "total_amount_due": "", "invoices": [] });
+        links.add_payment_urls(&mut data, "client-1", NaiveDate::from_ymd_opt(2026, 10, 19).unwrap());
+        assert!(data.get("payment_url").is_none());
+
+        assert!(PaymentLinks::new(&CollectionConfig::default(), SECRET.to_vec()).is_none());
+    }
+}
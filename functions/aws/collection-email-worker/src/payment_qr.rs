@@ -0,0 +1,139 @@
+use handlebars::Handlebars;
+use qrcode::{Color, EcLevel, QrCode};
+use serde_json::Value;
+
+use crate::email_provider::InlineImage;
+use crate::helpers;
+use crate::models::CollectionConfig;
+
+/// Content id of the QR image; templates place it with `{{payment_qr}}`
+pub const CONTENT_ID: &str = "payment-qr.png";
+
+/// Pixels per QR module and modules of white border around the code
+const MODULE_PX: usize = 6;
+const QUIET_ZONE: usize = 4;
+
+const QR_TEMPLATE: &str = "payment_qr";
+
+/// Renders the business `payment_qr_content` for each client and encodes it as a PNG
+/// QR code. The content is a Handlebars template over the client data, e.g.
+/// `{{payment_url}}` or `Bancolombia ahorros 123-456789-00 Ref. {{nit}}`.
+pub struct PaymentQr {
+    handlebars: Handlebars<'static>,
+}
+
+impl PaymentQr {
+    /// None when the business has no QR content
+    pub fn from_config(config: &CollectionConfig) -> Result<Option<Self>, String> {
+        let Some(content) = config.payment_qr_content.as_deref().map(str::trim).filter(|c| !c.is_empty()) else {
+            return Ok(None);
+        };
+
+        let mut handlebars = Handlebars::new();
+        // The text goes into the QR code, not into HTML
+        handlebars.register_escape_fn(handlebars::no_escape);
+        helpers::register(&mut handlebars);
+        handlebars.register_template_string(QR_TEMPLATE, content)
+            .map_err(|e| format!("Invalid payment QR content: {}", e))?;
+        Ok(Some(Self { handlebars }))
+    }
+
+    /// QR image for the client `data`; None when the content renders empty
+    pub fn render(&self, data: &Value) -> Result<Option<InlineImage>, String> {
+        let text = self.handlebars.render(QR_TEMPLATE, data).map_err(|e| e.to_string())?;
+        let text = text.trim();
+        if text.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(InlineImage {
+            content_id: CONTENT_ID.to_string(),
+            content_type: "image/png".to_string(),
+            data: qr_png(text)?,
+        }))
+    }
+}
+
+/// Black-on-white grayscale PNG of a QR code for `text`, with medium error correction
+pub fn qr_png(text: &str) -> Result<Vec<u8>, String> {
+    let code = QrCode::with_error_correction_level(text.as_bytes(), EcLevel::M).map_err(|e| e.to_string())?;
+    let modules = code.width();
+    let colors = code.to_colors();
+    let size = (modules + 2 * QUIET_ZONE) * MODULE_PX;
+
+    let mut pixels = vec![255u8; size * size];
+    for (index, color) in colors.iter().enumerate() {
+        if *color != Color::Dark {
+            continue;
+        }
+        let x = (index % modules + QUIET_ZONE) * MODULE_PX;
+        let y = (index / modules + QUIET_ZONE) * MODULE_PX;
+        for row in y..y + MODULE_PX {
+            pixels[row * size + x..row * size + x + MODULE_PX].fill(0);
+        }
+    }
+
+    let mut png_data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png_data, size as u32, size as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    writer.write_image_data(&pixels).map_err(|e| e.to_string())?;
+    writer.finish().map_err(|e| e.to_string())?;
+    Ok(png_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Decodes `png_data` and checks it draws the QR code of `text` module by module
+    fn assert_png_encodes(png_data: &[u8], text: &str) {
+        let mut reader = png::Decoder::new(png_data).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let frame = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!(frame.color_type, png::ColorType::Grayscale);
+
+        let code = QrCode::with_error_correction_level(text.as_bytes(), EcLevel::M).unwrap();
+        let modules = code.width();
+        let size = (modules + 2 * QUIET_ZONE) * MODULE_PX;
+        assert_eq!((frame.width as usize, frame.height as usize), (size, size));
+
+        // Sample the centre pixel of every module, quiet zone included
+        let colors = code.to_colors();
+        for row in 0..modules + 2 * QUIET_ZONE {
+            for column in 0..modules + 2 * QUIET_ZONE {
+                let inside = (QUIET_ZONE..QUIET_ZONE + modules).contains(&row)
+                    && (QUIET_ZONE..QUIET_ZONE + modules).contains(&column);
+                let dark = inside && colors[(row - QUIET_ZONE) * modules + column - QUIET_ZONE] == Color::Dark;
+                let pixel = pixels[(row * MODULE_PX + MODULE_PX / 2) * frame.line_size + column * MODULE_PX + MODULE_PX / 2];
+                assert_eq!(pixel, if dark { 0 } else { 255 }, "module ({}, {})", row, column);
+            }
+        }
+    }
+
+    #[test]
+    fn test_qr_png_draws_the_code() {
+        let png_data = qr_png("https://pagos.example.com/p/abc.def").unwrap();
+        assert!(png_data.starts_with(b"\x89PNG\r\n\x1a\n"));
+        assert_png_encodes(&png_data, "https://pagos.example.com/p/abc.def");
+    }
+
+    #[test]
+    fn test_render_uses_client_data_unescaped() {
+        let config = CollectionConfig {
+            payment_qr_content: Some("{{payment_url}}".to_string()),
+            ..Default::default()
+        };
+        let qr = PaymentQr::from_config(&config).unwrap().unwrap();
+
+        let image = qr.render(&json!({ "payment_url": "https://pagos.example.com/p?a=1&b=2" })).unwrap().unwrap();
+        assert_eq!(image.content_id, CONTENT_ID);
+        assert_png_encodes(&image.data, "https://pagos.example.com/p?a=1&b=2");
+        assert_eq!(qr.render(&json!({})).unwrap(), None, "nothing to encode");
+
+        assert!(PaymentQr::from_config(&CollectionConfig::default()).unwrap().is_none());
+        let broken = CollectionConfig { payment_qr_content: Some("{{#if x}}".to_string()), ..Default::default() };
+        assert!(PaymentQr::from_config(&broken).is_err());
+    }
+}
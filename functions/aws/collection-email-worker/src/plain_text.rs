@@ -0,0 +1,509 @@
+use html5ever::tendril::TendrilSink;
+use html5ever::parse_document;
+use markup5ever_rcdom::{Handle, NodeData, RcDom};
+
+/// Paragraph text is wrapped at this width (RFC 5322 recommends 78 characters)
+const WRAP_WIDTH: usize = 78;
+/// Wider table columns are truncated so rows stay readable
+const MAX_COLUMN_WIDTH: usize = 40;
+/// Longer subjects are cut at a word boundary; most clients show far fewer characters
+pub const MAX_SUBJECT_CHARS: usize = 150;
+
+/// Converts the final HTML of an email into its plain-text alternative: paragraphs are
+/// wrapped, headings underlined, lists bulleted or numbered, data tables laid out as
+/// aligned columns and links turned into numbered footnotes. Layout tables
+/// (`role="presentation"` or single-column) are flattened.
+pub fn html_to_text(html: &str) -> String {
+    let dom = parse_document(RcDom::default(), Default::default()).one(html);
+    let mut renderer = Renderer::default();
+    renderer.block(&dom.document, 0);
+    renderer.flush_paragraph();
+
+    let mut text = collapse_blank_lines(&renderer.out.join("\n"));
+    if !renderer.links.is_empty() {
+        text.push_str("\n\n");
+        let footnotes: Vec<String> = renderer.links.iter()
+            .enumerate()
+            .map(|(i, url)| format!("[{}] {}", i + 1, url))
+            .collect();
+        text.push_str(&footnotes.join("\n"));
+    }
+    text
+}
+
+/// Turns a rendered subject into a single header-safe line: tags are stripped, entities
+/// decoded, whitespace (including line breaks) folded and the result limited to
+/// [`MAX_SUBJECT_CHARS`].
+pub fn subject_line(subject: &str) -> String {
+    let text = if subject.contains('<') || subject.contains('&') {
+        let dom = parse_document(RcDom::default(), Default::default()).one(subject);
+        let mut text = String::new();
+        collect_text(&dom.document, &mut text);
+        text
+    } else {
+        subject.to_string()
+    };
+
+    let line = normalize_space(&text.replace(|c: char| c.is_control(), " "));
+    if line.chars().count() <= MAX_SUBJECT_CHARS {
+        return line;
+    }
+
+    let cut: String = line.chars().take(MAX_SUBJECT_CHARS - 1).collect();
+    let cut = match cut.rfind(' ') {
+        Some(space) if space > cut.len() / 2 => cut[..space].trim_end().to_string(),
+        _ => cut,
+    };
+    format!("{}…", cut)
+}
+
+fn collect_text(node: &Handle, out: &mut String) {
+    match &node.data {
+        NodeData::Text { contents } => out.push_str(&contents.borrow()),
+        NodeData::Element { name, .. } if matches!(name.local.as_ref(), "head" | "style" | "script") => {}
+        NodeData::Element { name, .. } if is_block(name.local.as_ref()) || name.local.as_ref() == "br" => {
+            out.push(' ');
+            for child in node.children.borrow().iter() {
+                collect_text(child, out);
+            }
+            out.push(' ');
+        }
+        _ => {
+            for child in node.children.borrow().iter() {
+                collect_text(child, out);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Renderer {
+    out: Vec<String>,
+    paragraph: String,
+    links: Vec<String>,
+}
+
+fn element_name(node: &Handle) -> Option<&str> {
+    match &node.data {
+        NodeData::Element { name, .. } => Some(name.local.as_ref()),
+        _ => None,
+    }
+}
+
+fn attr(node: &Handle, name: &str) -> Option<String> {
+    match &node.data {
+        NodeData::Element { attrs, .. } => attrs.borrow().iter()
+            .find(|a| a.name.local.as_ref() == name)
+            .map(|a| a.value.to_string()),
+        _ => None,
+    }
+}
+
+fn is_block(name: &str) -> bool {
+    matches!(
+        name,
+        "p" | "div" | "section" | "article" | "header" | "footer" | "main" | "center"
+            | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "ul" | "ol" | "li" | "table"
+            | "tbody" | "thead" | "tfoot" | "tr" | "td" | "th" | "blockquote" | "hr" | "pre"
+            | "body" | "html"
+    )
+}
+
+impl Renderer {
+    fn flush_paragraph(&mut self) {
+        let text = normalize_space(&self.paragraph);
+        self.paragraph.clear();
+        if text.is_empty() {
+            return;
+        }
+        self.out.extend(wrap(&text, WRAP_WIDTH));
+        self.out.push(String::new());
+    }
+
+    fn block(&mut self, node: &Handle, depth: usize) {
+        for child in node.children.borrow().iter() {
+            self.node(child, depth);
+        }
+    }
+
+    fn node(&mut self, node: &Handle, depth: usize) {
+        let name = match &node.data {
+            NodeData::Text { contents } => {
+                self.paragraph.push_str(&contents.borrow());
+                return;
+            }
+            NodeData::Element { name, .. } => name.local.as_ref().to_string(),
+            NodeData::Document => return self.block(node, depth),
+            _ => return,
+        };
+
+        match name.as_str() {
+            "head" | "style" | "script" | "title" | "meta" | "link" => {}
+            "br" => {
+                let text = normalize_space(&self.paragraph);
+                self.paragraph.clear();
+                if !text.is_empty() {
+                    self.out.extend(wrap(&text, WRAP_WIDTH));
+                } else {
+                    self.out.push(String::new());
+                }
+            }
+            "hr" => {
+                self.flush_paragraph();
+                self.out.push("-".repeat(WRAP_WIDTH.min(40)));
+                self.out.push(String::new());
+            }
+            "a" => self.link(node),
+            "img" => {
+                if let Some(alt) = attr(node, "alt").filter(|a| !a.trim().is_empty()) {
+                    self.paragraph.push_str(&format!(" [{}] ", alt.trim()));
+                }
+            }
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.flush_paragraph();
+                let title = normalize_space(&self.inline_text(node));
+                if !title.is_empty() {
+                    let underline = match name.as_str() {
+                        "h1" => Some('='),
+                        "h2" => Some('-'),
+                        _ => None,
+                    };
+                    self.out.push(title.clone());
+                    if let Some(c) = underline {
+                        self.out.push(c.to_string().repeat(title.chars().count()));
+                    }
+                    self.out.push(String::new());
+                }
+            }
+            "ul" | "ol" => {
+                self.flush_paragraph();
+                self.list(node, name == "ol", depth);
+                self.out.push(String::new());
+            }
+            "blockquote" => {
+                self.flush_paragraph();
+                let mut inner = Renderer { links: std::mem::take(&mut self.links), ..Default::default() };
+                inner.block(node, depth);
+                inner.flush_paragraph();
+                self.links = inner.links;
+                while inner.out.last().is_some_and(|line| line.is_empty()) {
+                    inner.out.pop();
+                }
+                for line in inner.out {
+                    self.out.push(if line.is_empty() { ">".to_string() } else { format!("> {}", line) });
+                }
+                self.out.push(String::new());
+            }
+            "table" => {
+                self.flush_paragraph();
+                self.table(node, depth);
+            }
+            other if is_block(other) => {
+                self.flush_paragraph();
+                self.block(node, depth);
+                self.flush_paragraph();
+            }
+            _ => self.block(node, depth),
+        }
+    }
+
+    /// Link text followed by a footnote marker; bare URLs are printed as they are
+    fn link(&mut self, node: &Handle) {
+        let text = normalize_space(&self.inline_text(node));
+        let href = attr(node, "href").map(|h| h.trim().to_string()).unwrap_or_default();
+        let usable = !href.is_empty() && !href.starts_with('#') && !href.starts_with("javascript:");
+
+        if !usable {
+            self.paragraph.push_str(&text);
+            return;
+        }
+
+        let target = href.strip_prefix("mailto:").unwrap_or(&href);
+        if text.is_empty() {
+            self.paragraph.push_str(target);
+        } else if text == target || text == href {
+            self.paragraph.push_str(&text);
+        } else {
+            let index = match self.links.iter().position(|l| *l == href) {
+                Some(i) => i + 1,
+                None => {
+                    self.links.push(href.clone());
+                    self.links.len()
+                }
+            };
+            self.paragraph.push_str(&format!("{} [{}]", text, index));
+        }
+    }
+
+    /// Text of an inline subtree, collecting link footnotes on the way
+    fn inline_text(&mut self, node: &Handle) -> String {
+        let saved = std::mem::take(&mut self.paragraph);
+        let saved_out = std::mem::take(&mut self.out);
+        self.block(node, 0);
+        self.flush_paragraph();
+        let lines = std::mem::replace(&mut self.out, saved_out);
+        self.paragraph = saved;
+        lines.into_iter().filter(|l| !l.is_empty()).collect::<Vec<_>>().join(" ")
+    }
+
+    fn list(&mut self, node: &Handle, ordered: bool, depth: usize) {
+        let indent = "  ".repeat(depth);
+        let mut number = 0;
+
+        for item in node.children.borrow().iter() {
+            if element_name(item) != Some("li") {
+                continue;
+            }
+            number += 1;
+            let marker = if ordered { format!("{}. ", number) } else { "- ".to_string() };
+
+            // Nested lists are rendered after the item text, one level deeper
+            let mut inner = Renderer { links: std::mem::take(&mut self.links), ..Default::default() };
+            let mut nested = Vec::new();
+            for child in item.children.borrow().iter() {
+                match element_name(child) {
+                    Some("ul") | Some("ol") => nested.push(child.clone()),
+                    _ => inner.node(child, depth + 1),
+                }
+            }
+            inner.flush_paragraph();
+            self.links = std::mem::take(&mut inner.links);
+
+            let lines: Vec<&String> = inner.out.iter().filter(|l| !l.is_empty()).collect();
+            let continuation = " ".repeat(marker.chars().count());
+            for (i, line) in lines.iter().enumerate() {
+                let prefix = if i == 0 { &marker } else { &continuation };
+                self.out.push(format!("{}{}{}", indent, prefix, line));
+            }
+            if lines.is_empty() {
+                self.out.push(format!("{}{}", indent, marker.trim_end()));
+            }
+
+            for list in nested {
+                self.list(&list, element_name(&list) == Some("ol"), depth + 1);
+            }
+        }
+    }
+
+    fn table(&mut self, node: &Handle, depth: usize) {
+        let rows = table_rows(node);
+        let layout = attr(node, "role").as_deref() == Some("presentation")
+            || rows.iter().all(|(row, _)| cells(row).len() <= 1);
+
+        if layout {
+            self.block(node, depth);
+            self.flush_paragraph();
+            return;
+        }
+
+        let mut grid: Vec<(bool, Vec<String>)> = Vec::new();
+        for (row, in_thead) in &rows {
+            let row_cells = cells(row);
+            let header = *in_thead || row_cells.iter().all(|c| element_name(c) == Some("th"));
+            let texts: Vec<String> = row_cells.iter().map(|c| truncate(&self.inline_text(c), MAX_COLUMN_WIDTH)).collect();
+            if texts.iter().any(|t| !t.is_empty()) {
+                grid.push((header, texts));
+            }
+        }
+
+        let columns = grid.iter().map(|(_, r)| r.len()).max().unwrap_or(0);
+        let mut widths = vec![0usize; columns];
+        let mut numeric = vec![true; columns];
+        for (header, row) in &grid {
+            for (i, cell) in row.iter().enumerate() {
+                widths[i] = widths[i].max(cell.chars().count());
+                if !header && !cell.is_empty() && !looks_numeric(cell) {
+                    numeric[i] = false;
+                }
+            }
+        }
+
+        // Columns left empty by TipTap's padding cells are dropped
+        let visible: Vec<usize> = (0..columns).filter(|&i| widths[i] > 0).collect();
+
+        for (index, (header, row)) in grid.iter().enumerate() {
+            let line: Vec<String> = visible.iter()
+                .map(|&i| {
+                    let cell = row.get(i).map(String::as_str).unwrap_or("");
+                    let pad = widths[i].saturating_sub(cell.chars().count());
+                    if numeric[i] && !header {
+                        format!("{}{}", " ".repeat(pad), cell)
+                    } else {
+                        format!("{}{}", cell, " ".repeat(pad))
+                    }
+                })
+                .collect();
+            self.out.push(line.join("  ").trim_end().to_string());
+
+            let next_is_body = grid.get(index + 1).map(|(h, _)| !h).unwrap_or(false);
+            if *header && next_is_body {
+                let rule: Vec<String> = visible.iter().map(|&i| "-".repeat(widths[i])).collect();
+                self.out.push(rule.join("  "));
+            }
+        }
+        self.out.push(String::new());
+    }
+}
+
+/// Rows of a table (excluding rows of nested tables), flagged when inside `<thead>`
+fn table_rows(table: &Handle) -> Vec<(Handle, bool)> {
+    let mut rows = Vec::new();
+    for child in table.children.borrow().iter() {
+        match element_name(child) {
+            Some("tr") => rows.push((child.clone(), false)),
+            Some(section @ ("thead" | "tbody" | "tfoot")) => {
+                let in_thead = section == "thead";
+                rows.extend(table_rows(child).into_iter().map(|(row, _)| (row, in_thead)));
+            }
+            _ => {}
+        }
+    }
+    rows
+}
+
+fn cells(row: &Handle) -> Vec<Handle> {
+    row.children.borrow().iter()
+        .filter(|c| matches!(element_name(c), Some("td") | Some("th")))
+        .cloned()
+        .collect()
+}
+
+/// Amounts such as "1.500.000", "$ 99,50" or "COP 2.500" are right-aligned
+fn looks_numeric(cell: &str) -> bool {
+    let is_code = |w: &str| w.len() == 3 && w.chars().all(|c| c.is_ascii_uppercase());
+    let words: Vec<&str> = cell.split(' ').filter(|w| !is_code(w)).collect();
+    let rest = words.join(" ");
+    rest.chars().any(|c| c.is_ascii_digit())
+        && rest.chars().all(|c| c.is_ascii_digit() || " .,$€£%-+()".contains(c))
+}
+
+fn normalize_space(text: &str) -> String {
+    text.replace('\u{a0}', " ").split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    let mut cut: String = text.chars().take(max - 1).collect();
+    cut.push('…');
+    cut
+}
+
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in text.split(' ') {
+        if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > width {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+fn collapse_blank_lines(text: &str) -> String {
+    let mut result: Vec<&str> = Vec::new();
+    for line in text.lines() {
+        if line.trim().is_empty() && result.last().map(|l| l.trim().is_empty()).unwrap_or(true) {
+            continue;
+        }
+        result.push(line);
+    }
+    while result.last().map(|l| l.trim().is_empty()).unwrap_or(false) {
+        result.pop();
+    }
+    result.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paragraphs_headings_and_links() {
+        let html = r#"<html><head><style>p { color: red; }</style></head><body>
+            <h1>Estado de cuenta</h1>
+            <p>Hola <b>ACME</b>,&nbsp;puede pagar <a href="https://pay.example.com/x">aquí</a>.</p>
+            <p>Escríbanos a <a href="mailto:cartera@example.com">cartera@example.com</a></p>
+        </body></html>"#;
+
+        assert_eq!(
+            html_to_text(html),
+            "Estado de cuenta\n================\n\nHola ACME, puede pagar aquí [1].\n\nEscríbanos a cartera@example.com\n\n[1] https://pay.example.com/x"
+        );
+    }
+
+    #[test]
+    fn test_invoice_table_is_aligned() {
+        let html = r#"<table class="tiptap-table"><tbody>
+            <tr><th><p>Factura</p></th><th><p>Vence</p></th><th><p>Valor</p></th></tr>
+            <tr><td><p>F-1</p></td><td><p>31-01-2026</p></td><td><p>1.500.000</p></td></tr>
+            <tr><td><p>F-20</p></td><td><p>15-02-2026</p></td><td><p>2.500</p></td></tr>
+        </tbody></table>"#;
+
+        assert_eq!(
+            html_to_text(html),
+            "Factura  Vence       Valor\n-------  ----------  ---------\nF-1      31-01-2026  1.500.000\nF-20     15-02-2026      2.500"
+        );
+    }
+
+    #[test]
+    fn test_empty_padding_columns_are_dropped() {
+        // TipTap pads rows with empty cells up to the widest row
+        let html = r#"<table><tbody>
+            <tr><th><p>Factura</p></th><th><p></p></th><th><p>Valor</p></th></tr>
+            <tr><td><p>F-1</p></td><td><p></p></td><td><p>2.500</p></td></tr>
+        </tbody></table>"#;
+
+        assert_eq!(html_to_text(html), "Factura  Valor\n-------  -----\nF-1      2.500");
+    }
+
+    #[test]
+    fn test_blockquote_has_no_trailing_marker() {
+        let html = "<blockquote><p>Recuerde pagar antes del 31</p></blockquote><p>Gracias</p>";
+        assert_eq!(html_to_text(html), "> Recuerde pagar antes del 31\n\nGracias");
+    }
+
+    #[test]
+    fn test_layout_tables_are_flattened() {
+        let html = r#"<table role="presentation"><tr><td><p>Contenido</p></td><td><p>Lateral</p></td></tr></table>
+            <table><tr><td>Solo una columna</td></tr></table>"#;
+
+        assert_eq!(html_to_text(html), "Contenido\n\nLateral\n\nSolo una columna");
+    }
+
+    #[test]
+    fn test_lists_and_wrapping() {
+        let html = r#"<ul><li>Primera</li><li>Segunda<ol><li>Uno</li><li>Dos</li></ol></li></ul>
+            <p>Lorem ipsum dolor sit amet consectetur adipiscing elit sed do eiusmod tempor incididunt ut labore et dolore</p>"#;
+
+        let text = html_to_text(html);
+        assert!(text.starts_with("- Primera\n- Segunda\n  1. Uno\n  2. Dos\n\n"), "{}", text);
+        assert!(text.lines().all(|l| l.chars().count() <= WRAP_WIDTH), "{}", text);
+    }
+
+    #[test]
+    fn test_subject_line() {
+        assert_eq!(subject_line("Recordatorio:\n  <b>ACME</b> &amp; Cía\r\n"), "Recordatorio: ACME & Cía");
+        assert_eq!(subject_line("<p>Hola</p><p>ACME</p>"), "Hola ACME");
+        assert_eq!(subject_line("Sin cambios"), "Sin cambios");
+
+        let long = "palabra ".repeat(40);
+        let line = subject_line(&long);
+        assert!(line.chars().count() <= MAX_SUBJECT_CHARS);
+        assert!(line.ends_with("palabra…"), "{}", line);
+    }
+
+    #[test]
+    fn test_repeated_links_share_a_footnote() {
+        let html = r#"<p><a href="https://a.example">Pagar</a> o <a href="https://a.example">pagar ahora</a></p>"#;
+        assert_eq!(html_to_text(html), "Pagar [1] o pagar ahora [1]\n\n[1] https://a.example");
+    }
+}
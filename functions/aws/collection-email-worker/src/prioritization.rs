@@ -0,0 +1,289 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use log::info;
+
+use crate::models::{CollectionClient, ExecutionBatch};
+use crate::supabase::SupabaseService;
+
+const CLIENT_FETCH_CHUNK: usize = 200;
+
+/// How clients are ordered within an execution (`collection_executions.priority_strategy`).
+/// The most important debtors go first, so if quotas or pauses cut an execution
+/// short, their reminders have already been sent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PriorityStrategy {
+    /// Keep the order the batches were created with
+    None,
+    /// Largest `total_amount_due` first
+    AmountDue,
+    /// Most days overdue first
+    DaysOverdue,
+    /// Most severe notification threshold (highest `days_from`) first
+    Threshold,
+}
+
+impl PriorityStrategy {
+    pub fn parse(value: Option<&str>) -> Self {
+        match value.map(|v| v.trim().to_lowercase()).as_deref() {
+            Some("amount_due") => PriorityStrategy::AmountDue,
+            Some("days_overdue") => PriorityStrategy::DaysOverdue,
+            Some("threshold") => PriorityStrategy::Threshold,
+            _ => PriorityStrategy::None,
+        }
+    }
+}
+
+fn custom_f64(client: &CollectionClient, key: &str) -> f64 {
+    client.custom_data
+        .as_ref()
+        .and_then(|cd| cd.get(key))
+        .and_then(crate::helpers::parse_number)
+        .unwrap_or(0.0)
+}
+
+/// Sort key for a client; higher means more important.
+/// `threshold_days` maps threshold_id -> days_from.
+fn priority_key(
+    client: &CollectionClient,
+    strategy: PriorityStrategy,
+    threshold_days: &HashMap<String, i64>,
+) -> (f64, f64) {
+    let amount = custom_f64(client, "total_amount_due");
+    match strategy {
+        PriorityStrategy::None => (0.0, 0.0),
+        PriorityStrategy::AmountDue => (amount, 0.0),
+        PriorityStrategy::DaysOverdue => (custom_f64(client, "total_days_overdue"), amount),
+        PriorityStrategy::Threshold => {
+            let days_from = client.threshold_id
+                .as_ref()
+                .and_then(|id| threshold_days.get(id))
+                .copied()
+                .unwrap_or(-1);
+            (days_from as f64, amount)
+        }
+    }
+}
+
+/// Orders clients most important first. The sort is stable and ties keep
+/// their current order, so `PriorityStrategy::None` is a no-op.
+pub fn sort_clients(
+    clients: &mut [CollectionClient],
+    strategy: PriorityStrategy,
+    threshold_days: &HashMap<String, i64>,
+) {
+    if strategy == PriorityStrategy::None {
+        return;
+    }
+
+    clients.sort_by(|a, b| {
+        let ka = priority_key(a, strategy, threshold_days);
+        let kb = priority_key(b, strategy, threshold_days);
+        kb.partial_cmp(&ka).unwrap_or(Ordering::Equal)
+    });
+}
+
+/// Redistributes already-ordered client ids over the batches, earliest batch first,
+/// keeping every batch at its current size. Returns (batch_id, client_ids) for the
+/// batches whose contents changed.
+pub fn rebalance_batches(
+    batches: &[ExecutionBatch],
+    ordered_client_ids: &[String],
+) -> Vec<(String, Vec<String>)> {
+    let mut batches: Vec<&ExecutionBatch> = batches.iter().collect();
+    batches.sort_by_key(|b| b.batch_number);
+
+    let mut remaining = ordered_client_ids.iter();
+    let mut changes = Vec::new();
+
+    for batch in batches {
+        let assigned: Vec<String> = remaining
+            .by_ref()
+            .take(batch.client_ids.len())
+            .cloned()
+            .collect();
+
+        if assigned != batch.client_ids {
+            changes.push((batch.id.clone(), assigned));
+        }
+    }
+
+    changes
+}
+
+/// Loads days_from for the thresholds referenced by `clients` (only needed for
+/// `PriorityStrategy::Threshold`)
+pub async fn load_threshold_days(
+    supabase: &SupabaseService,
+    strategy: PriorityStrategy,
+    clients: &[CollectionClient],
+) -> Result<HashMap<String, i64>, Box<dyn Error + Send + Sync>> {
+    if strategy != PriorityStrategy::Threshold {
+        return Ok(HashMap::new());
+    }
+
+    let mut ids: Vec<String> = clients.iter().filter_map(|c| c.threshold_id.clone()).collect();
+    ids.sort();
+    ids.dedup();
+    supabase.get_threshold_days(&ids).await
+}
+
+/// Reorders the clients of all pending batches of an execution so the most important
+/// debtors land in the earliest batches. Must run before the first batch is claimed;
+/// batch sizes and schedules are left untouched. Returns the number of batches rewritten.
+pub async fn prioritize_pending_batches(
+    supabase: &SupabaseService,
+    execution_id: &str,
+    strategy: PriorityStrategy,
+    batches: &[ExecutionBatch],
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    if strategy == PriorityStrategy::None || batches.len() < 2 {
+        return Ok(0);
+    }
+
+    let mut ordered_batches: Vec<&ExecutionBatch> = batches.iter().collect();
+    ordered_batches.sort_by_key(|b| b.batch_number);
+    let original_ids: Vec<String> = ordered_batches.iter()
+        .flat_map(|b| b.client_ids.iter().cloned())
+        .collect();
+
+    // Chunked to keep the PostgREST `in.(...)` filter within URL limits
+    let mut clients = Vec::with_capacity(original_ids.len());
+    for chunk in original_ids.chunks(CLIENT_FETCH_CHUNK) {
+        clients.extend(supabase.get_clients_by_ids(chunk).await?);
+    }
+
+    // Keep the creation order as the tie-breaker
+    let position: HashMap<&str, usize> = original_ids.iter().enumerate().map(|(i, id)| (id.as_str(), i)).collect();
+    clients.sort_by_key(|c| position.get(c.id.as_str()).copied().unwrap_or(usize::MAX));
+
+    let threshold_days = load_threshold_days(supabase, strategy, &clients).await?;
+    sort_clients(&mut clients, strategy, &threshold_days);
+
+    let mut ordered_ids: Vec<String> = clients.iter().map(|c| c.id.clone()).collect();
+    // Ids whose client row is missing keep their slot at the end
+    let found: HashSet<&str> = clients.iter().map(|c| c.id.as_str()).collect();
+    ordered_ids.extend(original_ids.iter().filter(|id| !found.contains(id.as_str())).cloned());
+
+    let changes = rebalance_batches(batches, &ordered_ids);
+    for (batch_id, client_ids) in &changes {
+        supabase.update_batch_client_ids(batch_id, client_ids).await?;
+    }
+
+    info!("[prioritization] Execution {} reordered with {:?}: {} batches rewritten", execution_id, strategy, changes.len());
+    Ok(changes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn client(id: &str, amount: f64, days: i64, threshold: Option<&str>) -> CollectionClient {
+        CollectionClient {
+            id: id.to_string(),
+            execution_id: "exec-1".to_string(),
+            status: "pending".to_string(),
+            invoices: None,
+            custom_data: Some(json!({
+                "total_amount_due": amount,
+                "total_days_overdue": days
+            })),
+            email_template_id: None,
+            threshold_id: threshold.map(|t| t.to_string()),
+        }
+    }
+
+    fn batch(id: &str, number: i32, client_ids: &[&str]) -> ExecutionBatch {
+        ExecutionBatch {
+            id: id.to_string(),
+            execution_id: "exec-1".to_string(),
+            batch_number: number,
+            client_ids: client_ids.iter().map(|s| s.to_string()).collect(),
+            total_clients: client_ids.len() as i32,
+            scheduled_for: None,
+            timezone: None,
+            status: "pending".to_string(),
+        }
+    }
+
+    fn ids(clients: &[CollectionClient]) -> Vec<&str> {
+        clients.iter().map(|c| c.id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_parse_strategy() {
+        assert_eq!(PriorityStrategy::parse(Some("amount_due")), PriorityStrategy::AmountDue);
+        assert_eq!(PriorityStrategy::parse(Some("DAYS_OVERDUE")), PriorityStrategy::DaysOverdue);
+        assert_eq!(PriorityStrategy::parse(Some("threshold")), PriorityStrategy::Threshold);
+        assert_eq!(PriorityStrategy::parse(Some("bogus")), PriorityStrategy::None);
+        assert_eq!(PriorityStrategy::parse(None), PriorityStrategy::None);
+    }
+
+    #[test]
+    fn test_sort_by_amount_due() {
+        let mut clients = vec![
+            client("a", 100.0, 90, None),
+            client("b", 5_000.0, 10, None),
+            client("c", 750.0, 30, None),
+        ];
+        sort_clients(&mut clients, PriorityStrategy::AmountDue, &HashMap::new());
+        assert_eq!(ids(&clients), vec!["b", "c", "a"]);
+    }
+
+    #[test]
+    fn test_sort_by_days_overdue_breaks_ties_by_amount() {
+        let mut clients = vec![
+            client("a", 100.0, 30, None),
+            client("b", 900.0, 30, None),
+            client("c", 50.0, 120, None),
+        ];
+        sort_clients(&mut clients, PriorityStrategy::DaysOverdue, &HashMap::new());
+        assert_eq!(ids(&clients), vec!["c", "b", "a"]);
+    }
+
+    #[test]
+    fn test_sort_by_threshold() {
+        let thresholds = HashMap::from([
+            ("t-early".to_string(), 0i64),
+            ("t-legal".to_string(), 90i64),
+        ]);
+        let mut clients = vec![
+            client("a", 100.0, 5, Some("t-early")),
+            client("b", 10.0, 95, Some("t-legal")),
+            client("c", 999.0, 0, None),
+        ];
+        sort_clients(&mut clients, PriorityStrategy::Threshold, &thresholds);
+        assert_eq!(ids(&clients), vec!["b", "a", "c"]);
+    }
+
+    #[test]
+    fn test_none_keeps_order() {
+        let mut clients = vec![client("a", 1.0, 1, None), client("b", 9.0, 9, None)];
+        sort_clients(&mut clients, PriorityStrategy::None, &HashMap::new());
+        assert_eq!(ids(&clients), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_rebalance_keeps_batch_sizes() {
+        let batches = vec![
+            batch("b2", 2, &["c3", "c4"]),
+            batch("b1", 1, &["c1", "c2", "c5"]),
+        ];
+        let ordered: Vec<String> = ["c5", "c4", "c3", "c2", "c1"].iter().map(|s| s.to_string()).collect();
+
+        let changes = rebalance_batches(&batches, &ordered);
+
+        assert_eq!(changes, vec![
+            ("b1".to_string(), vec!["c5".to_string(), "c4".to_string(), "c3".to_string()]),
+            ("b2".to_string(), vec!["c2".to_string(), "c1".to_string()]),
+        ]);
+    }
+
+    #[test]
+    fn test_rebalance_skips_unchanged_batches() {
+        let batches = vec![batch("b1", 1, &["c1"]), batch("b2", 2, &["c2"])];
+        let ordered = vec!["c1".to_string(), "c2".to_string()];
+        assert!(rebalance_batches(&batches, &ordered).is_empty());
+    }
+}
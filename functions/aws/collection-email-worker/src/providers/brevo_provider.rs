@@ -1,6 +1,7 @@
 use async_trait::async_trait;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::error::Error;
 use log::{info, error};
 
@@ -11,7 +12,10 @@ use crate::email_provider::{EmailProvider, EmailMessage, SendResult};
 pub struct BrevoProvider {
     client: Client,
     api_url: String,
+    events_api_url: String,
     api_key: String,
+    /// BREVO_INLINE_IMAGES=true; see `supports_inline_images`
+    inline_images: bool,
 }
 
 #[derive(Serialize, Debug)]
@@ -42,6 +46,10 @@ struct BrevoEmailRequest {
     text_content: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     attachment: Option<Vec<BrevoAttachment>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    headers: Option<HashMap<String, String>>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -50,17 +58,36 @@ struct BrevoEmailResponse {
     message_id: String,
 }
 
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct BrevoEvent {
+    message_id: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BrevoEventsResponse {
+    #[serde(default)]
+    events: Vec<BrevoEvent>,
+}
+
 impl BrevoProvider {
     pub fn new() -> Self {
         let api_url = std::env::var("BREVO_SMTP_API_URL")
             .unwrap_or_else(|_| "https://api.brevo.com/v3/smtp/email".to_string());
+        let events_api_url = std::env::var("BREVO_EVENTS_API_URL")
+            .unwrap_or_else(|_| "https://api.brevo.com/v3/smtp/statistics/events".to_string());
         let api_key = std::env::var("BREVO_API_KEY")
             .expect("BREVO_API_KEY must be set when using Brevo provider");
+        let inline_images = std::env::var("BREVO_INLINE_IMAGES")
+            .map(|v| v.trim().eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
         
         Self {
             client: Client::new(),
             api_url,
+            events_api_url,
             api_key,
+            inline_images,
         }
     }
 
@@ -87,7 +114,17 @@ impl EmailProvider for BrevoProvider {
     async fn send_email(&self, message: EmailMessage) -> Result<SendResult, Box<dyn Error + Send + Sync>> {
         info!("Sending email via Brevo to: {:?}", message.to);
 
-        let attachments = if !message.attachments.is_empty() {
+        // Brevo's attachment objects only take a name and the content; there is no
+        // Content-ID or disposition field, and Brevo does not document how the parts are
+        // built. Inline images therefore go as attachments named after their content id,
+        // which is how the HTML references them (`cid:payment-qr.png`), and only when the
+        // operator has checked that their account shows them inline (BREVO_INLINE_IMAGES).
+        // The worker keeps their total under Brevo's tighter limits (`EmbedLimits::for_provider`).
+        let inline_images = message.inline_images.iter().map(|image| BrevoAttachment {
+            content: base64::Engine::encode(&base64::engine::general_purpose::STANDARD, &image.data),
+            name: image.content_id.clone(),
+        });
+        let attachments = if !message.attachments.is_empty() || !message.inline_images.is_empty() {
             let brevo_attachments: Vec<BrevoAttachment> = message.attachments
                 .iter()
                 .map(|att| {
@@ -97,6 +134,7 @@ impl EmailProvider for BrevoProvider {
                         name: att.name.clone(),
                     }
                 })
+                .chain(inline_images)
                 .collect();
             Some(brevo_attachments)
         } else {
@@ -117,6 +155,8 @@ impl EmailProvider for BrevoProvider {
             html_content: message.html_body.clone(),
             text_content: Some(message.text_body.clone()),
             attachment: attachments,
+            tags: message.idempotency_key.clone().map(|key| vec![key]),
+            headers: message.message_id.clone().map(|id| HashMap::from([("Message-Id".to_string(), id)])),
         };
 
         info!("Brevo API request prepared, sending to: {}", self.api_url);
@@ -152,4 +192,34 @@ impl EmailProvider for BrevoProvider {
     fn provider_name(&self) -> &str {
         "Brevo"
     }
+
+    /// Consulta los eventos transaccionales de Brevo filtrando por el tag de idempotencia
+    async fn find_sent_message(&self, idempotency_key: &str) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        let response = self.client
+            .get(&self.events_api_url)
+            .header("api-key", &self.api_key)
+            .query(&[("tags", idempotency_key), ("event", "requests"), ("limit", "1")])
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+            return Err(format!("Brevo events API error: {} - {}", status, error_text).into());
+        }
+
+        let events: BrevoEventsResponse = response.json().await?;
+        Ok(events.events.into_iter().find_map(|e| e.message_id))
+    }
+
+    /// Los eventos "requests" registran cada envío aceptado y se consultan por tag
+    fn confirms_every_send(&self) -> bool {
+        true
+    }
+
+    /// Brevo no tiene un mecanismo documentado para imágenes inline (ver `send_email`);
+    /// solo se usan si BREVO_INLINE_IMAGES=true
+    fn supports_inline_images(&self) -> bool {
+        self.inline_images
+    }
 }
@@ -1,9 +1,10 @@
 use async_trait::async_trait;
 use aws_sdk_ses::Client;
-use aws_sdk_ses::types::RawMessage;
+use aws_sdk_ses::types::{MessageTag, RawMessage};
 use aws_sdk_ses::primitives::Blob;
 use std::error::Error;
 use mail_builder::MessageBuilder;
+use mail_builder::mime::MimePart;
 use log::info;
 
 use crate::email_provider::{EmailProvider, EmailMessage, SendResult};
@@ -13,6 +14,7 @@ use crate::email_provider::{EmailProvider, EmailMessage, SendResult};
 pub struct SesProvider {
     client: Client,
     configuration_set: String,
+    #[allow(dead_code)]
     tracking_url: String,
 }
 
@@ -52,6 +54,34 @@ impl SesProvider {
     }
 }
 
+/// MIME body of a message with inline images, which mail_builder's simple bodies can't
+/// express: the HTML and the images it references go together in a multipart/related
+/// part, the alternative to the plain text, and attachments wrap both in multipart/mixed.
+fn body_with_inline_images(message: &EmailMessage, html: String) -> MimePart<'_> {
+    let mut related = vec![MimePart::new("text/html", html)];
+    related.extend(message.inline_images.iter().map(|image| {
+        MimePart::new(image.content_type.as_str(), image.data.as_slice())
+            .inline()
+            .cid(image.content_id.as_str())
+    }));
+    let alternative = MimePart::new("multipart/alternative", vec![
+        MimePart::new("text/plain", message.text_body.as_str()),
+        MimePart::new("multipart/related", related),
+    ]);
+    if message.attachments.is_empty() {
+        return alternative;
+    }
+
+    let mut mixed = vec![alternative];
+    mixed.extend(message.attachments.iter().map(|attachment| {
+        MimePart::new(
+            attachment.file_type.as_deref().unwrap_or("application/octet-stream"),
+            attachment.data.as_slice(),
+        ).attachment(attachment.name.as_str())
+    }));
+    MimePart::new("multipart/mixed", mixed)
+}
+
 #[async_trait]
 impl EmailProvider for SesProvider {
     async fn send_email(&self, message: EmailMessage) -> Result<SendResult, Box<dyn Error + Send + Sync>> {
@@ -64,30 +94,43 @@ impl EmailProvider for SesProvider {
 
         let mut builder = MessageBuilder::new()
             .from(message.from.as_str())
-            .subject(message.subject.as_str())
-            .text_body(message.text_body.as_str())
-            .html_body(html_with_pixel);
+            .subject(message.subject.as_str());
 
         for recipient in &message.to {
             builder = builder.to(recipient.as_str());
         }
 
-        if !message.attachments.is_empty() {
-            info!("Adding {} attachments to email", message.attachments.len());
-            for attachment in &message.attachments {
-                info!("Adding attachment: {} ({} bytes, type: {:?})",
-                    attachment.name,
-                    attachment.data.len(),
-                    attachment.file_type
-                );
-                builder = builder.attachment(
-                    attachment.file_type.as_deref().unwrap_or("application/octet-stream"),
-                    &attachment.name,
-                    attachment.data.clone()
-                );
-            }
+        // Worker-generated Message-ID (mail_builder adds the angle brackets)
+        if let Some(message_id) = &message.message_id {
+            builder = builder.message_id(message_id.trim_start_matches('<').trim_end_matches('>'));
+        }
+
+        // Inline images need a custom MIME body, which then carries the attachments too
+        if !message.inline_images.is_empty() {
+            info!("Embedding {} inline images and {} attachments", message.inline_images.len(), message.attachments.len());
+            builder = builder.body(body_with_inline_images(&message, html_with_pixel));
         } else {
-            info!("No attachments to add to email");
+            builder = builder
+                .text_body(message.text_body.as_str())
+                .html_body(html_with_pixel);
+
+            if !message.attachments.is_empty() {
+                info!("Adding {} attachments to email", message.attachments.len());
+                for attachment in &message.attachments {
+                    info!("Adding attachment: {} ({} bytes, type: {:?})",
+                        attachment.name,
+                        attachment.data.len(),
+                        attachment.file_type
+                    );
+                    builder = builder.attachment(
+                        attachment.file_type.as_deref().unwrap_or("application/octet-stream"),
+                        &attachment.name,
+                        attachment.data.clone()
+                    );
+                }
+            } else {
+                info!("No attachments to add to email");
+            }
         }
 
         let raw_email = builder.write_to_vec()?;
@@ -106,6 +149,16 @@ impl EmailProvider for SesProvider {
             send_request = send_request.configuration_set_name(&self.configuration_set);
         }
 
+        // The tag travels with every SES event, letting the event handler resolve outbox entries
+        if let Some(key) = &message.idempotency_key {
+            send_request = send_request.tags(
+                MessageTag::builder()
+                    .name("idempotency_key")
+                    .value(key)
+                    .build()?
+            );
+        }
+
         let output = send_request.send().await?;
 
         let message_id = output.message_id;
@@ -121,4 +174,88 @@ impl EmailProvider for SesProvider {
     fn provider_name(&self) -> &str {
         "AWS SES"
     }
+
+    /// SES no permite consultar envíos; solo hay evidencia si el configuration set
+    /// publica los eventos Send hacia el event handler
+    fn confirms_every_send(&self) -> bool {
+        !self.configuration_set.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email_provider::InlineImage;
+    use crate::models::Attachment;
+
+    fn message(attachments: Vec<Attachment>) -> EmailMessage {
+        EmailMessage {
+            to: vec!["cliente@example.com".to_string()],
+            subject: "Estado de cuenta".to_string(),
+            html_body: String::new(),
+            text_body: "Pague con el QR".to_string(),
+            from: "Cobros <cobros@example.com>".to_string(),
+            attachments,
+            inline_images: vec![InlineImage {
+                content_id: "payment-qr.png".to_string(),
+                content_type: "image/png".to_string(),
+                data: b"\x89PNG-data".to_vec(),
+            }],
+            client_id: None,
+            execution_id: None,
+            message_id: None,
+            idempotency_key: None,
+        }
+    }
+
+    fn raw(message: &EmailMessage) -> String {
+        MessageBuilder::new()
+            .from(message.from.as_str())
+            .to(message.to[0].as_str())
+            .subject(message.subject.as_str())
+            .body(body_with_inline_images(message, r#"<img src="cid:payment-qr.png">"#.to_string()))
+            .write_to_string()
+            .unwrap()
+    }
+
+    fn position(raw: &str, needle: &str) -> usize {
+        raw.find(needle).unwrap_or_else(|| panic!("{} not found in:\n{}", needle, raw))
+    }
+
+    #[test]
+    fn test_inline_images_go_in_a_related_part_next_to_the_html() {
+        let raw = raw(&message(vec![]));
+        assert!(!raw.contains("multipart/mixed"));
+
+        let alternative = position(&raw, "Content-Type: multipart/alternative");
+        let text = position(&raw, "Content-Type: text/plain");
+        let related = position(&raw, "Content-Type: multipart/related");
+        let html = position(&raw, "Content-Type: text/html");
+        let image = position(&raw, "Content-Type: image/png");
+        assert!(alternative < text && text < related && related < html && html < image, "{}", raw);
+
+        let image_headers = &raw[image..];
+        assert!(image_headers.contains("Content-ID: <payment-qr.png>"), "{}", raw);
+        assert!(image_headers.contains("Content-Disposition: inline"), "{}", raw);
+    }
+
+    #[test]
+    fn test_attachments_wrap_the_body_in_a_mixed_part() {
+        let attachment = Attachment {
+            id: "a1".to_string(),
+            name: "factura.pdf".to_string(),
+            storage_path: "facturas/factura.pdf".to_string(),
+            storage_bucket: "attachments".to_string(),
+            file_type: Some("application/pdf".to_string()),
+            data: b"%PDF-1.4".to_vec(),
+        };
+        let raw = raw(&message(vec![attachment]));
+
+        let mixed = position(&raw, "Content-Type: multipart/mixed");
+        let alternative = position(&raw, "Content-Type: multipart/alternative");
+        let image = position(&raw, "Content-ID: <payment-qr.png>");
+        let pdf = position(&raw, "Content-Type: application/pdf");
+        assert!(mixed < alternative && alternative < image && image < pdf, "{}", raw);
+        assert!(raw[pdf..].contains("Content-Disposition: attachment; filename=\"factura.pdf\""), "{}", raw);
+    }
 }
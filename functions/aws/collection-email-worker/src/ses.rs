@@ -5,11 +5,13 @@ use std::error::Error;
 use crate::models::Attachment;
 use mail_builder::MessageBuilder;
 
+#[allow(dead_code)]
 pub struct SesService {
     client: Client,
     configuration_set: String,
 }
 
+#[allow(dead_code)]
 impl SesService {
     pub async fn new() -> Self {
         let config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
@@ -19,6 +21,7 @@ impl SesService {
         Self { client, configuration_set }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn send_email(
         &self,
         to: &str,
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use regex::{Captures, Regex};
+use sha2::{Digest, Sha256};
+
+use crate::email_provider::InlineImage;
+use crate::supabase::SupabaseService;
+
+/// `src` attribute of every `<img>` tag, with whatever precedes it inside the tag
+static IMG_SRC: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?i)(<img\b[^>]*?\ssrc\s*=\s*)(?:"([^"]*)"|'([^']*)')"#).unwrap()
+});
+
+/// How much image data one email may carry inline. Larger images, and those that would
+/// push the email over its total, keep their remote URL.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EmbedLimits {
+    pub max_image_bytes: usize,
+    pub max_total_bytes: usize,
+}
+
+impl EmbedLimits {
+    /// Brevo sends inline images as base64 attachments inside a JSON request, so it gets
+    /// tighter caps than the raw MIME message of SES
+    pub fn for_provider(provider_name: &str) -> Self {
+        if provider_name.eq_ignore_ascii_case("brevo") {
+            Self { max_image_bytes: 512 * 1024, max_total_bytes: 2 * 1024 * 1024 }
+        } else {
+            Self::default()
+        }
+    }
+}
+
+impl Default for EmbedLimits {
+    fn default() -> Self {
+        Self { max_image_bytes: 1024 * 1024, max_total_bytes: 5 * 1024 * 1024 }
+    }
+}
+
+/// Replaces `<img>` sources on the project's public Supabase storage with `cid:`
+/// references to inline copies, so clients that block remote images still show logos
+/// and pictures. Each image is downloaded once per batch.
+pub struct StorageImages {
+    /// `<SUPABASE_URL>/storage/v1/object/public/`
+    prefix: String,
+    limits: EmbedLimits,
+    /// Downloads by URL; None when the download failed or the image is over the limit
+    cache: HashMap<String, Option<InlineImage>>,
+}
+
+fn unescape_attribute(value: &str) -> String {
+    value.replace("&amp;", "&")
+}
+
+/// Stable content id of a URL, keeping its file extension: `img-1a2b3c4d5e6f7a8b.png`
+fn content_id(url: &str) -> String {
+    let hash = format!("{:x}", Sha256::digest(url.as_bytes()));
+    let extension = url.split(['?', '#']).next().unwrap_or_default()
+        .rsplit_once('/').map(|(_, name)| name).unwrap_or_default()
+        .rsplit_once('.').map(|(_, ext)| ext.to_ascii_lowercase())
+        .filter(|ext| !ext.is_empty() && ext.len() <= 4 && ext.chars().all(|c| c.is_ascii_alphanumeric()));
+    match extension {
+        Some(ext) => format!("img-{}.{}", &hash[..16], ext),
+        None => format!("img-{}", &hash[..16]),
+    }
+}
+
+/// SVG can carry scripts and most email clients don't show it inline
+const SVG: &str = "image/svg+xml";
+
+/// Image MIME type from the response header, or from the file extension when storage
+/// answers with a generic type. None for SVG images, which keep their remote URL.
+fn image_type(url: &str, header: Option<&str>) -> Option<String> {
+    let header = header.map(|h| h.split(';').next().unwrap_or_default().trim().to_ascii_lowercase());
+    if let Some(mime) = header.filter(|h| h.starts_with("image/")) {
+        return (mime != SVG).then_some(mime);
+    }
+    let path = url.split(['?', '#']).next().unwrap_or_default().to_ascii_lowercase();
+    let mime = match path.rsplit_once('.').map(|(_, ext)| ext) {
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        _ => return None,
+    };
+    Some(mime.to_string())
+}
+
+impl StorageImages {
+    pub fn new(supabase_url: &str, limits: EmbedLimits) -> Self {
+        Self {
+            prefix: format!("{}/storage/v1/object/public/", supabase_url.trim_end_matches('/')),
+            limits,
+            cache: HashMap::new(),
+        }
+    }
+
+    /// Storage URLs the HTML shows in `<img>` tags, in order and without repeats
+    fn sources(&self, html: &str) -> Vec<String> {
+        let mut urls: Vec<String> = Vec::new();
+        for caps in IMG_SRC.captures_iter(html) {
+            let url = unescape_attribute(caps.get(2).or(caps.get(3)).map(|m| m.as_str()).unwrap_or_default().trim());
+            if url.starts_with(&self.prefix) && !urls.contains(&url) {
+                urls.push(url);
+            }
+        }
+        urls
+    }
+
+    /// Stores a downloaded image unless it is over the per-image limit or not an image
+    fn insert(&mut self, url: String, data: Vec<u8>, content_type: Option<&str>) {
+        let image = match image_type(&url, content_type) {
+            Some(_) if data.len() > self.limits.max_image_bytes => {
+                log::warn!("[storage_images] {} is {} bytes, over the {} byte limit; keeping the remote URL", url, data.len(), self.limits.max_image_bytes);
+                None
+            }
+            Some(content_type) => Some(InlineImage { content_id: content_id(&url), content_type, data }),
+            None => {
+                log::warn!("[storage_images] {} is not an image; keeping the remote URL", url);
+                None
+            }
+        };
+        self.cache.insert(url, image);
+    }
+
+    /// Points the cached images of `html` at `cid:` parts and returns them. `used_bytes`
+    /// is the inline data the email already carries (the payment QR); images that would
+    /// exceed the total stay remote.
+    fn rewrite(&self, html: &str, mut used_bytes: usize) -> (String, Vec<InlineImage>) {
+        let mut images: Vec<InlineImage> = Vec::new();
+        let mut content_ids: HashMap<String, String> = HashMap::new();
+        for url in self.sources(html) {
+            let Some(Some(image)) = self.cache.get(&url) else {
+                continue;
+            };
+            if used_bytes + image.data.len() > self.limits.max_total_bytes {
+                log::warn!("[storage_images] Inline images would exceed {} bytes; keeping {} remote", self.limits.max_total_bytes, url);
+                continue;
+            }
+            used_bytes += image.data.len();
+            content_ids.insert(url, image.content_id.clone());
+            images.push(image.clone());
+        }
+
+        let html = IMG_SRC.replace_all(html, |caps: &Captures| {
+            let url = unescape_attribute(caps.get(2).or(caps.get(3)).map(|m| m.as_str()).unwrap_or_default().trim());
+            match content_ids.get(&url) {
+                Some(content_id) => format!("{}\"cid:{}\"", &caps[1], content_id),
+                None => caps[0].to_string(),
+            }
+        }).into_owned();
+        (html, images)
+    }
+
+    /// Downloads the storage images of `html` not seen earlier in the batch, then
+    /// rewrites their sources. Returns the new HTML and the images to attach.
+    pub async fn embed(&mut self, supabase: &SupabaseService, html: &str, used_bytes: usize) -> (String, Vec<InlineImage>) {
+        for url in self.sources(html) {
+            if self.cache.contains_key(&url) {
+                continue;
+            }
+            match supabase.download_public_object(&url, self.limits.max_image_bytes).await {
+                Ok((data, content_type)) => self.insert(url, data, content_type.as_deref()),
+                Err(e) => {
+                    log::warn!("[storage_images] Failed to download {}: {}; keeping the remote URL", url, e);
+                    self.cache.insert(url, None);
+                }
+            }
+        }
+        self.rewrite(html, used_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STORAGE: &str = "https://proj.supabase.co/storage/v1/object/public/business-media";
+
+    fn images(limits: EmbedLimits) -> StorageImages {
+        let mut images = StorageImages::new("https://proj.supabase.co/", limits);
+        images.insert(format!("{}/logo.PNG", STORAGE), vec![1; 10], Some("image/png"));
+        images.insert(format!("{}/banner.jpg?v=2&w=600", STORAGE), vec![2; 20], Some("application/octet-stream"));
+        images.insert(format!("{}/terms.pdf", STORAGE), vec![3; 5], Some("application/pdf"));
+        images.insert(format!("{}/icon.svg", STORAGE), vec![4; 5], Some("application/octet-stream"));
+        images.insert(format!("{}/badge.png", STORAGE), vec![5; 5], Some("image/svg+xml; charset=utf-8"));
+        images
+    }
+
+    #[test]
+    fn test_rewrites_storage_images_once() {
+        let images = images(EmbedLimits::default());
+        let html = format!(
+            r#"<img alt="Logo" src="{s}/logo.PNG"><p><img src='{s}/banner.jpg?v=2&amp;w=600' width="600"></p><img src="{s}/logo.PNG"><img src="https://cdn.example.com/x.png"><img src="{s}/terms.pdf"><img src="{s}/icon.svg"><img src="{s}/badge.png">"#,
+            s = STORAGE
+        );
+        let (rewritten, inline) = images.rewrite(&html, 0);
+
+        let logo = content_id(&format!("{}/logo.PNG", STORAGE));
+        assert!(logo.starts_with("img-") && logo.ends_with(".png"), "{}", logo);
+        assert_eq!(inline.len(), 2, "the repeated logo is attached once");
+        assert_eq!(inline[1].content_type, "image/jpeg", "guessed from the extension");
+        assert_eq!(rewritten.matches(&format!(r#"src="cid:{}""#, logo)).count(), 2);
+        assert!(rewritten.contains(&format!(r#"src="cid:{}" width="600""#, inline[1].content_id)));
+        assert!(rewritten.contains(r#"<img src="https://cdn.example.com/x.png">"#), "other hosts stay remote");
+        assert!(rewritten.contains("terms.pdf"), "non-images stay remote");
+        assert!(rewritten.contains("icon.svg") && rewritten.contains("badge.png"), "SVG stays remote");
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let limits = EmbedLimits { max_image_bytes: 15, max_total_bytes: 100 };
+        let images = images(limits);
+        let html = format!(r#"<img src="{s}/logo.PNG"><img src="{s}/banner.jpg?v=2&amp;w=600">"#, s = STORAGE);
+
+        let (rewritten, inline) = images.rewrite(&html, 0);
+        assert_eq!(inline.len(), 1, "the 20 byte banner is over the image limit");
+        assert!(rewritten.contains("banner.jpg"));
+
+        let (rewritten, inline) = images.rewrite(&html, 95);
+        assert!(inline.is_empty(), "the QR already uses most of the total");
+        assert_eq!(rewritten, html);
+
+        assert_eq!(EmbedLimits::for_provider("Brevo").max_total_bytes, 2 * 1024 * 1024);
+        assert_eq!(EmbedLimits::for_provider("AWS SES"), EmbedLimits::default());
+    }
+}
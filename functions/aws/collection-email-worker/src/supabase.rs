@@ -1,7 +1,9 @@
 use reqwest::Client;
 use serde_json::json;
 use std::error::Error;
-use crate::models::{CollectionClient, CollectionExecution, EmailTemplate, Attachment, ExecutionBatch};
+use crate::models::{CollectionClient, CollectionConfig, CollectionExecution, EmailTemplate, Attachment, ExecutionBatch, EmailLayout, TemplatePartial};
+use crate::outbox::OutboxRecord;
+use crate::fair_scheduler::ActiveExecution;
 use std::env;
 
 pub struct SupabaseService {
@@ -53,6 +55,98 @@ impl SupabaseService {
         "APEX".to_string()
     }
 
+    /// IANA timezone of a business (`businesses.timezone`), used to date invoice aging
+    pub async fn get_business_timezone(&self, business_id: &str) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        let url = format!("{}/rest/v1/businesses?id=eq.{}&select=timezone", self.base_url, business_id);
+
+        let response = self.client.get(&url)
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(format!("Failed to fetch business timezone: {}", response.status()).into());
+        }
+
+        let businesses: Vec<serde_json::Value> = response.json().await?;
+        Ok(businesses.first()
+            .and_then(|b| b.get("timezone"))
+            .and_then(|v| v.as_str())
+            .map(str::to_string))
+    }
+
+    /// Rendering settings of a business; defaults when the business has no config row
+    pub async fn get_collection_config(&self, business_id: &str) -> Result<CollectionConfig, Box<dyn Error + Send + Sync>> {
+        let url = format!("{}/rest/v1/collection_config?business_id=eq.{}&select=*", self.base_url, business_id);
+
+        let response = self.client.get(&url)
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(format!("Failed to fetch collection config: {}", response.status()).into());
+        }
+
+        let configs: Vec<CollectionConfig> = response.json().await?;
+        Ok(configs.into_iter().next().unwrap_or_default())
+    }
+
+    /// Email layout of a business, with `logo_url` resolved against the public
+    /// `business-media` bucket. None when the business has no active layout.
+    pub async fn get_email_layout(&self, business_id: &str) -> Result<Option<EmailLayout>, Box<dyn Error + Send + Sync>> {
+        let url = format!(
+            "{}/rest/v1/collection_email_layouts?business_id=eq.{}&is_active=eq.true&select=*&limit=1",
+            self.base_url, business_id
+        );
+
+        let response = self.client.get(&url)
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(format!("Failed to fetch email layout: {}", response.status()).into());
+        }
+
+        let layouts: Vec<EmailLayout> = response.json().await?;
+        Ok(layouts.into_iter().next().map(|mut layout| {
+            layout.logo_url = layout.logo_path.as_deref()
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(|path| {
+                    if path.starts_with("https://") || path.starts_with("http://") {
+                        path.to_string()
+                    } else {
+                        format!("{}/storage/v1/object/public/business-media/{}", self.base_url, path.trim_start_matches('/'))
+                    }
+                });
+            layout
+        }))
+    }
+
+    pub async fn get_template_partials(&self, business_id: &str) -> Result<Vec<TemplatePartial>, Box<dyn Error + Send + Sync>> {
+        let url = format!(
+            "{}/rest/v1/collection_template_partials?business_id=eq.{}&is_active=eq.true&select=name,content,version",
+            self.base_url, business_id
+        );
+
+        let response = self.client.get(&url)
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(format!("Failed to fetch template partials: {}", response.status()).into());
+        }
+
+        Ok(response.json().await?)
+    }
+
     pub async fn get_execution(&self, execution_id: &str) -> Result<CollectionExecution, Box<dyn Error + Send + Sync>> {
         let url = format!("{}/rest/v1/collection_executions?id=eq.{}&select=*", self.base_url, execution_id);
         
@@ -125,6 +219,38 @@ impl SupabaseService {
         Ok(attachments)
     }
 
+    /// Base URL of the Supabase project, which public storage URLs start with
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Downloads an object from public storage. Returns its bytes and content type; the
+    /// download is aborted as soon as it goes over `max_bytes`, whatever Content-Length says.
+    pub async fn download_public_object(&self, url: &str, max_bytes: usize) -> Result<(Vec<u8>, Option<String>), Box<dyn Error + Send + Sync>> {
+        let mut response = self.client.get(url).send().await?;
+
+        if !response.status().is_success() {
+            return Err(format!("Failed to download {}: {}", url, response.status()).into());
+        }
+        if let Some(length) = response.content_length().filter(|length| *length > max_bytes as u64) {
+            return Err(format!("{} is {} bytes, over the {} byte limit", url, length, max_bytes).into());
+        }
+
+        let content_type = response.headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let mut data = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if data.len() + chunk.len() > max_bytes {
+                return Err(format!("{} is over the {} byte limit", url, max_bytes).into());
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok((data, content_type))
+    }
+
+    #[allow(dead_code)]
     pub async fn get_pending_clients(&self, execution_id: &str) -> Result<Vec<CollectionClient>, Box<dyn Error + Send + Sync>> {
         let url = format!("{}/rest/v1/collection_clients?execution_id=eq.{}&status=eq.pending&select=*", self.base_url, execution_id);
         
@@ -142,6 +268,27 @@ impl SupabaseService {
         Ok(clients)
     }
 
+    /// First `limit` clients of an execution in any status (used by dry runs)
+    pub async fn get_execution_clients(&self, execution_id: &str, limit: usize) -> Result<Vec<CollectionClient>, Box<dyn Error + Send + Sync>> {
+        let url = format!(
+            "{}/rest/v1/collection_clients?execution_id=eq.{}&order=created_at.asc&limit={}&select=*",
+            self.base_url, execution_id, limit
+        );
+
+        let response = self.client.get(&url)
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(format!("Failed to fetch clients: {}", response.status()).into());
+        }
+
+        let clients: Vec<CollectionClient> = response.json().await?;
+        Ok(clients)
+    }
+
     pub async fn get_clients_by_ids(&self, client_ids: &[String]) -> Result<Vec<CollectionClient>, Box<dyn Error + Send + Sync>> {
         if client_ids.is_empty() {
             return Ok(vec![]);
@@ -358,6 +505,7 @@ impl SupabaseService {
         Ok(())
     }
 
+    #[allow(dead_code)]
     pub async fn get_earliest_pending_batch_time(&self) -> Result<Option<chrono::DateTime<chrono::Utc>>, Box<dyn Error + Send + Sync>> {
         let url = format!("{}/rest/v1/execution_batches?status=eq.pending&order=scheduled_for.asc&limit=1&select=scheduled_for", self.base_url);
         
@@ -465,7 +613,9 @@ impl SupabaseService {
         Ok(claimed)
     }
 
-    /// Check if an event already exists to prevent duplicates
+    /// Check if an event already exists to prevent duplicates.
+    /// `message_id` is the worker-generated Message-ID, which the event handler copies
+    /// into `event_data.message_id`.
     pub async fn check_event_exists(
         &self, 
         client_id: &str, 
@@ -493,4 +643,266 @@ impl SupabaseService {
         let events: Vec<serde_json::Value> = response.json().await?;
         Ok(!events.is_empty())
     }
+
+    /// Replace the client list of a batch that has not been picked up yet
+    pub async fn update_batch_client_ids(&self, batch_id: &str, client_ids: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let url = format!("{}/rest/v1/execution_batches?id=eq.{}&status=eq.pending", self.base_url, batch_id);
+
+        let body = json!({
+            "client_ids": client_ids,
+            "total_clients": client_ids.len()
+        });
+
+        let response = self.client.patch(&url)
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Content-Type", "application/json")
+            .header("Prefer", "return=minimal")
+            .json(&body)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(format!("Failed to update batch clients: {}", response.status()).into());
+        }
+
+        Ok(())
+    }
+
+    /// Map of threshold_id -> days_from for the given notification thresholds
+    pub async fn get_threshold_days(&self, threshold_ids: &[String]) -> Result<std::collections::HashMap<String, i64>, Box<dyn Error + Send + Sync>> {
+        if threshold_ids.is_empty() {
+            return Ok(std::collections::HashMap::new());
+        }
+
+        let url = format!(
+            "{}/rest/v1/notification_thresholds?id=in.({})&select=id,days_from",
+            self.base_url, threshold_ids.join(",")
+        );
+
+        let response = self.client.get(&url)
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(format!("Failed to fetch thresholds: {}", response.status()).into());
+        }
+
+        let rows: Vec<serde_json::Value> = response.json().await?;
+        Ok(rows.iter()
+            .filter_map(|r| Some((r.get("id")?.as_str()?.to_string(), r.get("days_from")?.as_i64()?)))
+            .collect())
+    }
+
+    /// Insert an outbox entry. Returns false if an entry with the same idempotency key
+    /// already exists (the unique constraint makes this the atomic claim).
+    pub async fn insert_outbox_record(&self, record: &serde_json::Value) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let url = format!("{}/rest/v1/collection_email_outbox", self.base_url);
+
+        let response = self.client.post(&url)
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Content-Type", "application/json")
+            .header("Prefer", "return=minimal")
+            .json(record)
+            .send()
+            .await?;
+
+        if response.status() == reqwest::StatusCode::CONFLICT {
+            return Ok(false);
+        }
+
+        if !response.status().is_success() {
+            return Err(format!("Failed to insert outbox record: {}", response.status()).into());
+        }
+
+        Ok(true)
+    }
+
+    pub async fn get_outbox_record(&self, idempotency_key: &str) -> Result<Option<OutboxRecord>, Box<dyn Error + Send + Sync>> {
+        let url = format!(
+            "{}/rest/v1/collection_email_outbox?idempotency_key=eq.{}&select=*",
+            self.base_url, idempotency_key
+        );
+
+        let response = self.client.get(&url)
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(format!("Failed to fetch outbox record: {}", response.status()).into());
+        }
+
+        let records: Vec<OutboxRecord> = response.json().await?;
+        Ok(records.into_iter().next())
+    }
+
+    /// Conditionally update an outbox entry (compare-and-swap on status and, optionally, attempt).
+    /// Returns true if a row was updated.
+    pub async fn update_outbox_record(
+        &self,
+        idempotency_key: &str,
+        expected_status: &str,
+        expected_attempt: Option<i32>,
+        body: &serde_json::Value,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let mut url = format!(
+            "{}/rest/v1/collection_email_outbox?idempotency_key=eq.{}&status=eq.{}",
+            self.base_url, idempotency_key, expected_status
+        );
+        if let Some(attempt) = expected_attempt {
+            url.push_str(&format!("&attempt=eq.{}", attempt));
+        }
+
+        let response = self.client.patch(&url)
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Content-Type", "application/json")
+            .header("Prefer", "return=representation")
+            .json(body)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(format!("Failed to update outbox record: {}", response.status()).into());
+        }
+
+        let updated: Vec<serde_json::Value> = response.json().await?;
+        Ok(!updated.is_empty())
+    }
+
+    /// Pending outbox entries not touched since `updated_before` (RFC 3339)
+    pub async fn get_stale_outbox_records(
+        &self,
+        updated_before: &str,
+        execution_id: Option<&str>,
+    ) -> Result<Vec<OutboxRecord>, Box<dyn Error + Send + Sync>> {
+        let mut url = format!(
+            "{}/rest/v1/collection_email_outbox?status=eq.pending&updated_at=lt.{}&order=updated_at.asc&limit=500&select=*",
+            self.base_url, urlencode(updated_before)
+        );
+        if let Some(exec_id) = execution_id {
+            url.push_str(&format!("&execution_id=eq.{}", exec_id));
+        }
+
+        let response = self.client.get(&url)
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(format!("Failed to fetch stale outbox records: {}", response.status()).into());
+        }
+
+        let records: Vec<OutboxRecord> = response.json().await?;
+        Ok(records)
+    }
+
+    /// Executions (of any business) with a pending or processing batch scheduled before
+    /// `due_before` (RFC 3339). Paused, completed and failed executions are excluded.
+    pub async fn get_active_executions(&self, due_before: &str) -> Result<Vec<ActiveExecution>, Box<dyn Error + Send + Sync>> {
+        let url = format!(
+            "{}/rest/v1/execution_batches?status=in.(pending,processing)&scheduled_for=lte.{}\
+             &collection_executions.status=in.(pending,processing)\
+             &select=execution_id,collection_executions!inner(business_id)",
+            self.base_url, urlencode(due_before)
+        );
+
+        let response = self.client.get(&url)
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(format!("Failed to fetch active executions: {}", response.status()).into());
+        }
+
+        let rows: Vec<serde_json::Value> = response.json().await?;
+        let mut active: Vec<ActiveExecution> = rows.iter()
+            .filter_map(|r| Some(ActiveExecution {
+                execution_id: r.get("execution_id")?.as_str()?.to_string(),
+                business_id: r.get("collection_executions")?.get("business_id")?.as_str()?.to_string(),
+            }))
+            .collect();
+        active.sort_by(|a, b| a.execution_id.cmp(&b.execution_id));
+        active.dedup_by(|a, b| a.execution_id == b.execution_id);
+        Ok(active)
+    }
+
+    /// Map of business_id -> collection_config.send_weight
+    pub async fn get_business_send_weights(&self, business_ids: &[String]) -> Result<std::collections::HashMap<String, f64>, Box<dyn Error + Send + Sync>> {
+        if business_ids.is_empty() {
+            return Ok(std::collections::HashMap::new());
+        }
+
+        let url = format!(
+            "{}/rest/v1/collection_config?business_id=in.({})&select=business_id,send_weight",
+            self.base_url, business_ids.join(",")
+        );
+
+        let response = self.client.get(&url)
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(format!("Failed to fetch send weights: {}", response.status()).into());
+        }
+
+        let rows: Vec<serde_json::Value> = response.json().await?;
+        Ok(rows.iter()
+            .filter_map(|r| Some((r.get("business_id")?.as_str()?.to_string(), crate::helpers::parse_number(r.get("send_weight")?)?)))
+            .collect())
+    }
+
+    /// Move a pending batch to a later time (fair scheduling deferral)
+    pub async fn update_batch_scheduled_for(&self, batch_id: &str, scheduled_for: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let url = format!("{}/rest/v1/execution_batches?id=eq.{}&status=eq.pending", self.base_url, batch_id);
+
+        let response = self.client.patch(&url)
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Content-Type", "application/json")
+            .header("Prefer", "return=minimal")
+            .json(&json!({ "scheduled_for": scheduled_for }))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(format!("Failed to update batch schedule: {}", response.status()).into());
+        }
+
+        Ok(())
+    }
+
+    pub async fn update_execution_expected_completion(&self, execution_id: &str, expected_completion_at: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let url = format!("{}/rest/v1/collection_executions?id=eq.{}", self.base_url, execution_id);
+
+        let response = self.client.patch(&url)
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Content-Type", "application/json")
+            .header("Prefer", "return=minimal")
+            .json(&json!({ "expected_completion_at": expected_completion_at }))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(format!("Failed to update expected completion: {}", response.status()).into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Minimal query-string escaping for values such as RFC 3339 timestamps ("+" would decode as a space)
+fn urlencode(value: &str) -> String {
+    value.replace('%', "%25").replace('+', "%2B").replace(':', "%3A")
 }
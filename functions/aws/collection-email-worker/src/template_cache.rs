@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, LazyLock, Mutex};
+use handlebars::Handlebars;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+
+use crate::helpers;
+use crate::models::{EmailTemplate, TemplatePartial};
+use crate::partials::{self, PartialSet};
+use crate::supabase::SupabaseService;
+
+const CONTENT: &str = "content";
+const SUBJECT: &str = "subject";
+
+/// A template preprocessed and compiled once, ready to render any number of clients
+pub struct CompiledTemplate {
+    pub source: EmailTemplate,
+    fingerprint: String,
+    handlebars: Handlebars<'static>,
+    content_error: Option<String>,
+    subject_error: Option<String>,
+    /// Partials the template includes, as `name@version`
+    pub partials: Vec<String>,
+}
+
+/// Compiled templates of this warm container, keyed by template id. An entry is reused
+/// only while the template's subject and content, and the business partials, hash to the
+/// same fingerprint, so an edited template or partial is recompiled on its next fetch.
+/// At most TEMPLATE_CACHE_SIZE templates are kept; the least recently used goes first.
+static COMPILED: LazyLock<Mutex<CompiledCache>> = LazyLock::new(|| {
+    let capacity = std::env::var("TEMPLATE_CACHE_SIZE")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(64);
+    Mutex::new(CompiledCache::new(capacity))
+});
+
+/// Least-recently-used map of compiled templates
+struct CompiledCache {
+    capacity: usize,
+    /// Incremented on every access; an entry's stamp tells when it was last used
+    clock: u64,
+    entries: HashMap<String, (u64, Arc<CompiledTemplate>)>,
+}
+
+impl CompiledCache {
+    fn new(capacity: usize) -> Self {
+        Self { capacity, clock: 0, entries: HashMap::new() }
+    }
+
+    fn get(&mut self, key: &str) -> Option<Arc<CompiledTemplate>> {
+        self.clock += 1;
+        let (used, entry) = self.entries.get_mut(key)?;
+        *used = self.clock;
+        Some(entry.clone())
+    }
+
+    fn insert(&mut self, key: String, entry: Arc<CompiledTemplate>) {
+        self.clock += 1;
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            let oldest = self.entries.iter().min_by_key(|(_, (used, _))| *used).map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(key, (self.clock, entry));
+    }
+}
+
+fn fingerprint(template: &EmailTemplate, rich_fields: &[String], partials: &[TemplatePartial]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(template.subject.as_bytes());
+    hasher.update([0u8]);
+    hasher.update(template.content.as_bytes());
+    for field in rich_fields {
+        hasher.update([0u8]);
+        hasher.update(field.as_bytes());
+    }
+    for partial in partials {
+        hasher.update([1u8]);
+        hasher.update(partial.name.as_bytes());
+        hasher.update(partial.version.to_le_bytes());
+        hasher.update(partial.content.as_bytes());
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+impl CompiledTemplate {
+    /// Runs the TipTap preprocessing and compiles subject and body with HTML escaping;
+    /// `rich_fields` are rendered raw (see [`crate::escaping`]). The business `partials`
+    /// get the same preprocessing and are registered for `{{> name}}`. Compile errors,
+    /// including unknown or cyclic partials, are kept and reported when rendering, so
+    /// callers handle them like render errors.
+    pub fn compile(source: EmailTemplate, rich_fields: &[String], partials: &[TemplatePartial]) -> Self {
+        let mut handlebars = Handlebars::new();
+        helpers::register(&mut handlebars);
+        let mut partial_set = PartialSet::new(partials, rich_fields);
+        partial_set.register(&mut handlebars);
+        let mut used_partials = Vec::new();
+
+        let content = partials::preprocess(&source.content, rich_fields);
+        let content_error = match partial_set.resolve(&content) {
+            Ok(used) => {
+                used_partials.extend(used);
+                handlebars.register_template_string(CONTENT, content).err().map(|e| e.to_string())
+            }
+            Err(e) => Some(e),
+        };
+
+        let subject_error = if source.subject.contains("{{") {
+            let subject = helpers::rewrite_length_paths(&source.subject);
+            match partial_set.resolve(&subject) {
+                Ok(used) => {
+                    used_partials.extend(used);
+                    handlebars.register_template_string(SUBJECT, subject).err().map(|e| e.to_string())
+                }
+                Err(e) => Some(e),
+            }
+        } else {
+            None
+        };
+        used_partials.sort();
+        used_partials.dedup();
+
+        Self {
+            fingerprint: fingerprint(&source, rich_fields, partials),
+            source,
+            handlebars,
+            content_error,
+            subject_error,
+            partials: used_partials,
+        }
+    }
+
+    /// Returns the compiled template for `source`, compiling it only when this container
+    /// has not seen this version yet
+    pub fn cached(source: EmailTemplate, rich_fields: &[String], partials: &[TemplatePartial]) -> Arc<Self> {
+        let key = source.id.clone();
+        let current = fingerprint(&source, rich_fields, partials);
+        let mut compiled = COMPILED.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Some(entry) = compiled.get(&key) {
+            if entry.fingerprint == current {
+                return entry.clone();
+            }
+            info!("[template_cache] Template {} changed, recompiling", key);
+        }
+
+        let entry = Arc::new(Self::compile(source, rich_fields, partials));
+        compiled.insert(key, entry.clone());
+        entry
+    }
+
+    pub fn render_content(&self, data: &serde_json::Value) -> Result<String, Box<dyn Error + Send + Sync>> {
+        if let Some(e) = &self.content_error {
+            return Err(e.clone().into());
+        }
+        Ok(self.handlebars.render(CONTENT, data)?)
+    }
+
+    /// Renders the raw subject; templates without expressions are returned as they are
+    pub fn render_subject(&self, data: &serde_json::Value) -> Result<String, Box<dyn Error + Send + Sync>> {
+        if let Some(e) = &self.subject_error {
+            return Err(e.clone().into());
+        }
+        if !self.handlebars.has_template(SUBJECT) {
+            return Ok(self.source.subject.clone());
+        }
+        Ok(self.handlebars.render(SUBJECT, data)?)
+    }
+}
+
+/// Templates used while processing one batch: each template is fetched from Supabase at
+/// most once, including failed fetches, which are reported for every client using it.
+/// The business partials are loaded once by the caller and shared by every template.
+pub struct BatchTemplates {
+    rich_fields: Vec<String>,
+    partials: Vec<TemplatePartial>,
+    templates: HashMap<String, Result<Arc<CompiledTemplate>, String>>,
+}
+
+impl BatchTemplates {
+    pub fn new(rich_fields: &[String], partials: Vec<TemplatePartial>) -> Self {
+        Self { rich_fields: rich_fields.to_vec(), partials, templates: HashMap::new() }
+    }
+
+    pub async fn get(&mut self, supabase: &SupabaseService, template_id: &str) -> Result<Arc<CompiledTemplate>, String> {
+        if let Some(entry) = self.templates.get(template_id) {
+            return entry.clone();
+        }
+
+        let entry = match supabase.get_template(template_id).await {
+            Ok(template) => Ok(CompiledTemplate::cached(template, &self.rich_fields, &self.partials)),
+            Err(e) => {
+                warn!("[template_cache] Failed to fetch template {}: {}", template_id, e);
+                Err(format!("Failed to fetch template: {}", e))
+            }
+        };
+        self.templates.insert(template_id.to_string(), entry.clone());
+        entry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::Instant;
+
+    fn template(id: &str, content: &str) -> EmailTemplate {
+        EmailTemplate {
+            id: id.to_string(),
+            subject: "Hola {{full_name}}".to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_compiled_template_renders_subject_and_content() {
+        let compiled = CompiledTemplate::compile(template("t", "<p>{{full_name}} debe {{invoices.length}}</p>"), &[], &[]);
+        let data = json!({ "full_name": "ACME", "invoices": [1, 2] });
+
+        assert_eq!(compiled.render_subject(&data).unwrap(), "Hola ACME");
+        assert_eq!(compiled.render_content(&data).unwrap(), "<p>ACME debe 2</p>");
+    }
+
+    #[test]
+    fn test_compile_errors_surface_on_render() {
+        let compiled = CompiledTemplate::compile(template("t", "<p>{{#if x}}sin cerrar</p>"), &[], &[]);
+        assert!(compiled.render_content(&json!({})).is_err());
+        assert!(compiled.render_subject(&json!({})).is_ok());
+    }
+
+    #[test]
+    fn test_cache_reuses_until_template_changes() {
+        let first = CompiledTemplate::cached(template("cache-test", "<p>v1</p>"), &[], &[]);
+        let again = CompiledTemplate::cached(template("cache-test", "<p>v1</p>"), &[], &[]);
+        assert!(Arc::ptr_eq(&first, &again));
+
+        let edited = CompiledTemplate::cached(template("cache-test", "<p>v2</p>"), &[], &[]);
+        assert!(!Arc::ptr_eq(&first, &edited));
+        assert_eq!(edited.render_content(&json!({})).unwrap(), "<p>v2</p>");
+
+        let rich = CompiledTemplate::cached(template("cache-test", "<p>v2</p>"), &["agent_message".to_string()], &[]);
+        assert!(!Arc::ptr_eq(&edited, &rich));
+    }
+
+    #[test]
+    fn test_cache_evicts_least_recently_used() {
+        let mut cache = CompiledCache::new(2);
+        let compiled = |id: &str| Arc::new(CompiledTemplate::compile(template(id, "<p></p>"), &[], &[]));
+        cache.insert("a".to_string(), compiled("a"));
+        cache.insert("b".to_string(), compiled("b"));
+        assert!(cache.get("a").is_some());
+
+        cache.insert("c".to_string(), compiled("c"));
+        assert_eq!(cache.entries.len(), 2);
+        assert!(cache.get("b").is_none(), "b was used least recently");
+        assert!(cache.get("a").is_some() && cache.get("c").is_some());
+
+        cache.insert("c".to_string(), compiled("c"));
+        assert!(cache.get("a").is_some(), "replacing an entry evicts nothing");
+    }
+
+    #[test]
+    fn test_values_are_escaped_unless_raw() {
+        let content = "<p>{{full_name}}</p><p>{{{firma}}}</p><p>{{agent_message}}</p>";
+        let compiled = CompiledTemplate::compile(template("t", content), &["agent_message".to_string()], &[]);
+        let data = json!({
+            "full_name": "<img src=x onerror=alert(1)>A&B",
+            "firma": "<b>Cartera</b>",
+            "agent_message": "<i>Gracias</i>"
+        });
+
+        assert_eq!(
+            compiled.render_content(&data).unwrap(),
+            "<p>&lt;img src&#x3D;x onerror&#x3D;alert(1)&gt;A&amp;B</p><p><b>Cartera</b></p><p><i>Gracias</i></p>"
+        );
+    }
+
+    #[test]
+    fn test_partials_are_preprocessed_and_tracked() {
+        let partials = vec![
+            TemplatePartial { name: "firma".to_string(), content: "<p>{{agent_message}}</p><p>{{> contacto}}</p>".to_string(), version: 2 },
+            TemplatePartial { name: "contacto".to_string(), content: "<p>Tel {{phone}}</p>".to_string(), version: 1 },
+        ];
+        let rich = ["agent_message".to_string()];
+        let compiled = CompiledTemplate::compile(template("t", "<p>Hola</p><p>{{&gt; firma}}</p>"), &rich, &partials);
+        let data = json!({ "agent_message": "<b>Cartera</b>", "phone": "300" });
+
+        assert_eq!(compiled.partials, vec!["contacto@1", "firma@2"]);
+        assert_eq!(compiled.render_content(&data).unwrap(), "<p>Hola</p><p><p><b>Cartera</b></p><p><p>Tel 300</p></p></p>");
+
+        let edited = vec![TemplatePartial { version: 3, ..partials[0].clone() }, partials[1].clone()];
+        let first = CompiledTemplate::cached(template("partials-cache-test", "{{> firma}}"), &[], &partials);
+        let again = CompiledTemplate::cached(template("partials-cache-test", "{{> firma}}"), &[], &edited);
+        assert!(!Arc::ptr_eq(&first, &again));
+    }
+
+    #[test]
+    fn test_cyclic_partials_fail_only_templates_using_them() {
+        let partials = vec![
+            TemplatePartial { name: "a".to_string(), content: "{{> b}}".to_string(), version: 1 },
+            TemplatePartial { name: "b".to_string(), content: "{{> a}}".to_string(), version: 1 },
+        ];
+        let broken = CompiledTemplate::compile(template("t", "<p>{{> a}}</p>"), &[], &partials);
+        let err = broken.render_content(&json!({})).unwrap_err().to_string();
+        assert_eq!(err, "Partial cycle: a -> b -> a");
+
+        let fine = CompiledTemplate::compile(template("t", "<p>ok</p>"), &[], &partials);
+        assert_eq!(fine.render_content(&json!({})).unwrap(), "<p>ok</p>");
+    }
+
+    /// Per-client render cost of compiling for every client (the previous behaviour)
+    /// versus compiling once per batch. Run with
+    /// `cargo test --release bench_render_cost -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_render_cost() {
+        let content = r#"<p>Hola {{full_name}},</p>
+            <table class="tiptap-table"><tbody>
+            <tr><th><p>Factura</p></th><th><p>Vence</p></th><th><p>Valor</p></th></tr>
+            <tr><td colspan="3"><p>{{#each invoices}}</p></td></tr>
+            <tr><td><p>{{invoice_number}}</p></td><td><p>{{date due_date}}</p></td><td><p>{{currency amount_due}}</p></td></tr>
+            <tr><td colspan="3"><p>{{/each}}</p></td></tr>
+            </tbody></table>
+            <p>Total: {{currency total_amount_due}}</p>"#;
+        let data = json!({
+            "full_name": "ACME SAS",
+            "total_amount_due": 4500000,
+            "output_date_format": "%d-%m-%Y",
+            "invoices": (0..10).map(|i| json!({
+                "invoice_number": format!("F-{}", i),
+                "due_date": "2026-01-31",
+                "amount_due": 450000
+            })).collect::<Vec<_>>()
+        });
+        let clients = 500;
+
+        let started = Instant::now();
+        for _ in 0..clients {
+            let compiled = CompiledTemplate::compile(template("bench", content), &[], &[]);
+            compiled.render_subject(&data).unwrap();
+            compiled.render_content(&data).unwrap();
+        }
+        let per_client_before = started.elapsed() / clients;
+
+        let started = Instant::now();
+        let compiled = CompiledTemplate::compile(template("bench", content), &[], &[]);
+        for _ in 0..clients {
+            compiled.render_subject(&data).unwrap();
+            compiled.render_content(&data).unwrap();
+        }
+        let per_client_after = started.elapsed() / clients;
+
+        println!(
+            "render cost per client: compile every client {:?}, compile once {:?}",
+            per_client_before, per_client_after
+        );
+    }
+}
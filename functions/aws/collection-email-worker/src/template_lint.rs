@@ -0,0 +1,554 @@
+use std::collections::BTreeSet;
+use std::sync::LazyLock;
+use handlebars::Template;
+use regex::Regex;
+use serde::Serialize;
+
+use crate::formatting::{is_amount_field, is_date_field};
+use crate::helpers;
+use crate::tiptap::unescape_expression;
+use crate::models::{CollectionClient, EmailTemplate, TemplatePartial};
+
+/// Root fields every client has: the variables offered by the template editor plus the
+/// ones added while rendering
+const ROOT_FIELDS: [&str; 25] = [
+    "email", "full_name", "nit", "company_name", "phone", "total_amount_due",
+    "total_days_overdue", "total_invoices", "agent_message", "invoices",
+    "currency", "locale", "output_date_format", "language",
+    "as_of_date", "is_overdue", "aging_bucket", "overdue_total", "aging_total_current",
+    "aging_total_1_30", "aging_total_31_60", "aging_total_61_90", "aging_total_90_plus",
+    "payment_url", "payment_qr",
+];
+
+/// Helpers that take no arguments, so `{{name}}` is a call rather than a variable
+const BARE_HELPERS: [&str; 1] = ["invoice_table"];
+
+/// Fields of the standard invoice schema
+const INVOICE_FIELDS: [&str; 8] = [
+    "invoice_number", "invoice_date", "due_date", "days_overdue", "amount_due", "is_overdue", "aging_bucket",
+    "payment_url",
+];
+
+static EXPRESSION: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?s)\{\{\{?(.*?)\}?\}\}").unwrap());
+static ZERO_SPAN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?i)\b(colspan|rowspan)\s*=\s*["']?0["']?"#).unwrap());
+static IMG_TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?is)<img\b[^>]*>").unwrap());
+static ALT_ATTR: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?i)\salt\s*=").unwrap());
+static UNSUPPORTED_TAG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)<(script|iframe|object|embed|form)\b").unwrap());
+static REMOTE_ATTR: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?is)<(img|link|video|audio|source|input|table|td|th|body)\b[^>]*?\s(?:src|href|background|poster)\s*=\s*["']?((?:https?:)?//[^"'\s>]+)"#).unwrap()
+});
+static REMOTE_CSS_URL: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?i)url\(\s*["']?((?:https?:)?//[^"')\s]+)"#).unwrap());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// One finding, positioned in the template field it was found in. `line` and `column`
+/// are 1-based; `offset` and `length` count characters, as the editor does.
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    pub field: &'static str,
+    pub line: usize,
+    pub column: usize,
+    pub offset: usize,
+    pub length: usize,
+}
+
+/// Variables a template may use: the standard schema, extended with the custom_data and
+/// invoice keys of sample clients. Derived `<field>_value` and `<field>_iso` variants of
+/// amount and date fields are included. Partials are only checked once the business's
+/// partials are known.
+#[derive(Debug, Clone)]
+pub struct TemplateSchema {
+    root: BTreeSet<String>,
+    invoice: BTreeSet<String>,
+    partials: Option<BTreeSet<String>>,
+}
+
+impl Default for TemplateSchema {
+    fn default() -> Self {
+        let mut schema = Self { root: BTreeSet::new(), invoice: BTreeSet::new(), partials: None };
+        for field in ROOT_FIELDS {
+            add_field(&mut schema.root, field);
+        }
+        for field in INVOICE_FIELDS {
+            add_field(&mut schema.invoice, field);
+        }
+        schema
+    }
+}
+
+fn add_field(fields: &mut BTreeSet<String>, key: &str) {
+    if is_amount_field(key) {
+        fields.insert(format!("{}_value", key));
+    }
+    if is_date_field(key) {
+        fields.insert(format!("{}_iso", key));
+    }
+    fields.insert(key.to_string());
+}
+
+impl TemplateSchema {
+    pub fn with_clients(mut self, clients: &[CollectionClient]) -> Self {
+        for client in clients {
+            if let Some(map) = client.custom_data.as_ref().and_then(|d| d.as_object()) {
+                for key in map.keys() {
+                    add_field(&mut self.root, key);
+                }
+            }
+            for invoice in client.invoices.as_ref().and_then(|i| i.as_array()).into_iter().flatten() {
+                for key in invoice.as_object().into_iter().flat_map(|m| m.keys()) {
+                    add_field(&mut self.invoice, key);
+                }
+            }
+        }
+        self
+    }
+
+    pub fn with_partials(mut self, partials: &[TemplatePartial]) -> Self {
+        self.partials = Some(partials.iter().map(|p| p.name.clone()).collect());
+        self
+    }
+}
+
+/// Checks the subject and content of a template before it is used: Handlebars syntax and
+/// block balance, variables outside `schema`, TipTap artefacts, images without alt text
+/// and remote or unsupported resources.
+pub fn validate_template(template: &EmailTemplate, schema: &TemplateSchema) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    lint_handlebars(&template.subject, "subject", schema, &mut diagnostics);
+    lint_handlebars(&template.content, "content", schema, &mut diagnostics);
+    lint_html(&template.content, &mut diagnostics);
+    diagnostics.sort_by_key(|d| (d.field != "subject", d.offset));
+    diagnostics
+}
+
+fn diagnostic(
+    source: &str,
+    field: &'static str,
+    range: std::ops::Range<usize>,
+    severity: Severity,
+    code: &'static str,
+    message: String,
+) -> Diagnostic {
+    let before = &source[..range.start];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+
+    Diagnostic {
+        severity,
+        code,
+        message,
+        field,
+        line,
+        column: source[line_start..range.start].chars().count() + 1,
+        offset: before.chars().count(),
+        length: source[range].chars().count(),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Scope {
+    Root,
+    Invoice,
+    /// Inside `{{#with}}` or `{{#each}}` over something other than the invoices
+    Unknown,
+}
+
+struct OpenBlock {
+    name: String,
+    range: std::ops::Range<usize>,
+}
+
+enum Token {
+    Literal,
+    Word { text: String, callee: bool },
+}
+
+/// Splits the inside of an expression into literals and words. Words right after `(` are
+/// helper names; hash keys (`decimals=`) are dropped and their values kept.
+fn tokenize(expression: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.chars().peekable();
+    let mut after_paren = false;
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            '"' | '\'' => {
+                chars.next();
+                for next in chars.by_ref() {
+                    if next == c {
+                        break;
+                    }
+                }
+                tokens.push(Token::Literal);
+                after_paren = false;
+            }
+            '(' => {
+                chars.next();
+                after_paren = true;
+            }
+            ')' => {
+                chars.next();
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' || c == '"' || c == '\'' {
+                        break;
+                    }
+                    chars.next();
+                    if c == '=' {
+                        word.clear();
+                        break;
+                    }
+                    word.push(c);
+                }
+                if word.is_empty() {
+                    continue;
+                }
+                let literal = word.parse::<f64>().is_ok() || matches!(word.as_str(), "true" | "false" | "null" | "undefined");
+                tokens.push(if literal { Token::Literal } else { Token::Word { text: word, callee: after_paren } });
+                after_paren = false;
+            }
+        }
+    }
+    tokens
+}
+
+/// Returns the first segment of `path` and the scope it resolves in, or `None` when the
+/// path can't be checked (`this`, `@index`, unknown scopes)
+fn resolve<'a>(path: &'a str, scopes: &[Scope]) -> Option<(&'a str, Scope)> {
+    let mut path = path;
+    let mut depth = scopes.len() - 1;
+
+    if let Some(rest) = path.strip_prefix("@root.") {
+        path = rest;
+        depth = 0;
+    }
+    while let Some(rest) = path.strip_prefix("../") {
+        path = rest;
+        depth = depth.saturating_sub(1);
+    }
+    path = path.strip_prefix("this.").or_else(|| path.strip_prefix("./")).unwrap_or(path);
+
+    if path.is_empty() || path == "this" || path.starts_with('@') {
+        return None;
+    }
+    let first = path.split(['.', '[', '/']).next().unwrap_or(path);
+    match scopes[depth] {
+        Scope::Unknown => None,
+        scope => Some((first, scope)),
+    }
+}
+
+fn lint_handlebars(source: &str, field: &'static str, schema: &TemplateSchema, diagnostics: &mut Vec<Diagnostic>) {
+    let mut blocks: Vec<OpenBlock> = Vec::new();
+    let mut scopes = vec![Scope::Root];
+    // Structural errors already reported make the parser's own error redundant
+    let mut reported = false;
+
+    for caps in EXPRESSION.captures_iter(source) {
+        let whole = caps.get(0).unwrap();
+        let range = whole.range();
+        // Read the expression as Handlebars will see it after the TipTap preprocessing
+        let decoded = unescape_expression(&caps[1]);
+        let inner = decoded.trim_matches('~').trim();
+
+        if inner.starts_with('!') {
+            continue;
+        }
+        if let Some(reference) = inner.trim_start_matches('#').strip_prefix('>') {
+            let name = reference.split_whitespace().next().unwrap_or_default();
+            if schema.partials.as_ref().is_some_and(|known| !known.contains(name)) {
+                diagnostics.push(diagnostic(source, field, range.clone(), Severity::Error, "unknown_partial",
+                    format!("'{}' is not a partial of this business", name)));
+            }
+            if inner.starts_with('#') {
+                blocks.push(OpenBlock { name: name.to_string(), range });
+                scopes.push(scopes[scopes.len() - 1]);
+            }
+            continue;
+        }
+        if inner.contains('<') && inner.contains('>') {
+            reported = true;
+            diagnostics.push(diagnostic(source, field, range, Severity::Error, "markup_in_expression",
+                "The expression contains HTML formatting; remove the formatting applied to part of the variable".to_string()));
+            continue;
+        }
+
+        let (kind, body) = match inner.chars().next() {
+            Some(c @ ('#' | '^' | '/')) => (Some(c), inner[1..].trim()),
+            _ => (None, inner),
+        };
+
+        if kind == Some('/') {
+            match blocks.pop() {
+                None => {
+                    reported = true;
+                    diagnostics.push(diagnostic(source, field, range, Severity::Error, "unexpected_close",
+                        format!("{{{{/{}}}}} closes a block that was never opened", body)));
+                }
+                Some(open) => {
+                    scopes.pop();
+                    if open.name != body {
+                        reported = true;
+                        diagnostics.push(diagnostic(source, field, range, Severity::Error, "mismatched_block",
+                            format!("{{{{/{}}}}} closes {{{{#{}}}}}", body, open.name)));
+                    }
+                }
+            }
+            continue;
+        }
+
+        let tokens = tokenize(body);
+        let mut words = tokens.iter().enumerate().filter_map(|(i, t)| match t {
+            Token::Word { text, callee } => Some((i, text.as_str(), *callee)),
+            Token::Literal => None,
+        }).peekable();
+
+        // The first word of a block, an `else` chain or an expression with parameters is a helper
+        let is_call = kind.is_some() || tokens.len() > 1
+            || matches!(tokens.first(), Some(Token::Word { text, .. }) if BARE_HELPERS.contains(&text.as_str()));
+        let mut helper_name = None;
+        if let Some(&(0, first, _)) = words.peek() {
+            if first == "else" {
+                words.next();
+                if let Some(&(1, name, _)) = words.peek() {
+                    helper_name = Some(name.to_string());
+                    words.next();
+                }
+            } else if is_call {
+                helper_name = Some(first.to_string());
+                words.next();
+            }
+        }
+
+        for (_, word, callee) in words {
+            if callee {
+                continue;
+            }
+            let Some((name, scope)) = resolve(word, &scopes) else { continue };
+            let known = match scope {
+                Scope::Root => &schema.root,
+                _ => &schema.invoice,
+            };
+            if !known.contains(name) {
+                let place = if scope == Scope::Invoice { "the invoices" } else { "the client data" };
+                diagnostics.push(diagnostic(source, field, range.clone(), Severity::Warning, "unknown_variable",
+                    format!("`{}` does not exist in {}", name, place)));
+            }
+        }
+
+        if let Some('#' | '^') = kind {
+            let name = helper_name.unwrap_or_default();
+            let target = tokens.get(1).and_then(|t| match t {
+                Token::Word { text, .. } => Some(text.as_str()),
+                Token::Literal => None,
+            });
+            let current = *scopes.last().unwrap();
+            let scope = match (name.as_str(), target) {
+                ("each", Some(path)) if resolve(path, &scopes) == Some(("invoices", Scope::Root)) => Scope::Invoice,
+                ("each", _) | ("with", _) => Scope::Unknown,
+                _ => current,
+            };
+            blocks.push(OpenBlock { name, range });
+            scopes.push(scope);
+        }
+    }
+
+    for open in blocks {
+        reported = true;
+        diagnostics.push(diagnostic(source, field, open.range, Severity::Error, "unclosed_block",
+            format!("{{{{#{}}}}} is never closed", open.name)));
+    }
+
+    // Anything else Handlebars rejects, positioned by its own parser
+    if !reported {
+        let (decoded, raw_offset) = unescape_expressions(source);
+        if let Err(e) = Template::compile(&helpers::rewrite_length_paths(&decoded)) {
+            let offset = e.pos()
+                .and_then(|(line, column)| {
+                    let start = decoded.split_inclusive('\n').take(line.saturating_sub(1)).map(str::len).sum::<usize>();
+                    decoded[start..].char_indices().nth(column.saturating_sub(1)).map(|(i, _)| raw_offset(start + i))
+                })
+                .unwrap_or(0);
+            diagnostics.push(diagnostic(source, field, offset..offset, Severity::Error, "syntax_error", e.to_string()));
+        }
+    }
+}
+
+/// `source` with the TipTap entities inside its expressions decoded, and a function
+/// mapping offsets of the decoded text back to `source`. Offsets inside a decoded
+/// expression keep their distance from its start, within the expression.
+fn unescape_expressions(source: &str) -> (String, impl Fn(usize) -> usize) {
+    let mut decoded = String::with_capacity(source.len());
+    // (decoded start, decoded length, raw start, raw length) of every expression
+    let mut expressions: Vec<(usize, usize, usize, usize)> = Vec::new();
+    let mut last = 0;
+    for m in EXPRESSION.find_iter(source) {
+        decoded.push_str(&source[last..m.start()]);
+        let expression = unescape_expression(m.as_str());
+        expressions.push((decoded.len(), expression.len(), m.start(), m.len()));
+        decoded.push_str(&expression);
+        last = m.end();
+    }
+    decoded.push_str(&source[last..]);
+
+    let raw_offset = move |offset: usize| match expressions.iter().rev().find(|e| e.0 <= offset) {
+        Some(&(start, len, raw_start, raw_len)) if offset < start + len => raw_start + (offset - start).min(raw_len - 1),
+        Some(&(start, len, raw_start, raw_len)) => raw_start + raw_len + (offset - start - len),
+        None => offset,
+    };
+    (decoded, raw_offset)
+}
+
+fn lint_html(source: &str, diagnostics: &mut Vec<Diagnostic>) {
+    for m in ZERO_SPAN.find_iter(source) {
+        diagnostics.push(diagnostic(source, "content", m.range(), Severity::Warning, "zero_span",
+            format!("`{}` is a TipTap artefact; the cell will be rendered with span 1", m.as_str())));
+    }
+
+    for m in IMG_TAG.find_iter(source) {
+        if !ALT_ATTR.is_match(m.as_str()) {
+            diagnostics.push(diagnostic(source, "content", m.range(), Severity::Warning, "image_missing_alt",
+                "Image without alt text; it is shown when images are blocked and read by screen readers".to_string()));
+        }
+    }
+
+    for caps in UNSUPPORTED_TAG.captures_iter(source) {
+        let m = caps.get(0).unwrap();
+        diagnostics.push(diagnostic(source, "content", m.range(), Severity::Error, "unsupported_element",
+            format!("<{}> is removed or blocked by email clients", caps[1].to_lowercase())));
+    }
+
+    let remote = REMOTE_ATTR.captures_iter(source).map(|c| c.get(2).unwrap())
+        .chain(REMOTE_CSS_URL.captures_iter(source).map(|c| c.get(1).unwrap()));
+    for url in remote {
+        diagnostics.push(diagnostic(source, "content", url.range(), Severity::Warning, "remote_resource",
+            format!("{} is loaded from the network; many email clients block it until the recipient allows it", url.as_str())));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lint(content: &str) -> Vec<Diagnostic> {
+        let template = EmailTemplate { id: "t".to_string(), subject: "Hola".to_string(), content: content.to_string() };
+        validate_template(&template, &TemplateSchema::default())
+    }
+
+    fn codes(diagnostics: &[Diagnostic]) -> Vec<&'static str> {
+        diagnostics.iter().map(|d| d.code).collect()
+    }
+
+    #[test]
+    fn test_valid_template_has_no_diagnostics() {
+        let diagnostics = lint(
+            r#"<p>Hola {{full_name}}, debe {{currency total_amount_due_value decimals=0}}</p>
+            {{#each invoices}}<p>{{invoice_number}} {{date due_date_iso}} {{days_overdue}} {{../company_name}}</p>{{/each}}
+            {{#if (gt invoices.length 1)}}<p>{{pluralize invoices "factura"}}</p>{{else}}<p>{{@root.nit}}</p>{{/if}}
+            <img src="cid:logo" alt="Logo">"#,
+        );
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    }
+
+    #[test]
+    fn test_unbalanced_blocks_are_positioned() {
+        let diagnostics = lint("<p>Hola</p>\n<p>{{#each invoices}}{{invoice_number}}</p>");
+        assert_eq!(codes(&diagnostics), vec!["unclosed_block"]);
+        assert_eq!((diagnostics[0].line, diagnostics[0].column), (2, 4));
+        assert_eq!(diagnostics[0].offset, 15);
+        assert_eq!(diagnostics[0].length, "{{#each invoices}}".len());
+
+        let diagnostics = lint("{{#if full_name}}x{{/each}}{{/if}}");
+        assert_eq!(codes(&diagnostics), vec!["mismatched_block", "unexpected_close"]);
+    }
+
+    #[test]
+    fn test_unknown_variables_by_scope() {
+        let diagnostics = lint("{{nombre}} {{#each invoices}}{{numero}} {{invoice_number}}{{/each}} {{#each otros}}{{x}}{{/each}}");
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, vec![
+            "`nombre` does not exist in the client data",
+            "`numero` does not exist in the invoices",
+            "`otros` does not exist in the client data",
+        ]);
+
+        let client = CollectionClient {
+            id: "c".to_string(),
+            execution_id: "e".to_string(),
+            status: "pending".to_string(),
+            invoices: Some(serde_json::json!([{ "numero": "1" }])),
+            custom_data: Some(serde_json::json!({ "nombre": "ACME", "saldo": 10 })),
+            email_template_id: None,
+            threshold_id: None,
+        };
+        let schema = TemplateSchema::default().with_clients(&[client]);
+        let template = EmailTemplate {
+            id: "t".to_string(),
+            subject: "{{nombre}} {{saldo_value}}".to_string(),
+            content: "{{#each invoices}}{{numero}}{{/each}}".to_string(),
+        };
+        assert!(validate_template(&template, &schema).is_empty());
+    }
+
+    #[test]
+    fn test_partials_are_checked_against_the_business() {
+        let template = EmailTemplate {
+            id: "t".to_string(),
+            subject: String::new(),
+            content: "{{> firma}} {{> falta}} {{#> marco}}{{full_name}}{{/marco}}".to_string(),
+        };
+        assert!(validate_template(&template, &TemplateSchema::default()).is_empty());
+
+        let partials = ["firma", "marco"].map(|name| TemplatePartial { name: name.to_string(), content: String::new(), version: 1 });
+        let diagnostics = validate_template(&template, &TemplateSchema::default().with_partials(&partials));
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, "unknown_partial");
+        assert_eq!((diagnostics[0].offset, diagnostics[0].length), (12, 11));
+
+        // TipTap stores `>` as an entity; the preprocessing decodes it before compiling
+        let template = EmailTemplate {
+            id: "t".to_string(),
+            subject: String::new(),
+            content: "<p>{{&gt; firma}}</p><p>{{&gt; falta}} {{/if}}</p>".to_string(),
+        };
+        let diagnostics = validate_template(&template, &TemplateSchema::default().with_partials(&partials));
+        assert_eq!(codes(&diagnostics), vec!["unknown_partial", "unexpected_close"]);
+        assert_eq!((diagnostics[0].offset, diagnostics[0].length), (24, 14));
+    }
+
+    #[test]
+    fn test_tiptap_artefacts_and_resources() {
+        let diagnostics = lint(concat!(
+            r#"<table><tr><td colspan="0">{{<strong>full_name</strong>}}</td></tr></table>{{invoice_table}}"#,
+            r#"<img src="https://cdn.example.com/logo.png"><div style="background: url('//x.example/bg.png')"></div>"#,
+            r#"<script>alert(1)</script><a href="https://pay.example.com">Pagar</a>"#,
+        ));
+        assert_eq!(
+            codes(&diagnostics),
+            vec!["zero_span", "markup_in_expression", "image_missing_alt", "remote_resource", "remote_resource", "unsupported_element"]
+        );
+    }
+
+    #[test]
+    fn test_syntax_errors_from_the_parser() {
+        let diagnostics = lint("<p>Hola</p>\n<p>{{full_name</p>");
+        assert_eq!(codes(&diagnostics), vec!["syntax_error"]);
+        assert_eq!(diagnostics[0].line, 2);
+    }
+}
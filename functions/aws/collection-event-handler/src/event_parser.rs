@@ -50,8 +50,57 @@ pub struct SesMail {
     pub destination: Vec<String>,
     #[serde(rename = "sendingAccountId", default)]
     pub sending_account_id: Option<String>,
+    /// Identity ARN the message was sent from (`arn:aws:ses:<region>:<account>:identity/...`)
+    #[serde(rename = "sourceArn", default)]
+    pub source_arn: Option<String>,
     #[serde(rename = "timestamp", default)]
     pub timestamp: Option<String>,
+    /// Message tags set on send (e.g. the worker's outbox idempotency_key)
+    #[serde(default)]
+    pub tags: std::collections::HashMap<String, Vec<String>>,
+    #[serde(rename = "commonHeaders", default)]
+    pub common_headers: Option<SesCommonHeaders>,
+    #[serde(default)]
+    pub headers: Vec<SesHeader>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SesCommonHeaders {
+    #[serde(rename = "messageId", default)]
+    pub message_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SesHeader {
+    pub name: String,
+    pub value: String,
+}
+
+impl SesMail {
+    /// The Message-ID header generated by the worker (`<client.attempt@domain>`), if the
+    /// event carries the original headers. Falls back to None for messages sent before
+    /// the worker assigned its own ids.
+    pub fn rfc_message_id(&self) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case("Message-ID"))
+            .map(|h| h.value.as_str())
+            .or_else(|| self.common_headers.as_ref()?.message_id.as_deref())
+            .filter(|id| id.starts_with('<') && id.ends_with('>'))
+    }
+
+    /// Provider that sent the message, named as the worker's `provider_name()` so both
+    /// functions report the same `Provider` metric dimension
+    pub fn provider(&self) -> Option<&'static str> {
+        match self.source_arn.as_deref()?.split(':').nth(2)? {
+            "ses" => Some("AWS SES"),
+            _ => None,
+        }
+    }
+
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags.get(name)?.first().map(|s| s.as_str())
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -106,3 +155,57 @@ pub struct SesRecipient {
     #[serde(rename = "diagnosticCode", default)]
     pub diagnostic_code: Option<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mail(json: &str) -> SesMail {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_rfc_message_id_from_headers() {
+        let m = mail(r#"{
+            "messageId": "0100018f-ses",
+            "destination": ["a@example.com"],
+            "headers": [{"name": "Message-ID", "value": "<c1.1@apex.borls.com>"}],
+            "commonHeaders": {"messageId": "<0100018f-ses@email.amazonses.com>"}
+        }"#);
+        assert_eq!(m.rfc_message_id(), Some("<c1.1@apex.borls.com>"));
+    }
+
+    #[test]
+    fn test_rfc_message_id_from_common_headers() {
+        let m = mail(r#"{
+            "messageId": "0100018f-ses",
+            "destination": ["a@example.com"],
+            "commonHeaders": {"messageId": "<c1.2@apex.borls.com>"}
+        }"#);
+        assert_eq!(m.rfc_message_id(), Some("<c1.2@apex.borls.com>"));
+    }
+
+    #[test]
+    fn test_idempotency_tag() {
+        let m = mail(r#"{
+            "messageId": "0100018f-ses",
+            "destination": ["a@example.com"],
+            "tags": {"idempotency_key": ["abc123"]}
+        }"#);
+        assert_eq!(m.tag("idempotency_key"), Some("abc123"));
+        assert_eq!(m.rfc_message_id(), None);
+    }
+
+    #[test]
+    fn test_provider_from_source_arn() {
+        let m = mail(r#"{
+            "messageId": "0100018f-ses",
+            "destination": ["a@example.com"],
+            "sourceArn": "arn:aws:ses:us-east-1:123456789012:identity/apex.borls.com"
+        }"#);
+        assert_eq!(m.provider(), Some("AWS SES"));
+
+        let m = mail(r#"{"messageId": "x", "destination": []}"#);
+        assert_eq!(m.provider(), None);
+    }
+}
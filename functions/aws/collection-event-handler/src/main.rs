@@ -6,10 +6,13 @@
  use simple_logger::SimpleLogger;
 
 mod event_parser;
+mod metrics;
 mod supabase;
 
 use event_parser::{SnsEvent, SesEvent};
 use supabase::SupabaseService;
+use metrics::Metrics;
+use std::collections::BTreeMap;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
@@ -30,6 +33,9 @@ async fn func(event: LambdaEvent<Value>) -> Result<Value, Error> {
             let supabase = SupabaseService::new();
             let mut processed = 0;
             let mut errors = 0;
+            // One metrics set per provider and business so every EMF line carries its
+            // tenant dimension
+            let mut metrics_by_business: BTreeMap<(String, String), Metrics> = BTreeMap::new();
 
             for record in sns.records {
                 let message = record.sns.message;
@@ -47,16 +53,54 @@ async fn func(event: LambdaEvent<Value>) -> Result<Value, Error> {
                 };
 
                 let message_id = ses_event.mail.message_id.clone();
+                let rfc_message_id = ses_event.mail.rfc_message_id().map(|id| id.to_string());
                 let event_type = ses_event.notification_type.clone();
 
-                info!("Processing {} event for MessageID: {}", event_type, message_id);
+                info!("Processing {} event for MessageID: {} (Message-ID header: {:?})", event_type, message_id, rfc_message_id);
 
-                match supabase.find_client_by_message_id(&message_id).await {
-                    Ok(Some((client_id, execution_id))) => {
+                let event_provider = ses_event.mail.provider();
+                let provider = event_provider.unwrap_or("unknown");
+                let outbox_key = ses_event.mail.tag("idempotency_key").map(|k| k.to_string());
+                if let Some(key) = &outbox_key {
+                    match supabase.resolve_outbox_entry(key, event_provider, &message_id).await {
+                        Ok(true) => info!("Resolved pending or failed outbox entry {} as sent", key),
+                        Ok(false) => {}
+                        Err(e) => error!("Failed to resolve outbox entry {}: {}", key, e),
+                    }
+                }
+
+                // Correlate on our own Message-ID first; the provider id and the outbox key
+                // cover messages sent before the worker generated ids, or whose client row
+                // was never updated because the worker crashed after sending.
+                let mut client_lookup = Ok(None);
+                if let Some(id) = &rfc_message_id {
+                    client_lookup = supabase.find_client_by_message_id("message_id", id).await;
+                }
+                if matches!(client_lookup, Ok(None)) {
+                    client_lookup = supabase.find_client_by_message_id("provider_message_id", &message_id).await;
+                }
+                if matches!(client_lookup, Ok(None)) {
+                    client_lookup = supabase.find_client_by_message_id("message_id", &message_id).await;
+                }
+                if let (Ok(None), Some(key)) = (&client_lookup, &outbox_key) {
+                    client_lookup = supabase.find_client_by_outbox_key(key).await;
+                }
+
+                match client_lookup {
+                    Ok(Some((client_id, execution_id, business_id))) => {
                         info!("Found client ID: {} (exec: {})", client_id, execution_id);
 
+                        let metrics = business_metrics(&mut metrics_by_business, provider, business_id.as_deref());
+                        if let Some(metric_name) = event_metric_name(&event_type) {
+                            metrics.count(metric_name, 1);
+                        }
+
                         // Track Event
-                        let metadata = serde_json::to_value(&ses_event).unwrap_or(Value::Null);
+                        let mut metadata = serde_json::to_value(&ses_event).unwrap_or(Value::Null);
+                        if let (Some(obj), Some(id)) = (metadata.as_object_mut(), &rfc_message_id) {
+                            // Lets the worker's check_event_exists match on our own id
+                            obj.insert("message_id".to_string(), Value::String(id.clone()));
+                        }
                         if let Err(e) = supabase.create_event(&client_id, &execution_id, &event_type, metadata).await {
                             error!("Failed to create event log: {}", e);
                         }
@@ -100,6 +144,11 @@ async fn func(event: LambdaEvent<Value>) -> Result<Value, Error> {
                     }
                     Ok(None) => {
                         warn!("No client found for MessageID: {}", message_id);
+                        let metrics = business_metrics(&mut metrics_by_business, provider, None);
+                        if let Some(metric_name) = event_metric_name(&event_type) {
+                            metrics.count(metric_name, 1);
+                        }
+                        metrics.count("EventsUnmatched", 1);
                     }
                     Err(e) => {
                         error!("Error looking up client: {}", e);
@@ -108,6 +157,10 @@ async fn func(event: LambdaEvent<Value>) -> Result<Value, Error> {
                 }
             }
 
+            for metrics in metrics_by_business.values_mut() {
+                metrics.flush();
+            }
+
             info!("Processed {} events, {} errors", processed, errors);
             Ok(serde_json::json!({ 
                 "message": "Events processed", 
@@ -121,3 +174,29 @@ async fn func(event: LambdaEvent<Value>) -> Result<Value, Error> {
         }
     }
 }
+
+/// Metrics of one provider and business; events whose client was not found are counted
+/// under BusinessId "unknown"
+fn business_metrics<'a>(
+    metrics_by_business: &'a mut BTreeMap<(String, String), Metrics>,
+    provider: &str,
+    business_id: Option<&str>,
+) -> &'a mut Metrics {
+    let business_id = business_id.unwrap_or("unknown");
+    metrics_by_business
+        .entry((provider.to_string(), business_id.to_string()))
+        .or_insert_with(|| Metrics::new()
+            .with_dimension("Provider", provider)
+            .with_dimension("BusinessId", business_id))
+}
+
+/// Maps an SES event type to the metric counted for it
+fn event_metric_name(event_type: &str) -> Option<&'static str> {
+    match event_type {
+        "Delivery" => Some("EmailsDelivered"),
+        "Bounce" => Some("EmailsBounced"),
+        "Complaint" => Some("EmailsComplained"),
+        "Open" => Some("EmailsOpened"),
+        _ => None,
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::BTreeMap;
+use serde_json::{json, Map, Value};
+use chrono::Utc;
+
+/// Collects counters for one dimension set and writes them to stdout using the
+/// CloudWatch Embedded Metric Format (EMF). Lambda ships stdout to CloudWatch Logs,
+/// which extracts the metrics without any extra API calls.
+pub struct Metrics {
+    namespace: String,
+    dimensions: BTreeMap<String, String>,
+    counters: BTreeMap<String, f64>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let namespace = std::env::var("METRICS_NAMESPACE")
+            .unwrap_or_else(|_| "Apex/Collection".to_string());
+
+        Self {
+            namespace,
+            dimensions: BTreeMap::new(),
+            counters: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_dimension(mut self, name: &str, value: &str) -> Self {
+        self.dimensions.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// Adds `value` to a counter metric
+    pub fn count(&mut self, name: &str, value: u32) {
+        *self.counters.entry(name.to_string()).or_insert(0.0) += value as f64;
+    }
+
+    /// Builds the EMF document for the collected values, or None if nothing was recorded
+    pub fn to_emf(&self, timestamp_ms: i64) -> Option<Value> {
+        if self.counters.is_empty() {
+            return None;
+        }
+
+        let mut root = Map::new();
+        let mut definitions = Vec::new();
+
+        for (name, value) in &self.counters {
+            definitions.push(json!({ "Name": name, "Unit": "Count" }));
+            root.insert(name.clone(), json!(value));
+        }
+
+        for (name, value) in &self.dimensions {
+            root.insert(name.clone(), json!(value));
+        }
+
+        let dimension_names: Vec<&String> = self.dimensions.keys().collect();
+
+        root.insert("_aws".to_string(), json!({
+            "Timestamp": timestamp_ms,
+            "CloudWatchMetrics": [{
+                "Namespace": self.namespace,
+                "Dimensions": [dimension_names],
+                "Metrics": definitions
+            }]
+        }));
+
+        Some(Value::Object(root))
+    }
+
+    /// Writes the collected metrics as a single EMF log line and resets them
+    pub fn flush(&mut self) {
+        if let Some(doc) = self.to_emf(Utc::now().timestamp_millis()) {
+            println!("{}", doc);
+        }
+        self.counters.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_emf_document_shape() {
+        let mut metrics = Metrics::new()
+            .with_dimension("Provider", "AWS SES")
+            .with_dimension("BusinessId", "biz-1");
+        metrics.count("EmailsDelivered", 2);
+        metrics.count("EmailsBounced", 1);
+
+        let line = metrics.to_emf(1_700_000_000_000).unwrap().to_string();
+        let doc: Value = serde_json::from_str(&line).unwrap();
+
+        assert_eq!(doc["EmailsDelivered"], json!(2.0));
+        assert_eq!(doc["EmailsBounced"], json!(1.0));
+        assert_eq!(doc["BusinessId"], "biz-1");
+
+        let cw = &doc["_aws"]["CloudWatchMetrics"][0];
+        assert_eq!(cw["Dimensions"], json!([["BusinessId", "Provider"]]));
+        assert_eq!(cw["Metrics"].as_array().unwrap().len(), 2);
+    }
+}
@@ -52,10 +52,14 @@ impl SupabaseService {
         Ok(())
     }
 
-    pub async fn find_client_by_message_id(&self, message_id: &str) -> Result<Option<(String, String)>, Box<dyn Error>> {
+    /// Returns (client_id, execution_id, business_id) for the client that owns the message.
+    /// `field` is the custom_data key to match: `message_id` holds the worker-generated
+    /// RFC 5322 id (or the provider id on legacy rows), `provider_message_id` the provider id.
+    /// The business is read through the execution embed so metrics can be dimensioned by tenant.
+    pub async fn find_client_by_message_id(&self, field: &str, message_id: &str) -> Result<Option<(String, String, Option<String>)>, Box<dyn Error>> {
         let url = format!(
-            "{}/rest/v1/collection_clients?custom_data->>message_id=eq.{}&select=id,execution_id",
-            self.base_url, message_id
+            "{}/rest/v1/collection_clients?custom_data->>{}=eq.{}&select=id,execution_id,collection_executions(business_id)",
+            self.base_url, field, urlencoding::encode(message_id)
         );
 
         let response = self.client.get(&url)
@@ -75,8 +79,13 @@ impl SupabaseService {
         if let Some(first) = results.first() {
             if let Some(id) = first.get("id").and_then(|i| i.as_str()) {
                 if let Some(exec_id) = first.get("execution_id").and_then(|e| e.as_str()) {
+                    let business_id = first
+                        .get("collection_executions")
+                        .and_then(|e| e.get("business_id"))
+                        .and_then(|b| b.as_str())
+                        .map(|b| b.to_string());
                     debug!("Found client with message_id {}: {} (exec: {})", message_id, id, exec_id);
-                    return Ok(Some((id.to_string(), exec_id.to_string())));
+                    return Ok(Some((id.to_string(), exec_id.to_string(), business_id)));
                 }
             }
         }
@@ -85,6 +94,77 @@ impl SupabaseService {
         Ok(None)
     }
 
+    /// Completes an outbox entry from a provider event. Events are the primary evidence
+    /// that an in-doubt send reached the provider. Failed entries are completed too: the
+    /// worker fails an entry when the provider call errored or reconcile found no
+    /// evidence in time, yet the provider may have accepted the message, and leaving the
+    /// entry failed would let a retry send it again. Without a `provider` the one the
+    /// worker recorded on the entry is kept.
+    pub async fn resolve_outbox_entry(&self, idempotency_key: &str, provider: Option<&str>, message_id: &str) -> Result<bool, Box<dyn Error>> {
+        let url = format!(
+            "{}/rest/v1/collection_email_outbox?idempotency_key=eq.{}&status=in.(pending,failed)",
+            self.base_url, urlencoding::encode(idempotency_key)
+        );
+
+        let mut body = json!({
+            "status": "sent",
+            "provider_message_id": message_id,
+            "resolved_by": "event",
+            "completed_at": chrono::Utc::now().to_rfc3339()
+        });
+        if let (Some(obj), Some(provider)) = (body.as_object_mut(), provider) {
+            obj.insert("provider".to_string(), json!(provider));
+        }
+
+        let response = self.client.patch(&url)
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Content-Type", "application/json")
+            .header("Prefer", "return=representation")
+            .json(&body)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await?;
+            return Err(format!("Failed to resolve outbox entry: {} - {}", status, text).into());
+        }
+
+        let updated: Vec<serde_json::Value> = response.json().await?;
+        Ok(!updated.is_empty())
+    }
+
+    /// Finds the client behind an outbox entry, for events whose message_id was never
+    /// written to the client because the worker crashed right after sending.
+    pub async fn find_client_by_outbox_key(&self, idempotency_key: &str) -> Result<Option<(String, String, Option<String>)>, Box<dyn Error>> {
+        let url = format!(
+            "{}/rest/v1/collection_email_outbox?idempotency_key=eq.{}&select=client_id,execution_id,business_id",
+            self.base_url, urlencoding::encode(idempotency_key)
+        );
+
+        let response = self.client.get(&url)
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await?;
+            return Err(format!("Failed to search outbox: {} - {}", status, text).into());
+        }
+
+        let results: Vec<serde_json::Value> = response.json().await?;
+
+        Ok(results.first().and_then(|first| {
+            let client_id = first.get("client_id")?.as_str()?.to_string();
+            let execution_id = first.get("execution_id")?.as_str()?.to_string();
+            let business_id = first.get("business_id").and_then(|b| b.as_str()).map(|b| b.to_string());
+            Some((client_id, execution_id, business_id))
+        }))
+    }
+
     pub async fn update_client_status(
         &self,
         client_id: &str,
@@ -0,0 +1,21 @@
+-- Migration: Currency and locale for collection email amounts
+-- Date: 2026-10-19
+-- Purpose: Let each business choose how amounts are rendered in collection emails.
+-- The email worker formats every amount field of the client and its invoices with
+-- the currency's minor units and the locale's separators and symbol placement.
+--
+-- Businesses without a currency keep the legacy output (COP, "." thousands, no decimals).
+-- Clients (custom_data.currency) and invoices (invoices[].currency) can override the currency.
+
+ALTER TABLE collection_config
+ADD COLUMN IF NOT EXISTS currency VARCHAR(3) DEFAULT NULL
+    CHECK (currency IS NULL OR currency ~ '^[A-Z]{3}$'),
+ADD COLUMN IF NOT EXISTS locale VARCHAR(20) DEFAULT NULL,
+ADD COLUMN IF NOT EXISTS amount_decimals SMALLINT DEFAULT NULL
+    CHECK (amount_decimals IS NULL OR amount_decimals BETWEEN 0 AND 6),
+ADD COLUMN IF NOT EXISTS show_currency_symbol BOOLEAN NOT NULL DEFAULT FALSE;
+
+COMMENT ON COLUMN collection_config.currency IS 'ISO 4217 currency for email amounts (e.g. COP, USD). NULL keeps the legacy COP format without decimals';
+COMMENT ON COLUMN collection_config.locale IS 'Locale for amount separators and symbol placement (e.g. es-CO, en-US). NULL uses the currency''s usual format';
+COMMENT ON COLUMN collection_config.amount_decimals IS 'Overrides the currency minor units for email amounts';
+COMMENT ON COLUMN collection_config.show_currency_symbol IS 'Prefix/suffix amounts with the currency symbol in email templates';
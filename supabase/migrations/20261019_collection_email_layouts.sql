@@ -0,0 +1,45 @@
+-- Migration: Per-business email layouts and branding
+-- Date: 2026-10-19
+-- Purpose: The email worker wraps every rendered body in the business's layout. That
+-- layout covers the logo, brand colours, width, footer, contact details and legal text.
+-- Businesses without an active layout keep the default APX layout. The same applies
+-- to each column left NULL.
+--
+-- logo_path is a path inside the public 'business-media' bucket (e.g.
+-- 'images/apex/<business_id>/<timestamp>.png'), or an absolute URL.
+-- Colours must be #rgb or #rrggbb; other values are ignored by the worker.
+
+CREATE TABLE IF NOT EXISTS collection_email_layouts (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    business_id UUID NOT NULL UNIQUE REFERENCES businesses(id) ON DELETE CASCADE,
+
+    brand_name TEXT,
+    logo_path TEXT,
+    primary_color VARCHAR(7) CHECK (primary_color IS NULL OR primary_color ~ '^#([0-9a-fA-F]{3}|[0-9a-fA-F]{6})$'),
+    background_color VARCHAR(7) CHECK (background_color IS NULL OR background_color ~ '^#([0-9a-fA-F]{3}|[0-9a-fA-F]{6})$'),
+    link_color VARCHAR(7) CHECK (link_color IS NULL OR link_color ~ '^#([0-9a-fA-F]{3}|[0-9a-fA-F]{6})$'),
+    text_color VARCHAR(7) CHECK (text_color IS NULL OR text_color ~ '^#([0-9a-fA-F]{3}|[0-9a-fA-F]{6})$'),
+    container_width INTEGER CHECK (container_width IS NULL OR container_width BETWEEN 480 AND 800),
+
+    footer_text TEXT,
+    contact_email TEXT,
+    contact_phone TEXT,
+    contact_address TEXT,
+    legal_text TEXT,
+
+    is_active BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+    updated_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+ALTER TABLE collection_email_layouts ENABLE ROW LEVEL SECURITY;
+
+CREATE POLICY "Business can manage own email layouts"
+    ON collection_email_layouts FOR ALL
+    USING (business_id = (
+        (auth.jwt() -> 'app_metadata' ->> 'business_id')::uuid
+    ));
+
+COMMENT ON TABLE collection_email_layouts IS 'Branding applied by the email worker around collection emails, one row per business';
+COMMENT ON COLUMN collection_email_layouts.logo_path IS 'Path in the public business-media bucket, or an absolute URL';
+COMMENT ON COLUMN collection_email_layouts.legal_text IS 'Replaces the default APX legal notice in the footer';
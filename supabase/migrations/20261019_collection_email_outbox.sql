@@ -0,0 +1,60 @@
+-- Migration: Create collection_email_outbox table
+-- Date: 2026-10-19
+-- Purpose: Record every send before it reaches the email provider so a worker crash
+-- between the provider call and the client status update cannot cause duplicate sends.
+--
+-- Lifecycle:
+--   pending -> sent      Provider accepted the message (worker, event handler or reconciler)
+--   pending -> failed    Provider rejected the message; the row can be retried
+--   pending (stale)      In doubt: resolved by the reconciler, never re-sent blindly
+
+CREATE TABLE IF NOT EXISTS collection_email_outbox (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    idempotency_key VARCHAR(64) NOT NULL UNIQUE, -- sha256(execution_id:client_id)
+    client_id UUID NOT NULL REFERENCES collection_clients(id) ON DELETE CASCADE,
+    execution_id UUID NOT NULL REFERENCES collection_executions(id) ON DELETE CASCADE,
+    business_id UUID REFERENCES businesses(id) ON DELETE CASCADE,
+    message_hash VARCHAR(64) NOT NULL, -- sha256 of the rendered message
+    status VARCHAR(20) NOT NULL DEFAULT 'pending'
+        CHECK (status IN ('pending', 'sent', 'failed')),
+    attempt INTEGER NOT NULL DEFAULT 1,
+    provider VARCHAR(50),
+    provider_message_id VARCHAR(255),
+    error TEXT,
+    resolved_by VARCHAR(20), -- 'worker', 'event', 'provider', 'timeout'
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+    updated_at TIMESTAMPTZ DEFAULT NOW(),
+    completed_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_collection_email_outbox_client ON collection_email_outbox(client_id);
+CREATE INDEX IF NOT EXISTS idx_collection_email_outbox_execution ON collection_email_outbox(execution_id);
+CREATE INDEX IF NOT EXISTS idx_collection_email_outbox_pending
+    ON collection_email_outbox(updated_at)
+    WHERE status = 'pending';
+
+CREATE OR REPLACE FUNCTION update_collection_email_outbox_updated_at()
+RETURNS TRIGGER AS $$
+BEGIN
+    NEW.updated_at = NOW();
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS update_collection_email_outbox_updated_at ON collection_email_outbox;
+CREATE TRIGGER update_collection_email_outbox_updated_at
+    BEFORE UPDATE ON collection_email_outbox
+    FOR EACH ROW
+    EXECUTE FUNCTION update_collection_email_outbox_updated_at();
+
+-- Only the Lambdas (service role) write to the outbox; tenants can read their own rows
+ALTER TABLE collection_email_outbox ENABLE ROW LEVEL SECURITY;
+
+CREATE POLICY "View own outbox" ON collection_email_outbox
+    FOR SELECT
+    USING (business_id = (auth.jwt() -> 'app_metadata' ->> 'business_id')::uuid);
+
+COMMENT ON TABLE collection_email_outbox IS 'Write-ahead record of collection email sends, used to prevent duplicate sends after worker crashes';
+
+-- Audit event emitted by the worker's outbox reconciler
+ALTER TYPE execution_event_type ADD VALUE IF NOT EXISTS 'OUTBOX_RESOLVED';
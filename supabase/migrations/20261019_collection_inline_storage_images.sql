@@ -0,0 +1,12 @@
+-- Migration: Inline storage images in collection emails
+-- Date: 2026-10-19
+-- Purpose: Many corporate mail clients block remote images, hiding business logos and
+-- banners. With inline_storage_images enabled the email worker downloads every <img>
+-- served from the project's public storage once per batch and sends it inside the
+-- email as an inline part (cid:), keeping the remote URL for images over the size
+-- limits (1 MB per image and 5 MB per email on SES; 512 KB and 2 MB on Brevo).
+
+ALTER TABLE collection_config
+ADD COLUMN IF NOT EXISTS inline_storage_images BOOLEAN DEFAULT FALSE;
+
+COMMENT ON COLUMN collection_config.inline_storage_images IS 'Embed images hosted on public Supabase storage as inline cid: parts instead of remote URLs';
@@ -0,0 +1,11 @@
+-- Migration: Language of system-generated email text
+-- Date: 2026-10-19
+-- Purpose: The email worker writes footer, legal notice, fallback name and plain-text
+-- placeholder from a message catalogue (es, en). The language is taken from the
+-- client's custom_data ("language" or "locale"), then this column, then the business
+-- locale, then Spanish. Templates can branch with {{#lang "en"}}...{{else}}...{{/lang}}.
+
+ALTER TABLE collection_config
+ADD COLUMN IF NOT EXISTS language VARCHAR(10) DEFAULT NULL;
+
+COMMENT ON COLUMN collection_config.language IS 'Language of system email text (es, en). NULL uses the locale language, then es';
@@ -0,0 +1,19 @@
+-- Migration: Signed payment links in collection emails
+-- Date: 2026-10-19
+-- Purpose: When payment_url_base is set, the email worker adds a signed, expiring
+-- payment_url to the client (whole balance) and to every invoice with a number.
+-- Templates use {{payment_url}}; inside {{#each invoices}} it is the invoice's link.
+--
+-- Links are <payment_url_base>/<token>. The token is <payload>.<signature>, both
+-- base64url without padding: the payload is JSON {"c": client_id, "i": invoice_number,
+-- "a": amount, "e": expiry unix seconds} and the signature its HMAC-SHA256 with the
+-- PAYMENT_LINK_SECRET of the worker. The payment page checks tokens by invoking the
+-- worker with {"action": "verify_payment_token", "token": "..."} or by recomputing the
+-- HMAC with the same secret.
+
+ALTER TABLE collection_config
+ADD COLUMN IF NOT EXISTS payment_url_base TEXT DEFAULT NULL,
+ADD COLUMN IF NOT EXISTS payment_link_ttl_days INTEGER DEFAULT NULL CHECK (payment_link_ttl_days > 0);
+
+COMMENT ON COLUMN collection_config.payment_url_base IS 'Payment page of signed {{payment_url}} links (<base>/<token>). NULL disables payment links';
+COMMENT ON COLUMN collection_config.payment_link_ttl_days IS 'Days payment links stay valid after the email is rendered. NULL uses 30';
@@ -0,0 +1,14 @@
+-- Migration: Payment QR code in collection emails
+-- Date: 2026-10-19
+-- Purpose: payment_qr_content is the text the email worker encodes in a QR code per
+-- client, written as a Handlebars template over the client data: usually
+-- '{{payment_url}}' (the signed payment link) or a bank-transfer reference such as
+-- 'Bancolombia ahorros 123-456789-00 Ref. {{nit}}'. Templates place the image with
+-- {{payment_qr}} (optionally {{payment_qr size=200}}). The PNG travels inside the
+-- email as an inline image (cid:payment-qr.png) and is only attached when the
+-- template places it.
+
+ALTER TABLE collection_config
+ADD COLUMN IF NOT EXISTS payment_qr_content TEXT DEFAULT NULL;
+
+COMMENT ON COLUMN collection_config.payment_qr_content IS 'Handlebars text encoded in the {{payment_qr}} image, e.g. {{payment_url}}. NULL disables the QR code';
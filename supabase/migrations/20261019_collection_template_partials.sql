@@ -0,0 +1,80 @@
+-- Migration: Shared template partials per business
+-- Date: 2026-10-19
+-- Purpose: Reusable blocks (greeting, invoice table, signature...) that collection
+-- templates include with {{> name}}, e.g. {{> invoice_table}} or {{> firma}}.
+-- The email worker registers every active partial of the business, preprocesses them
+-- like templates (TipTap HTML), and fails only the templates that include an unknown
+-- or cyclic partial (a includes b includes a).
+--
+-- version is bumped on every content change and the previous content is kept in
+-- collection_template_partial_versions. Sent emails record the partial versions used
+-- in collection_clients.custom_data.template_partials ("firma@3").
+
+CREATE TABLE IF NOT EXISTS collection_template_partials (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    business_id UUID NOT NULL REFERENCES businesses(id) ON DELETE CASCADE,
+
+    name VARCHAR(64) NOT NULL CHECK (name ~ '^[A-Za-z_][A-Za-z0-9_-]*$'),
+    description TEXT,
+    content TEXT NOT NULL,
+    version INTEGER NOT NULL DEFAULT 1,
+
+    is_active BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+    updated_at TIMESTAMPTZ DEFAULT NOW(),
+
+    UNIQUE (business_id, name)
+);
+
+CREATE TABLE IF NOT EXISTS collection_template_partial_versions (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    partial_id UUID NOT NULL REFERENCES collection_template_partials(id) ON DELETE CASCADE,
+    business_id UUID NOT NULL REFERENCES businesses(id) ON DELETE CASCADE,
+    version INTEGER NOT NULL,
+    content TEXT NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+
+    UNIQUE (partial_id, version)
+);
+
+CREATE INDEX IF NOT EXISTS idx_collection_template_partials_business
+    ON collection_template_partials(business_id) WHERE is_active = TRUE;
+
+-- Archive the previous content and bump the version when the content changes
+CREATE OR REPLACE FUNCTION version_collection_template_partial()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF NEW.content IS DISTINCT FROM OLD.content THEN
+        INSERT INTO collection_template_partial_versions (partial_id, business_id, version, content)
+        VALUES (OLD.id, OLD.business_id, OLD.version, OLD.content)
+        ON CONFLICT (partial_id, version) DO NOTHING;
+        NEW.version = OLD.version + 1;
+    END IF;
+    NEW.updated_at = NOW();
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS version_collection_template_partial ON collection_template_partials;
+CREATE TRIGGER version_collection_template_partial
+    BEFORE UPDATE ON collection_template_partials
+    FOR EACH ROW
+    EXECUTE FUNCTION version_collection_template_partial();
+
+ALTER TABLE collection_template_partials ENABLE ROW LEVEL SECURITY;
+ALTER TABLE collection_template_partial_versions ENABLE ROW LEVEL SECURITY;
+
+CREATE POLICY "Business can manage own template partials"
+    ON collection_template_partials FOR ALL
+    USING (business_id = (
+        (auth.jwt() -> 'app_metadata' ->> 'business_id')::uuid
+    ));
+
+CREATE POLICY "Business can read own template partial versions"
+    ON collection_template_partial_versions FOR SELECT
+    USING (business_id = (
+        (auth.jwt() -> 'app_metadata' ->> 'business_id')::uuid
+    ));
+
+COMMENT ON TABLE collection_template_partials IS 'Reusable Handlebars partials of collection templates, included with {{> name}}';
+COMMENT ON COLUMN collection_template_partials.version IS 'Bumped on every content change; previous contents are in collection_template_partial_versions';
@@ -0,0 +1,13 @@
+-- Migration: Per-execution send prioritization
+-- Date: 2026-10-19
+-- Purpose: Let an execution send to its most important debtors first. The email worker
+-- reorders clients inside each batch and, before the first batch is picked up, moves
+-- the highest-priority clients into the earliest batches.
+
+ALTER TABLE collection_executions
+ADD COLUMN IF NOT EXISTS priority_strategy VARCHAR(20) NOT NULL DEFAULT 'none'
+    CHECK (priority_strategy IN ('none', 'amount_due', 'days_overdue', 'threshold'));
+
+COMMENT ON COLUMN collection_executions.priority_strategy IS 'Client send order: none (creation order), amount_due, days_overdue or threshold (most severe first)';
+
+ALTER TYPE execution_event_type ADD VALUE IF NOT EXISTS 'PRIORITIZED';
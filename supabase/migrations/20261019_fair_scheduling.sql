@@ -0,0 +1,20 @@
+-- Migration: Fair scheduling across concurrent executions
+-- Date: 2026-10-19
+-- Purpose: Share the global email provider send rate between businesses that run
+-- executions at the same time. The email worker splits the rate between active
+-- businesses in proportion to send_weight (1 = plain round-robin), then equally between
+-- each business's executions, defers the next batch accordingly and records the
+-- expected completion time of the execution.
+
+ALTER TABLE collection_config
+ADD COLUMN IF NOT EXISTS send_weight NUMERIC(6,2) NOT NULL DEFAULT 1
+    CHECK (send_weight > 0);
+
+COMMENT ON COLUMN collection_config.send_weight IS 'Relative share of the provider send rate when several businesses send at the same time (default 1)';
+
+ALTER TABLE collection_executions
+ADD COLUMN IF NOT EXISTS expected_completion_at TIMESTAMPTZ;
+
+COMMENT ON COLUMN collection_executions.expected_completion_at IS 'Estimated time the last client will be sent, updated by the email worker after each batch';
+
+ALTER TYPE execution_event_type ADD VALUE IF NOT EXISTS 'FAIR_SHARE';
@@ -0,0 +1,25 @@
+-- Migration: Invoice table helper settings
+-- Date: 2026-10-19
+-- Purpose: Templates render the client's invoices with {{invoice_table}}. The business
+-- chooses the columns, their labels and formats, the sort order and the totals row:
+--
+-- {
+--   "columns": [
+--     { "field": "invoice_number", "label": "No. factura" },
+--     { "field": "due_date", "format": "date" },
+--     { "field": "amount_due", "label": "Saldo", "format": "amount", "align": "right" }
+--   ],
+--   "sort_by": "due_date",
+--   "sort_order": "asc",
+--   "show_totals": true,
+--   "totals_label": "Total adeudado"
+-- }
+--
+-- NULL shows invoice number, dates, days overdue and amount due (the ones present) in
+-- file order with a totals row. Templates can override per use, e.g.
+-- {{invoice_table columns="invoice_number,amount_due" sort="amount_due" order="desc"}}.
+
+ALTER TABLE collection_config
+ADD COLUMN IF NOT EXISTS invoice_table JSONB DEFAULT NULL;
+
+COMMENT ON COLUMN collection_config.invoice_table IS 'Settings of the {{invoice_table}} helper: columns (field, label, format, align), sort_by, sort_order, show_totals, totals_label';
@@ -0,0 +1,16 @@
+-- Migration: Strict or fallback handling of template render failures
+-- Date: 2026-10-19
+-- Purpose: A template that fails to render is never sent raw anymore.
+-- In 'strict' mode (default) the client is marked failed with the render error.
+-- In 'fallback' mode the email worker renders fallback_template_id instead, through
+-- the same pipeline (formatting, helpers, layout). If the fallback also fails, or is
+-- not set, the client fails as in strict mode.
+
+ALTER TABLE collection_config
+ADD COLUMN IF NOT EXISTS render_mode TEXT NOT NULL DEFAULT 'strict'
+    CHECK (render_mode IN ('strict', 'fallback')),
+ADD COLUMN IF NOT EXISTS fallback_template_id UUID DEFAULT NULL
+    REFERENCES collection_templates(id) ON DELETE SET NULL;
+
+COMMENT ON COLUMN collection_config.render_mode IS 'strict: fail the client when its template fails to render. fallback: send fallback_template_id instead';
+COMMENT ON COLUMN collection_config.fallback_template_id IS 'Template rendered when the client template fails and render_mode is fallback';
@@ -0,0 +1,8 @@
+-- Migration: Render warning audit event
+-- Date: 2026-10-19
+-- Purpose: The email worker re-renders invoice dates in collection_config.output_date_format.
+-- Values it cannot parse (and other render problems) are sent unchanged and recorded in
+-- execution_audit_logs as RENDER_WARNING with the client, template and offending fields.
+-- The same warnings are returned by the worker's `dry_run` action.
+
+ALTER TYPE execution_event_type ADD VALUE IF NOT EXISTS 'RENDER_WARNING';
@@ -0,0 +1,11 @@
+-- Migration: HTML escaping of customer data in collection emails
+-- Date: 2026-10-19
+-- Purpose: The email worker now HTML-escapes every value rendered with {{field}}, so
+-- imported custom_data (full_name, company_name...) can't inject markup into emails.
+-- Raw HTML only passes through {{{triple-stash}}} or the fields listed here, which are
+-- sanitized (scripts, embeds, event handlers and javascript: URLs removed) before rendering.
+
+ALTER TABLE collection_config
+ADD COLUMN IF NOT EXISTS rich_text_fields TEXT[] NOT NULL DEFAULT '{}';
+
+COMMENT ON COLUMN collection_config.rich_text_fields IS 'custom_data fields containing HTML that templates render as (sanitized) markup. Other fields are HTML-escaped';
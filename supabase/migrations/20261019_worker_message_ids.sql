@@ -0,0 +1,16 @@
+-- Migration: Worker-generated RFC 5322 Message-IDs
+-- Date: 2026-10-19
+-- Purpose: The email worker now assigns its own Message-ID (<client-uuid.attempt@domain>)
+-- and persists it in the outbox before sending. collection_clients.custom_data keeps
+-- message_id (ours) and provider_message_id (SES/Brevo id) for event correlation.
+
+ALTER TABLE collection_email_outbox
+ADD COLUMN IF NOT EXISTS message_id VARCHAR(255);
+
+COMMENT ON COLUMN collection_email_outbox.message_id IS 'Message-ID header generated by the worker for the current attempt';
+
+-- The event handler looks clients up by either id
+CREATE INDEX IF NOT EXISTS idx_collection_clients_message_id
+    ON collection_clients ((custom_data->>'message_id'));
+CREATE INDEX IF NOT EXISTS idx_collection_clients_provider_message_id
+    ON collection_clients ((custom_data->>'provider_message_id'));